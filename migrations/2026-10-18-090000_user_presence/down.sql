@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+  DROP COLUMN IF EXISTS status,
+  DROP COLUMN IF EXISTS status_text,
+  DROP COLUMN IF EXISTS last_seen_at;
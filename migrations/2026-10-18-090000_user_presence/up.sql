@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE users
+  ADD COLUMN status VARCHAR(32) DEFAULT NULL,
+  ADD COLUMN status_text VARCHAR(255) DEFAULT NULL,
+  ADD COLUMN last_seen_at timestamp with time zone DEFAULT NULL
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE room
+  DROP CONSTRAINT IF EXISTS room_direct_users_key,
+  DROP COLUMN IF EXISTS direct_user_a,
+  DROP COLUMN IF EXISTS direct_user_b;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+--- direct rooms are keyed by the unordered user pair, direct_user_a is always the lower id
+ALTER TABLE room
+  ADD COLUMN direct_user_a bigint DEFAULT NULL REFERENCES users(id),
+  ADD COLUMN direct_user_b bigint DEFAULT NULL REFERENCES users(id),
+  ADD CONSTRAINT room_direct_users_key UNIQUE (direct_user_a, direct_user_b)
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+--- one row per login, the refresh token is stored as a sha256 hex digest and rotated on every refresh
+CREATE TABLE user_session (
+  id bigserial NOT NULL PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  refresh_token_hash text NOT NULL UNIQUE,
+  previous_refresh_token_hash text DEFAULT NULL,
+  created_at timestamp with time zone DEFAULT now() NOT NULL,
+  last_used_at timestamp with time zone DEFAULT now() NOT NULL,
+  expires_at timestamp with time zone NOT NULL,
+  revoked_at timestamp with time zone DEFAULT NULL
+)
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE user_session
+  DROP COLUMN IF EXISTS user_agent,
+  DROP COLUMN IF EXISTS ip_address;
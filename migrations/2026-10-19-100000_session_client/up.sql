@@ -0,0 +1,5 @@
+-- Your SQL goes here
+--- where the login came from, shown to the user when listing sessions
+ALTER TABLE user_session
+  ADD COLUMN user_agent text DEFAULT NULL,
+  ADD COLUMN ip_address text DEFAULT NULL
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+--- single-use tickets authenticating a websocket handshake, stored as sha256 hex digests
+CREATE TABLE ws_ticket (
+  ticket_hash text NOT NULL PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  session_id bigint NOT NULL REFERENCES user_session(id),
+  expires_at timestamp with time zone NOT NULL
+)
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+--- the secret is encrypted with TOTP_ENCRYPTION_KEY, recovery codes are sha256 hex digests
+CREATE TABLE user_totp (
+  user_id bigint NOT NULL PRIMARY KEY REFERENCES users(id),
+  secret_ciphertext text NOT NULL,
+  confirmed_at timestamp with time zone,
+  last_used_step bigint,
+  recovery_code_hashes text[] NOT NULL DEFAULT '{}',
+  created_at timestamp with time zone NOT NULL DEFAULT NOW()
+)
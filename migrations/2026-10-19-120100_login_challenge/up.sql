@@ -0,0 +1,8 @@
+-- Your SQL goes here
+--- logins waiting for their second factor, the token is stored as a sha256 hex digest
+CREATE TABLE login_challenge (
+  challenge_hash text NOT NULL PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  expires_at timestamp with time zone NOT NULL,
+  failed_attempts integer NOT NULL DEFAULT 0
+)
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+--- where password reset tokens are sent, optional
+ALTER TABLE users ADD COLUMN email text UNIQUE
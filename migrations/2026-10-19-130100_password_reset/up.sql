@@ -0,0 +1,7 @@
+-- Your SQL goes here
+--- single-use password reset tokens, stored as sha256 hex digests
+CREATE TABLE password_reset (
+  token_hash text NOT NULL PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id),
+  expires_at timestamp with time zone NOT NULL
+)
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users DROP COLUMN IF EXISTS display_name, DROP COLUMN IF EXISTS bio, DROP COLUMN IF EXISTS avatar_url;
\ No newline at end of file
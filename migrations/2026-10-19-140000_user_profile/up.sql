@@ -0,0 +1,3 @@
+-- Your SQL goes here
+--- shown instead of the login name, which stays unique
+ALTER TABLE users ADD COLUMN display_name text, ADD COLUMN bio text, ADD COLUMN avatar_url text
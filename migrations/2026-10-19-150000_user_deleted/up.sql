@@ -0,0 +1,3 @@
+-- Your SQL goes here
+--- deleted accounts stay as anonymous rows, so their messages keep a sender
+ALTER TABLE users ADD COLUMN deleted_at timestamp with time zone DEFAULT NULL
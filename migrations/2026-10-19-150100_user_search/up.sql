@@ -0,0 +1,3 @@
+-- Your SQL goes here
+--- users may keep out of search results, they stay reachable by id
+ALTER TABLE users ADD COLUMN hidden_from_search boolean NOT NULL DEFAULT false
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+--- prefix search on login names
+CREATE INDEX users_name_search_idx ON users (lower(name) text_pattern_ops)
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+--- prefix search on display names
+CREATE INDEX users_display_name_search_idx ON users (lower(display_name) text_pattern_ops)
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE user_block (
+  blocker_id bigint NOT NULL  REFERENCES users(id),
+  blocked_id bigint NOT NULL  REFERENCES users(id),
+  created_at timestamp with time zone DEFAULT now() NOT NULL,
+  PRIMARY KEY (blocker_id, blocked_id)
+)
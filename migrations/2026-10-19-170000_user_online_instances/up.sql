@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- instances holding a live socket of the user, so that presence holds across a cluster
+ALTER TABLE users ADD COLUMN online_instances integer NOT NULL DEFAULT 0
\ No newline at end of file
@@ -0,0 +1,346 @@
+use crate::auth::{guard, AuthConfig};
+use crate::db::{db_config, setup_conn_pool, Repositories};
+use crate::errors;
+use crate::helpers::{get_env, get_env_or};
+use crate::mail::{smtp::SmtpMailer, FileMailer, LogMailer, Mailer};
+use crate::rate_limit::{limit_by_ip, limit_by_user, RateLimitConfig, RateLimits};
+use crate::routes::{
+  account, block, health, jwks, metrics, password, room, session, two_factor, user, SharedState,
+};
+use crate::ws::bus::{postgres::PostgresBus, redis::RedisBus};
+use crate::ws::heartbeat::HeartbeatConfig;
+use crate::ws::lobby::Lobby;
+use axum::http::{HeaderName, Request};
+use axum::{
+  middleware, routing::delete, routing::get, routing::post, routing::put, BoxError, Router,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tower_http::request_id::{
+  MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer,
+};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{info, warn, Level, Span};
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Everything needed to build and run the chat server.
+pub struct Config {
+  pub addr: SocketAddr,
+  pub state: SharedState,
+  pub routes: RouteGroups,
+  // How long the instance keeps serving while reporting not ready, so that load balancers
+  // stop sending traffic before the listener closes.
+  pub drain_grace: Duration,
+  // How long the shutdown may take before the server exits anyway.
+  pub shutdown_timeout: Duration,
+  // Retry hint sent to the clients whose sockets are closed on shutdown.
+  pub retry_after: Duration,
+}
+
+/// Optional groups of routes, every group is enabled by default.
+#[derive(Clone, Copy, Debug)]
+pub struct RouteGroups {
+  // `POST /dm/:user_id`
+  pub direct_messages: bool,
+  // `PUT /users/me/status`
+  pub presence: bool,
+  // `GET /ws`
+  pub multiplexed_socket: bool,
+  // `GET /metrics`
+  pub metrics: bool,
+  // `GET /users/search` and `GET /users/:id`
+  pub search: bool,
+}
+
+impl Default for RouteGroups {
+  fn default() -> Self {
+    RouteGroups {
+      direct_messages: true,
+      presence: true,
+      multiplexed_socket: true,
+      metrics: true,
+      search: true,
+    }
+  }
+}
+
+impl RouteGroups {
+  /// Reads `DISABLED_ROUTE_GROUPS`, a comma separated list of `dm`, `presence`, `ws`,
+  /// `metrics` and `search`.
+  pub fn from_env() -> Self {
+    let mut routes = RouteGroups::default();
+    let disabled: String = get_env_or("DISABLED_ROUTE_GROUPS", String::new());
+    for group in disabled.split(',').map(str::trim).filter(|g| !g.is_empty()) {
+      match group {
+        "dm" => routes.direct_messages = false,
+        "presence" => routes.presence = false,
+        "ws" => routes.multiplexed_socket = false,
+        "metrics" => routes.metrics = false,
+        "search" => routes.search = false,
+        group => panic!(
+          "couldn't interpret DISABLED_ROUTE_GROUPS: unknown group {:?}",
+          group
+        ),
+      }
+    }
+    routes
+  }
+}
+
+impl Config {
+  /// Connects to Postgres, runs the migrations and sets up the room bus selected by
+  /// `ROOM_BUS`, or the Postgres bus with `CLUSTER_MODE=true`. The rest of the settings come
+  /// from the env as well.
+  pub async fn from_env() -> Config {
+    let pool = setup_conn_pool().await;
+    let repos = Repositories::postgres(pool.clone());
+    let rate_limits = RateLimitConfig::from_env();
+
+    let lobby = Lobby::new(repos.clone(), HeartbeatConfig::from_env())
+      .with_message_rate(rate_limits.socket_messages);
+    // `CLUSTER_MODE=true` selected the Postgres bus before `ROOM_BUS` existed
+    let default_bus = if get_env_or("CLUSTER_MODE", false) {
+      "postgres"
+    } else {
+      "memory"
+    };
+    let lobby = match get_env_or("ROOM_BUS", default_bus.to_owned()).as_str() {
+      "memory" => lobby,
+      "postgres" => {
+        let bus = PostgresBus::spawn(pool, db_config(), lobby.local_state());
+        lobby.with_bus(bus)
+      }
+      "redis" => {
+        let bus = RedisBus::connect(&get_env("REDIS_URL"), lobby.local_state())
+          .await
+          .unwrap();
+        lobby.with_bus(bus)
+      }
+      bus => panic!("couldn't interpret ROOM_BUS: unknown bus {:?}", bus),
+    };
+
+    let mut config = Config::new(repos, lobby);
+    config.addr = get_env_or("SERVER_ADDR", config.addr);
+    config.routes = RouteGroups::from_env();
+    config.state.auth = Arc::new(AuthConfig::from_env());
+    config.state.mailer = mailer_from_env();
+    config.state.rate_limits = Arc::new(RateLimits::new(&rate_limits));
+    config.drain_grace = Duration::from_secs(get_env_or(
+      "SHUTDOWN_DRAIN_GRACE_SECS",
+      config.drain_grace.as_secs(),
+    ));
+    config.shutdown_timeout = Duration::from_secs(get_env_or(
+      "SHUTDOWN_TIMEOUT_SECS",
+      config.shutdown_timeout.as_secs(),
+    ));
+    config.retry_after = Duration::from_secs(get_env_or(
+      "SHUTDOWN_RETRY_AFTER_SECS",
+      config.retry_after.as_secs(),
+    ));
+    config
+  }
+
+  /// Uses the given storage and lobby with the default settings.
+  pub fn new(repos: Repositories, lobby: Lobby) -> Config {
+    Config {
+      addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+      state: SharedState {
+        repos,
+        lobby: Arc::new(lobby),
+        auth: Arc::new(AuthConfig::default()),
+        mailer: Arc::new(LogMailer),
+        rate_limits: Arc::new(RateLimits::default()),
+      },
+      routes: RouteGroups::default(),
+      drain_grace: Duration::from_secs(5),
+      shutdown_timeout: Duration::from_secs(30),
+      retry_after: Duration::from_secs(5),
+    }
+  }
+
+  /// Keeps everything in process memory, no database is needed.
+  pub fn in_memory() -> Config {
+    let repos = Repositories::in_memory();
+    let lobby = Lobby::new(repos.clone(), HeartbeatConfig::default());
+    Config::new(repos, lobby)
+  }
+}
+
+/// Picks the mail transport named by `MAILER`: `log` (the default), `file`, writing to
+/// `MAIL_DIR`, or `smtp`, connecting to `SMTP_URL` and sending as `MAIL_FROM`.
+fn mailer_from_env() -> Arc<dyn Mailer> {
+  match get_env_or("MAILER", "log".to_owned()).as_str() {
+    "log" => Arc::new(LogMailer),
+    "file" => Arc::new(FileMailer::new(get_env_or("MAIL_DIR", "mail".to_owned()))),
+    "smtp" => Arc::new(
+      SmtpMailer::new(&get_env("SMTP_URL"), &get_env("MAIL_FROM"))
+        .unwrap_or_else(|e| panic!("couldn't interpret SMTP_URL or MAIL_FROM: {}", e)),
+    ),
+    mailer => panic!("couldn't interpret MAILER: unknown mailer {:?}", mailer),
+  }
+}
+
+/// Builds every enabled route of the chat server, ready to be served or nested.
+pub fn app(config: &Config) -> Router {
+  let state = config.state.clone();
+  let mut guarded = Router::new()
+    .route("/users", get(user::get_user))
+    .route(
+      "/users/me",
+      get(user::get_user)
+        .patch(user::update_profile)
+        .delete(account::delete_account),
+    )
+    .route("/users/me/export", get(account::export_account))
+    .route(
+      "/users/me/blocks",
+      get(block::list_blocks).post(block::block_user),
+    )
+    .route("/users/me/blocks/:user_id", delete(block::unblock_user))
+    .route("/rooms", get(room::list_rooms))
+    .route("/rooms/create", post(room::create_room))
+    .route("/rooms/leave/:room_id", post(room::leave_room))
+    .route("/rooms/remove/:room_id", delete(room::remove_member))
+    .route("/rooms/join/:room_id", get(room::join_room))
+    .route("/rooms/messages/:room_id", get(room::list_messages))
+    .route("/rooms/invite/:room_id", post(room::invite_user))
+    .route("/auth/ws-ticket", post(session::ws_ticket))
+    .route("/auth/logout", post(session::logout))
+    .route("/auth/logout-all", post(session::logout_all))
+    .route("/users/me/sessions", get(session::list_sessions))
+    .route("/users/me/sessions/:id", delete(session::revoke_session))
+    .route(
+      "/users/me/totp",
+      post(two_factor::enrol_totp).delete(two_factor::disable_totp),
+    )
+    .route("/users/me/totp/confirm", post(two_factor::confirm_totp))
+    .route("/users/me/password", post(password::change_password));
+  if config.routes.search {
+    guarded = guarded
+      .route("/users/search", get(user::search_users))
+      .route("/users/:id", get(user::get_user_by_user_id));
+  }
+  if config.routes.presence {
+    guarded = guarded.route("/users/me/status", put(user::update_status));
+  }
+  if config.routes.direct_messages {
+    guarded = guarded.route("/dm/:user_id", post(room::open_direct_room));
+  }
+  if config.routes.multiplexed_socket {
+    guarded = guarded.route("/ws", get(room::connect));
+  }
+
+  // the routes that work without a login, limited by the address of the client
+  let public = Router::new()
+    .route("/users/signup", post(user::signup))
+    .route("/users/login", post(user::login))
+    .route("/users/login/totp", post(user::login_totp))
+    .route("/auth/refresh", post(session::refresh))
+    .route(
+      "/auth/password-reset",
+      post(password::request_password_reset),
+    )
+    .route(
+      "/auth/password-reset/confirm",
+      post(password::reset_password),
+    )
+    .route_layer(middleware::from_fn_with_state(state.clone(), limit_by_ip));
+
+  let mut router = guarded
+    .route_layer(middleware::from_fn_with_state(state.clone(), limit_by_user))
+    .route_layer(middleware::from_fn_with_state(state.clone(), guard))
+    .merge(public)
+    .route("/.well-known/jwks.json", get(jwks::jwks))
+    .route("/health", get(heath_check))
+    .route("/health/live", get(health::live))
+    .route("/health/ready", get(health::ready));
+  if config.routes.metrics {
+    router = router.route("/metrics", get(metrics::metrics));
+  }
+
+  router
+    .route_layer(middleware::from_fn_with_state(
+      state.clone(),
+      metrics::track_latency,
+    ))
+    .with_state(state)
+    .layer(middleware::from_fn(errors::attach_request_id))
+    .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER))
+    .layer(
+      TraceLayer::new_for_http()
+        .make_span_with(request_span)
+        .on_response(DefaultOnResponse::new().level(Level::INFO)),
+    )
+    .layer(SetRequestIdLayer::new(REQUEST_ID_HEADER, MakeRequestUuid))
+}
+
+/// Every HTTP request, and every socket upgraded from one, logs under this span.
+fn request_span<B>(request: &Request<B>) -> Span {
+  let request_id = request
+    .extensions()
+    .get::<RequestId>()
+    .and_then(|id| id.header_value().to_str().ok())
+    .unwrap_or_default();
+  tracing::info_span!(
+    "http_request",
+    method = %request.method(),
+    path = %request.uri().path(),
+    request_id,
+  )
+}
+
+/// Serves the chat server until `shutdown` resolves. The server then reports not ready for
+/// the drain grace period, stops accepting connections, closes every socket and flushes the
+/// pending database writes, giving up after the configured shutdown timeout.
+pub async fn serve(config: Config, shutdown: impl Future<Output = ()>) -> Result<(), BoxError> {
+  let lobby = config.state.lobby.clone();
+  let (stop_accepting_tx, stop_accepting_rx) = oneshot::channel::<()>();
+  let server = axum::Server::try_bind(&config.addr)?
+    .serve(app(&config).into_make_service_with_connect_info::<SocketAddr>())
+    .with_graceful_shutdown(async {
+      stop_accepting_rx.await.ok();
+    });
+  tokio::pin!(server);
+
+  info!(addr = %config.addr, "listening");
+  tokio::select! {
+    result = &mut server => return Ok(result?),
+    _ = shutdown => {}
+  }
+
+  lobby.begin_shutdown();
+  info!(
+    grace_secs = config.drain_grace.as_secs(),
+    "shutting down, reporting not ready"
+  );
+  tokio::select! {
+    result = &mut server => return Ok(result?),
+    _ = tokio::time::sleep(config.drain_grace) => {}
+  }
+
+  info!("closing sockets and flushing pending writes");
+  stop_accepting_tx.send(()).ok();
+  lobby.close_sockets(config.retry_after);
+  let drained = tokio::time::timeout(config.shutdown_timeout, async {
+    if let Err(e) = server.await {
+      warn!(error = %e, "error while shutting down the server");
+    }
+    lobby.drain().await;
+  })
+  .await;
+  if drained.is_err() {
+    warn!(
+      timeout_secs = config.shutdown_timeout.as_secs(),
+      "shutdown did not finish in time, exiting anyway"
+    );
+  }
+  Ok(())
+}
+
+async fn heath_check() -> &'static str {
+  "OK"
+}
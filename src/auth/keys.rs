@@ -0,0 +1,285 @@
+use crate::helpers::{get_env, get_env_or};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::errors::{Error, ErrorKind};
+use jsonwebtoken::jwk::{
+  AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, OctetKeyPairParameters,
+  OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{
+  decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+// Only for `Config::in_memory` and tests, `from_env` insists on a real secret.
+const DEFAULT_SECRET: &str = "secret";
+pub const DEFAULT_ISSUER: &str = "rust-tokio-chat-app";
+pub const DEFAULT_AUDIENCE: &str = "rust-tokio-chat-app";
+
+struct VerificationKey {
+  kid: String,
+  algorithm: Algorithm,
+  key: DecodingKey,
+  // what the jwks endpoint publishes, shared secrets are never published
+  jwk: Option<Jwk>,
+}
+
+/// Signs access tokens with one key and verifies them with any key still trusted, so
+/// the signing key can be rotated without invalidating the tokens already issued.
+pub struct JwtKeys {
+  kid: String,
+  algorithm: Algorithm,
+  signing_key: EncodingKey,
+  // the first one belongs to the signing key
+  verification_keys: Vec<VerificationKey>,
+  pub issuer: String,
+  pub audience: String,
+}
+
+impl Default for JwtKeys {
+  fn default() -> Self {
+    JwtKeys::hmac(DEFAULT_SECRET.as_bytes())
+  }
+}
+
+impl fmt::Debug for JwtKeys {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let kids: Vec<&str> = self
+      .verification_keys
+      .iter()
+      .map(|key| key.kid.as_str())
+      .collect();
+    f.debug_struct("JwtKeys")
+      .field("kid", &self.kid)
+      .field("algorithm", &self.algorithm)
+      .field("verification_keys", &kids)
+      .field("issuer", &self.issuer)
+      .field("audience", &self.audience)
+      .finish()
+  }
+}
+
+impl JwtKeys {
+  /// Signs with HS256 and a shared secret, which only this server can verify.
+  pub fn hmac(secret: &[u8]) -> Self {
+    let kid = thumbprint(&format!(
+      r#"{{"k":"{}","kty":"oct"}}"#,
+      URL_SAFE_NO_PAD.encode(secret)
+    ));
+    JwtKeys::new(
+      kid,
+      Algorithm::HS256,
+      EncodingKey::from_secret(secret),
+      DecodingKey::from_secret(secret),
+      None,
+    )
+  }
+
+  /// Signs with a PEM encoded private key, using RS256 for RSA and EdDSA for Ed25519
+  /// keys. The key id defaults to the RFC 7638 thumbprint of the public key.
+  pub fn from_pem(pem: &[u8]) -> Result<Self, Error> {
+    let parsed = pem::parse(pem).map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?;
+    let (algorithm, params, signing_key) = match parsed.tag.as_str() {
+      "RSA PRIVATE KEY" => {
+        let key_pair =
+          RsaKeyPair::from_der(&parsed.contents).map_err(|_| ErrorKind::InvalidRsaKey(""))?;
+        (
+          Algorithm::RS256,
+          rsa_parameters(&key_pair),
+          EncodingKey::from_rsa_pem(pem)?,
+        )
+      }
+      "PRIVATE KEY" => match RsaKeyPair::from_pkcs8(&parsed.contents) {
+        Ok(key_pair) => (
+          Algorithm::RS256,
+          rsa_parameters(&key_pair),
+          EncodingKey::from_rsa_pem(pem)?,
+        ),
+        Err(_) => {
+          let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&parsed.contents)
+            .map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?;
+          (
+            Algorithm::EdDSA,
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+              key_type: OctetKeyPairType::OctetKeyPair,
+              curve: EllipticCurve::Ed25519,
+              x: URL_SAFE_NO_PAD.encode(key_pair.public_key()),
+            }),
+            EncodingKey::from_ed_pem(pem)?,
+          )
+        }
+      },
+      _ => return Err(ErrorKind::InvalidKeyFormat.into()),
+    };
+    let kid = match &params {
+      AlgorithmParameters::RSA(rsa) => thumbprint(&format!(
+        r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#,
+        rsa.e, rsa.n
+      )),
+      AlgorithmParameters::OctetKeyPair(okp) => thumbprint(&format!(
+        r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#,
+        okp.x
+      )),
+      _ => unreachable!("only RSA and Ed25519 keys are read"),
+    };
+    let jwk = Jwk {
+      common: CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        algorithm: Some(algorithm),
+        key_id: Some(kid.clone()),
+        ..CommonParameters::default()
+      },
+      algorithm: params,
+    };
+    let decoding_key = DecodingKey::from_jwk(&jwk)?;
+    Ok(JwtKeys::new(
+      kid,
+      algorithm,
+      signing_key,
+      decoding_key,
+      Some(jwk),
+    ))
+  }
+
+  fn new(
+    kid: String,
+    algorithm: Algorithm,
+    signing_key: EncodingKey,
+    key: DecodingKey,
+    jwk: Option<Jwk>,
+  ) -> Self {
+    JwtKeys {
+      kid: kid.clone(),
+      algorithm,
+      signing_key,
+      verification_keys: vec![VerificationKey {
+        kid,
+        algorithm,
+        key,
+        jwk,
+      }],
+      issuer: DEFAULT_ISSUER.to_owned(),
+      audience: DEFAULT_AUDIENCE.to_owned(),
+    }
+  }
+
+  /// Reads `JWT_SIGNING_KEY`, the path of a PEM private key, or signs with HS256 and
+  /// `JWT_SECRET` when it is unset, one of the two is required. `JWT_KEY_ID` overrides the key id,
+  /// `JWT_VERIFICATION_KEYS` is the path of a JWK set of retired keys still accepted and
+  /// `JWT_ISSUER` and `JWT_AUDIENCE` set the claims tokens are issued and checked with.
+  pub fn from_env() -> Self {
+    let mut keys = match std::env::var("JWT_SIGNING_KEY") {
+      Ok(path) => {
+        let pem = std::fs::read(&path)
+          .unwrap_or_else(|e| panic!("couldn't read JWT_SIGNING_KEY {}: {}", path, e));
+        JwtKeys::from_pem(&pem)
+          .unwrap_or_else(|e| panic!("couldn't interpret JWT_SIGNING_KEY {}: {}", path, e))
+      }
+      Err(_) => JwtKeys::hmac(get_env("JWT_SECRET").as_bytes()),
+    };
+    if let Ok(kid) = std::env::var("JWT_KEY_ID") {
+      keys = keys.with_key_id(kid);
+    }
+    if let Ok(path) = std::env::var("JWT_VERIFICATION_KEYS") {
+      let jwks = std::fs::read(&path)
+        .ok()
+        .and_then(|json| serde_json::from_slice::<JwkSet>(&json).ok())
+        .unwrap_or_else(|| panic!("couldn't interpret JWT_VERIFICATION_KEYS {}", path));
+      keys = keys
+        .with_verification_keys(&jwks)
+        .unwrap_or_else(|e| panic!("couldn't interpret JWT_VERIFICATION_KEYS {}: {}", path, e));
+    }
+    keys.issuer = get_env_or("JWT_ISSUER", keys.issuer);
+    keys.audience = get_env_or("JWT_AUDIENCE", keys.audience);
+    keys
+  }
+
+  /// Names the signing key `kid` instead of its thumbprint.
+  pub fn with_key_id(mut self, kid: impl Into<String>) -> Self {
+    let kid = kid.into();
+    let key = &mut self.verification_keys[0];
+    key.kid = kid.clone();
+    if let Some(jwk) = &mut key.jwk {
+      jwk.common.key_id = Some(kid.clone());
+    }
+    self.kid = kid;
+    self
+  }
+
+  /// Keeps accepting tokens signed with the RSA and Ed25519 keys of `jwks`, typically
+  /// the keys published before the signing key was rotated. Every key needs a `kid`.
+  pub fn with_verification_keys(mut self, jwks: &JwkSet) -> Result<Self, Error> {
+    for jwk in &jwks.keys {
+      let kid = jwk
+        .common
+        .key_id
+        .clone()
+        .ok_or_else(|| Error::from(ErrorKind::InvalidKeyFormat))?;
+      let algorithm = match (&jwk.algorithm, jwk.common.algorithm) {
+        (AlgorithmParameters::RSA(_), Some(algorithm)) => algorithm,
+        (AlgorithmParameters::RSA(_), None) => Algorithm::RS256,
+        (AlgorithmParameters::OctetKeyPair(_), _) => Algorithm::EdDSA,
+        _ => return Err(ErrorKind::InvalidAlgorithm.into()),
+      };
+      self.verification_keys.push(VerificationKey {
+        kid,
+        algorithm,
+        key: DecodingKey::from_jwk(jwk)?,
+        jwk: Some(jwk.clone()),
+      });
+    }
+    Ok(self)
+  }
+
+  /// Signs the claims with the current key, naming it in the `kid` header.
+  pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+    let mut header = Header::new(self.algorithm);
+    header.kid = Some(self.kid.clone());
+    encode(&header, claims, &self.signing_key)
+  }
+
+  /// Verifies the token with the key its `kid` names, or the signing key when it names
+  /// none, and checks its expiry, issuer and audience.
+  pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, Error> {
+    let header = decode_header(token)?;
+    let key = match &header.kid {
+      Some(kid) => self.verification_keys.iter().find(|key| &key.kid == kid),
+      None => self.verification_keys.first(),
+    }
+    .ok_or_else(|| Error::from(ErrorKind::InvalidSignature))?;
+    let mut validation = Validation::new(key.algorithm);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+    validation.set_issuer(&[&self.issuer]);
+    validation.set_audience(&[&self.audience]);
+    Ok(decode::<T>(token, &key.key, &validation)?.claims)
+  }
+
+  /// The public keys tokens are verified with, as served on `/.well-known/jwks.json`.
+  pub fn jwks(&self) -> JwkSet {
+    JwkSet {
+      keys: self
+        .verification_keys
+        .iter()
+        .filter_map(|key| key.jwk.clone())
+        .collect(),
+    }
+  }
+}
+
+fn rsa_parameters(key_pair: &RsaKeyPair) -> AlgorithmParameters {
+  let public_key = key_pair.public_key();
+  AlgorithmParameters::RSA(RSAKeyParameters {
+    key_type: RSAKeyType::RSA,
+    n: URL_SAFE_NO_PAD.encode(public_key.modulus().big_endian_without_leading_zero()),
+    e: URL_SAFE_NO_PAD.encode(public_key.exponent().big_endian_without_leading_zero()),
+  })
+}
+
+// RFC 7638 thumbprint, `members` are the required members of the key in lexicographic
+// order without whitespace
+fn thumbprint(members: &str) -> String {
+  URL_SAFE_NO_PAD.encode(Sha256::digest(members.as_bytes()))
+}
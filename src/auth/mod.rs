@@ -0,0 +1,462 @@
+pub mod keys;
+pub mod totp;
+
+use crate::db::session::WsTicket;
+use crate::db::DbError;
+use crate::errors::{db_error_to_service_error, not_found_as, ErrorCode, ServiceError};
+use crate::helpers::{get_env, get_env_or};
+use crate::routes::SharedState;
+use axum::{
+  async_trait,
+  extract::{ConnectInfo, FromRequestParts, State},
+  headers::{authorization::Bearer, Authorization},
+  http::{
+    header::{SEC_WEBSOCKET_PROTOCOL, UPGRADE, USER_AGENT},
+    request::Parts,
+    HeaderMap, Request,
+  },
+  middleware::Next,
+  response::Response,
+  TypedHeader,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::errors::ErrorKind;
+use keys::JwtKeys;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use totp::SecretCipher;
+use tracing::warn;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "camelCase", deserialize = "camelCase"))]
+struct Claim {
+  user_id: i64,    // user id
+  session_id: i64, // session the token was issued for
+  exp: usize,      // expiry time
+  iat: usize,      // issued at
+  iss: String,     // issuer
+  aud: String,     // audience
+}
+
+const BEARER: &str = "Bearer ";
+// How stale `last_used_at` of a session may get before a request refreshes it.
+const SESSION_TOUCH_INTERVAL_SECS: i64 = 60;
+/// Subprotocol the socket endpoints agree to. Browsers pass a ticket as a second
+/// subprotocol, `ticket.<ticket>`, next to it.
+pub const WS_PROTOCOL: &str = "chat";
+const WS_TICKET_PROTOCOL_PREFIX: &str = "ticket.";
+
+/// Lifetimes of the issued tokens and the keys they are signed with.
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+  pub keys: Arc<JwtKeys>,
+  pub access_token_ttl: Duration,
+  // Every refresh extends the session by this long.
+  pub refresh_token_ttl: Duration,
+  // How long a websocket ticket can be redeemed.
+  pub ws_ticket_ttl: Duration,
+  // How long a login may wait for its second factor.
+  pub login_challenge_ttl: Duration,
+  pub password_reset_ttl: Duration,
+  pub totp_cipher: Arc<SecretCipher>,
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    AuthConfig {
+      keys: Arc::new(JwtKeys::default()),
+      access_token_ttl: Duration::from_secs(15 * 60),
+      refresh_token_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+      ws_ticket_ttl: Duration::from_secs(30),
+      login_challenge_ttl: Duration::from_secs(5 * 60),
+      password_reset_ttl: Duration::from_secs(30 * 60),
+      totp_cipher: Arc::new(SecretCipher::default()),
+    }
+  }
+}
+
+impl AuthConfig {
+  /// Reads `ACCESS_TOKEN_TTL_SECS`, `REFRESH_TOKEN_TTL_SECS`, `WS_TICKET_TTL_SECS`,
+  /// `LOGIN_CHALLENGE_TTL_SECS`, `PASSWORD_RESET_TTL_SECS` and the required
+  /// `TOTP_ENCRYPTION_KEY`, the keys are read by `JwtKeys::from_env`.
+  pub fn from_env() -> Self {
+    let default = AuthConfig::default();
+    AuthConfig {
+      keys: Arc::new(JwtKeys::from_env()),
+      access_token_ttl: Duration::from_secs(get_env_or(
+        "ACCESS_TOKEN_TTL_SECS",
+        default.access_token_ttl.as_secs(),
+      )),
+      refresh_token_ttl: Duration::from_secs(get_env_or(
+        "REFRESH_TOKEN_TTL_SECS",
+        default.refresh_token_ttl.as_secs(),
+      )),
+      ws_ticket_ttl: Duration::from_secs(get_env_or(
+        "WS_TICKET_TTL_SECS",
+        default.ws_ticket_ttl.as_secs(),
+      )),
+      login_challenge_ttl: Duration::from_secs(get_env_or(
+        "LOGIN_CHALLENGE_TTL_SECS",
+        default.login_challenge_ttl.as_secs(),
+      )),
+      password_reset_ttl: Duration::from_secs(get_env_or(
+        "PASSWORD_RESET_TTL_SECS",
+        default.password_reset_ttl.as_secs(),
+      )),
+      totp_cipher: Arc::new(SecretCipher::new(get_env("TOTP_ENCRYPTION_KEY").as_bytes())),
+    }
+  }
+}
+
+/// The session of the access token, added to the request extensions by `guard`.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionId(pub i64);
+
+/// Where a request comes from, recorded on the sessions it opens. The address is only
+/// known when the server is run with connect info, as `serve` does.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+  type Rejection = Infallible;
+
+  async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    Ok(ClientInfo {
+      user_agent: parts
+        .headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned),
+      ip_address: parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string()),
+    })
+  }
+}
+
+/// A fresh access token together with the refresh token that renews it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tokens {
+  pub auth_token: String,
+  pub refresh_token: String,
+  // lifetime of the access token in seconds
+  pub expires_in: u64,
+}
+
+/// A ticket authenticating a single websocket handshake.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ticket {
+  pub ticket: String,
+  // seconds left to redeem the ticket
+  pub expires_in: u64,
+}
+
+pub fn create_jwt(
+  keys: &JwtKeys,
+  user_id: i64,
+  session_id: i64,
+  ttl: Duration,
+) -> Result<String, ServiceError> {
+  let now = Utc::now().timestamp();
+  let claims = Claim {
+    user_id,
+    session_id,
+    exp: (now + ttl.as_secs() as i64) as usize,
+    iat: now as usize,
+    iss: keys.issuer.clone(),
+    aud: keys.audience.clone(),
+  };
+  keys
+    .encode(&claims)
+    .map_err(|_| ServiceError::new(ErrorCode::InternalError, "Failed to create jwt token"))
+}
+
+fn validate_token(keys: &JwtKeys, token: &str) -> Result<Claim, jsonwebtoken::errors::Error> {
+  let token = token.trim_start_matches(BEARER);
+  match keys.decode::<Claim>(token) {
+    Ok(claim) => {
+      let now = Utc::now().timestamp() as usize;
+      if now > claim.exp {
+        return Err(ErrorKind::ExpiredSignature.into());
+      }
+      Ok(claim)
+    }
+    Err(e) => Err(e),
+  }
+}
+
+/// Digest under which single-use secrets such as refresh tokens are stored.
+pub(crate) fn hash_token(token: &str) -> String {
+  format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Returns a random URL safe token and its digest.
+pub(crate) fn new_token() -> (String, String) {
+  let mut bytes = [0u8; 32];
+  rand::thread_rng().fill_bytes(&mut bytes);
+  let token = URL_SAFE_NO_PAD.encode(bytes);
+  let hash = hash_token(&token);
+  (token, hash)
+}
+
+/// Opens a new session for the user, as on signup and login.
+pub async fn start_session(
+  state: &SharedState,
+  user_id: i64,
+  client: ClientInfo,
+) -> Result<Tokens, ServiceError> {
+  let (refresh_token, refresh_token_hash) = new_token();
+  let session = state
+    .repos
+    .sessions
+    .create_session(
+      user_id,
+      &refresh_token_hash,
+      refresh_expiry(state),
+      client.user_agent,
+      client.ip_address,
+    )
+    .await
+    .map_err(db_error_to_service_error)?;
+  issue_tokens(state, session.user_id, session.id, refresh_token)
+}
+
+/// Trades a refresh token for new tokens. The refresh token is rotated, presenting an
+/// already rotated one revokes the whole session as the token has most likely leaked.
+pub async fn refresh_session(
+  state: &SharedState,
+  refresh_token: &str,
+) -> Result<Tokens, ServiceError> {
+  let refresh_token_hash = hash_token(refresh_token);
+  let session = state
+    .repos
+    .sessions
+    .find_session_by_refresh_token(&refresh_token_hash)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::InvalidRefreshToken,
+      "Invalid refresh token",
+    ))?;
+  if session.refresh_token_hash != refresh_token_hash {
+    state.lobby.metrics.auth_failure("refresh_token_reuse");
+    warn!(
+      user_id = session.user_id,
+      session_id = session.id,
+      "rotated refresh token presented again, revoking the session"
+    );
+    state
+      .repos
+      .sessions
+      .revoke_session(session.user_id, session.id)
+      .await
+      .map_err(db_error_to_service_error)?;
+    return Err(ServiceError::new(
+      ErrorCode::InvalidRefreshToken,
+      "Refresh token was already used",
+    ));
+  }
+  if !session.is_active() {
+    state.lobby.metrics.auth_failure("inactive_session");
+    return Err(ServiceError::new(
+      ErrorCode::SessionRevoked,
+      "Session has expired or was revoked",
+    ));
+  }
+
+  let (new_refresh_token, new_refresh_token_hash) = new_token();
+  let session = state
+    .repos
+    .sessions
+    .rotate_refresh_token(
+      session.id,
+      &refresh_token_hash,
+      &new_refresh_token_hash,
+      refresh_expiry(state),
+    )
+    .await
+    .map_err(not_found_as(
+      ErrorCode::InvalidRefreshToken,
+      "Refresh token was already used",
+    ))?;
+  issue_tokens(state, session.user_id, session.id, new_refresh_token)
+}
+
+/// Issues a ticket for the session, to be passed on a websocket handshake in place of
+/// the bearer token, which browsers cannot set there.
+pub async fn issue_ws_ticket(
+  state: &SharedState,
+  user_id: i64,
+  session_id: i64,
+) -> Result<Ticket, ServiceError> {
+  let (ticket, ticket_hash) = new_token();
+  let ttl = state.auth.ws_ticket_ttl;
+  state
+    .repos
+    .sessions
+    .create_ws_ticket(&WsTicket {
+      ticket_hash,
+      user_id,
+      session_id,
+      expires_at: Utc::now() + chrono::Duration::from_std(ttl).unwrap(),
+    })
+    .await
+    .map_err(db_error_to_service_error)?;
+  Ok(Ticket {
+    ticket,
+    expires_in: ttl.as_secs(),
+  })
+}
+
+fn refresh_expiry(state: &SharedState) -> chrono::DateTime<Utc> {
+  Utc::now() + chrono::Duration::from_std(state.auth.refresh_token_ttl).unwrap()
+}
+
+fn issue_tokens(
+  state: &SharedState,
+  user_id: i64,
+  session_id: i64,
+  refresh_token: String,
+) -> Result<Tokens, ServiceError> {
+  let ttl = state.auth.access_token_ttl;
+  Ok(Tokens {
+    auth_token: create_jwt(&state.auth.keys, user_id, session_id, ttl)?,
+    refresh_token,
+    expires_in: ttl.as_secs(),
+  })
+}
+
+/// Authenticates the request by its bearer token. Websocket handshakes may present a
+/// ticket from `issue_ws_ticket` instead, in the `ticket` query parameter or as a
+/// `ticket.<ticket>` subprotocol.
+pub async fn guard<T>(
+  State(state): State<SharedState>,
+  token: Option<TypedHeader<Authorization<Bearer>>>,
+  mut request: Request<T>,
+  next: Next<T>,
+) -> Result<Response, ServiceError> {
+  let (user_id, session_id) = match token {
+    Some(TypedHeader(token)) => authenticate_token(&state, token.token()).await?,
+    None => match ws_ticket(&request) {
+      Some(ticket) => redeem_ws_ticket(&state, &ticket).await?,
+      None => {
+        state.lobby.metrics.auth_failure("missing_token");
+        return Err(ServiceError::new(
+          ErrorCode::Unauthorized,
+          "Missing bearer token",
+        ));
+      }
+    },
+  };
+  check_session(&state, user_id, session_id).await?;
+
+  request.extensions_mut().insert(user_id);
+  request.extensions_mut().insert(SessionId(session_id));
+  Ok(next.run(request).await)
+}
+
+async fn authenticate_token(state: &SharedState, token: &str) -> Result<(i64, i64), ServiceError> {
+  match validate_token(&state.auth.keys, token) {
+    Ok(claim) => Ok((claim.user_id, claim.session_id)),
+    Err(e) if *e.kind() == ErrorKind::ExpiredSignature => {
+      state.lobby.metrics.auth_failure("expired_token");
+      Err(ServiceError::new(ErrorCode::TokenExpired, "Token expired"))
+    }
+    Err(_) => {
+      state.lobby.metrics.auth_failure("invalid_token");
+      Err(ServiceError::new(ErrorCode::Unauthorized, "Unauthorized"))
+    }
+  }
+}
+
+// Tickets are only looked for on websocket handshakes, other routes need the bearer token.
+fn ws_ticket<T>(request: &Request<T>) -> Option<String> {
+  if !is_websocket_upgrade(request.headers()) {
+    return None;
+  }
+  let from_query = request.uri().query().and_then(|query| {
+    query
+      .split('&')
+      .find_map(|pair| pair.strip_prefix("ticket="))
+      .map(str::to_owned)
+  });
+  from_query.or_else(|| {
+    request
+      .headers()
+      .get_all(SEC_WEBSOCKET_PROTOCOL)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .find_map(|protocol| protocol.trim().strip_prefix(WS_TICKET_PROTOCOL_PREFIX))
+      .map(str::to_owned)
+  })
+}
+
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+  headers
+    .get(UPGRADE)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+async fn redeem_ws_ticket(state: &SharedState, ticket: &str) -> Result<(i64, i64), ServiceError> {
+  match state
+    .repos
+    .sessions
+    .take_ws_ticket(&hash_token(ticket))
+    .await
+  {
+    Ok(ticket) if ticket.expires_at > Utc::now() => Ok((ticket.user_id, ticket.session_id)),
+    Ok(_) | Err(DbError::NotFound) => {
+      state.lobby.metrics.auth_failure("invalid_ticket");
+      Err(ServiceError::new(
+        ErrorCode::InvalidTicket,
+        "Ticket is unknown, expired or was already used",
+      ))
+    }
+    Err(e) => Err(db_error_to_service_error(e)),
+  }
+}
+
+async fn check_session(
+  state: &SharedState,
+  user_id: i64,
+  session_id: i64,
+) -> Result<(), ServiceError> {
+  if let Err(e) = state.repos.users.get_user_by_id(user_id).await {
+    state.lobby.metrics.auth_failure("unknown_user");
+    return Err(not_found_as(ErrorCode::Unauthorized, "Unauthorized")(e));
+  }
+  match state.repos.sessions.get_session_by_id(session_id).await {
+    Ok(session) if session.is_active() && session.user_id == user_id => {
+      let idle = Utc::now() - session.last_used_at;
+      if idle.num_seconds() >= SESSION_TOUCH_INTERVAL_SECS {
+        if let Err(e) = state.repos.sessions.touch_session(session.id).await {
+          warn!(session_id = session.id, error = %e, "error updating last_used_at");
+        }
+      }
+      Ok(())
+    }
+    Ok(_) | Err(DbError::NotFound) => {
+      state.lobby.metrics.auth_failure("revoked_session");
+      Err(ServiceError::new(
+        ErrorCode::SessionRevoked,
+        "Session has expired or was revoked",
+      ))
+    }
+    Err(e) => Err(db_error_to_service_error(e)),
+  }
+}
@@ -0,0 +1,341 @@
+use super::{hash_token, new_token};
+use crate::db::two_factor::{LoginChallenge, UserTotp};
+use crate::db::DbError;
+use crate::errors::{db_error_to_service_error, ErrorCode, ServiceError};
+use crate::routes::SharedState;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::{constant_time, hmac};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+const STEP_SECS: i64 = 30;
+const DIGITS: usize = 6;
+// Codes of the neighbouring steps are accepted as well, for clocks that are a bit off.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+// Wrong codes a login challenge survives, the password has to be entered again after.
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+const ISSUER: &str = "rust-tokio-chat-app";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Encrypts TOTP secrets at rest with AES-256-GCM, the key being the SHA-256 digest of
+/// `TOTP_ENCRYPTION_KEY`.
+pub struct SecretCipher {
+  key: LessSafeKey,
+}
+
+// Only for `Config::in_memory` and tests, `AuthConfig::from_env` insists on a real key.
+impl Default for SecretCipher {
+  fn default() -> Self {
+    SecretCipher::new(b"secret")
+  }
+}
+
+impl fmt::Debug for SecretCipher {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("SecretCipher")
+  }
+}
+
+impl SecretCipher {
+  pub fn new(passphrase: &[u8]) -> Self {
+    let key = UnboundKey::new(&AES_256_GCM, &Sha256::digest(passphrase)).unwrap();
+    SecretCipher {
+      key: LessSafeKey::new(key),
+    }
+  }
+
+  /// Encrypts the secret of a user, the ciphertext cannot be moved to another user.
+  pub fn seal(&self, user_id: i64, secret: &[u8]) -> String {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut sealed = secret.to_vec();
+    self
+      .key
+      .seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(user_id.to_be_bytes()),
+        &mut sealed,
+      )
+      .unwrap();
+    STANDARD.encode([&nonce[..], &sealed].concat())
+  }
+
+  /// `None` when the ciphertext was tampered with or sealed with another key.
+  pub fn open(&self, user_id: i64, sealed: &str) -> Option<Vec<u8>> {
+    let sealed = STANDARD.decode(sealed).ok()?;
+    if sealed.len() < NONCE_LEN {
+      return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let mut in_out = ciphertext.to_vec();
+    let secret = self
+      .key
+      .open_in_place(
+        Nonce::try_assume_unique_for_key(nonce).ok()?,
+        Aad::from(user_id.to_be_bytes()),
+        &mut in_out,
+      )
+      .ok()?;
+    Some(secret.to_vec())
+  }
+}
+
+pub fn new_secret() -> Vec<u8> {
+  let mut secret = vec![0u8; SECRET_LEN];
+  rand::thread_rng().fill_bytes(&mut secret);
+  secret
+}
+
+/// The `otpauth://` URI authenticator apps enrol from, usually shown as a QR code.
+pub fn provisioning_uri(secret: &[u8], account: &str) -> String {
+  format!(
+    "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+    percent_encode(ISSUER),
+    percent_encode(account),
+    base32_encode(secret),
+    percent_encode(ISSUER),
+    DIGITS,
+    STEP_SECS
+  )
+}
+
+pub fn current_step() -> i64 {
+  Utc::now().timestamp() / STEP_SECS
+}
+
+/// The RFC 6238 code of a time step.
+pub fn code_at(secret: &[u8], step: i64) -> String {
+  let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+  let digest = hmac::sign(&key, &step.to_be_bytes());
+  let digest = digest.as_ref();
+  let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+  let truncated = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+  format!(
+    "{:0width$}",
+    truncated % 10u32.pow(DIGITS as u32),
+    width = DIGITS
+  )
+}
+
+/// Returns the time step `code` belongs to, if it is valid now.
+pub fn verify_code(secret: &[u8], code: &str) -> Option<i64> {
+  let now = current_step();
+  (now - ALLOWED_DRIFT_STEPS..=now + ALLOWED_DRIFT_STEPS).find(|step| {
+    constant_time::verify_slices_are_equal(code_at(secret, *step).as_bytes(), code.as_bytes())
+      .is_ok()
+  })
+}
+
+/// Fresh one-time recovery codes, `xxxx-xxxx-xxxx-xxxx`.
+pub fn new_recovery_codes() -> Vec<String> {
+  (0..RECOVERY_CODE_COUNT)
+    .map(|_| {
+      let mut bytes = [0u8; 10];
+      rand::thread_rng().fill_bytes(&mut bytes);
+      let code = base32_encode(&bytes).to_lowercase();
+      let groups: Vec<&str> = (0..code.len())
+        .step_by(4)
+        .map(|i| &code[i..i + 4])
+        .collect();
+      groups.join("-")
+    })
+    .collect()
+}
+
+/// Recovery codes are stored like refresh tokens, ignoring dashes, spaces and case.
+pub fn hash_recovery_code(code: &str) -> String {
+  let normalized: String = code
+    .chars()
+    .filter(|c| *c != '-' && !c.is_whitespace())
+    .collect();
+  hash_token(&normalized.to_lowercase())
+}
+
+pub fn base32_encode(bytes: &[u8]) -> String {
+  let mut encoded = String::new();
+  let (mut buffer, mut bits) = (0u32, 0);
+  for byte in bytes {
+    buffer = (buffer << 8) | *byte as u32;
+    bits += 8;
+    while bits >= 5 {
+      bits -= 5;
+      encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+    }
+  }
+  if bits > 0 {
+    encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+  }
+  encoded
+}
+
+/// Decodes unpadded RFC 4648 base32, as authenticator apps display secrets.
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+  let mut decoded = Vec::new();
+  let (mut buffer, mut bits) = (0u32, 0);
+  for c in encoded.trim_end_matches('=').bytes() {
+    let value = BASE32_ALPHABET
+      .iter()
+      .position(|a| *a == c.to_ascii_uppercase())?;
+    buffer = (buffer << 5) | value as u32;
+    bits += 5;
+    if bits >= 8 {
+      bits -= 8;
+      decoded.push((buffer >> bits) as u8);
+    }
+  }
+  Some(decoded)
+}
+
+fn percent_encode(value: &str) -> String {
+  value
+    .bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (b as char).to_string()
+      }
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+/// Checks a code of the user's authenticator or one of the recovery codes, which is
+/// used up. An authenticator code is accepted once only.
+pub async fn check_second_factor(
+  state: &SharedState,
+  totp: &UserTotp,
+  code: &str,
+) -> Result<bool, ServiceError> {
+  let code = code.trim();
+  let used = if code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit()) {
+    let secret = open_secret(state, totp)?;
+    match verify_code(&secret, code) {
+      Some(step) => {
+        state
+          .repos
+          .two_factor
+          .use_totp_step(totp.user_id, step)
+          .await
+      }
+      None => return Ok(false),
+    }
+  } else {
+    state
+      .repos
+      .two_factor
+      .use_recovery_code(totp.user_id, &hash_recovery_code(code))
+      .await
+  };
+  match used {
+    Ok(_) => Ok(true),
+    Err(DbError::NotFound) => Ok(false),
+    Err(e) => Err(db_error_to_service_error(e)),
+  }
+}
+
+pub fn open_secret(state: &SharedState, totp: &UserTotp) -> Result<Vec<u8>, ServiceError> {
+  state
+    .auth
+    .totp_cipher
+    .open(totp.user_id, &totp.secret_ciphertext)
+    .ok_or_else(|| {
+      ServiceError::new(
+        ErrorCode::InternalError,
+        "Failed to decrypt the authenticator secret",
+      )
+    })
+}
+
+/// Starts the second step of a login, returning the challenge token and its lifetime.
+pub async fn start_login_challenge(
+  state: &SharedState,
+  user_id: i64,
+) -> Result<(String, u64), ServiceError> {
+  let (token, challenge_hash) = new_token();
+  let ttl = state.auth.login_challenge_ttl;
+  state
+    .repos
+    .two_factor
+    .create_login_challenge(&LoginChallenge {
+      challenge_hash,
+      user_id,
+      expires_at: Utc::now() + chrono::Duration::from_std(ttl).unwrap(),
+      failed_attempts: 0,
+    })
+    .await
+    .map_err(db_error_to_service_error)?;
+  Ok((token, ttl.as_secs()))
+}
+
+/// Completes a login with the second factor, returning the id of the user. Too many
+/// wrong codes end the challenge.
+pub async fn complete_login_challenge(
+  state: &SharedState,
+  token: &str,
+  code: &str,
+) -> Result<i64, ServiceError> {
+  let invalid_challenge = || {
+    state.lobby.metrics.auth_failure("invalid_login_challenge");
+    ServiceError::new(
+      ErrorCode::InvalidLoginChallenge,
+      "Login challenge is unknown, expired or was already used",
+    )
+  };
+  let challenge_hash = hash_token(token);
+  let challenge = match state
+    .repos
+    .two_factor
+    .get_login_challenge(&challenge_hash)
+    .await
+  {
+    Ok(challenge) if challenge.expires_at > Utc::now() => challenge,
+    Ok(_) | Err(DbError::NotFound) => return Err(invalid_challenge()),
+    Err(e) => return Err(db_error_to_service_error(e)),
+  };
+  let totp = match state
+    .repos
+    .two_factor
+    .get_user_totp(challenge.user_id)
+    .await
+  {
+    Ok(totp) => totp,
+    // the authenticator was removed since the password was checked
+    Err(DbError::NotFound) => return Err(invalid_challenge()),
+    Err(e) => return Err(db_error_to_service_error(e)),
+  };
+
+  if !check_second_factor(state, &totp, code).await? {
+    state.lobby.metrics.auth_failure("invalid_totp_code");
+    let failed = state
+      .repos
+      .two_factor
+      .record_failed_challenge(&challenge_hash)
+      .await;
+    if matches!(failed, Ok(c) if c.failed_attempts >= MAX_CHALLENGE_ATTEMPTS) {
+      let _ = state
+        .repos
+        .two_factor
+        .delete_login_challenge(&challenge_hash)
+        .await;
+    }
+    return Err(ServiceError::new(
+      ErrorCode::InvalidTotpCode,
+      "Invalid authentication code",
+    ));
+  }
+  match state
+    .repos
+    .two_factor
+    .delete_login_challenge(&challenge_hash)
+    .await
+  {
+    Ok(challenge) => Ok(challenge.user_id),
+    Err(DbError::NotFound) => Err(invalid_challenge()),
+    Err(e) => Err(db_error_to_service_error(e)),
+  }
+}
@@ -0,0 +1,94 @@
+use super::{DbError, PostgresRepo};
+use async_trait::async_trait;
+use bb8::PooledConnection;
+use bb8_postgres::PostgresConnectionManager;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::NoTls;
+
+/// The blocker does not see anything the blocked user says and the two cannot talk
+/// directly.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UserBlock {
+  pub blocker_id: i64,
+  pub blocked_id: i64,
+  pub created_at: DateTime<Utc>,
+}
+
+pub async fn block_user(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  blocker_id: i64,
+  blocked_id: i64,
+) -> Result<UserBlock, tokio_postgres::Error> {
+  // blocking twice keeps the first block, the no-op update makes it return the row
+  let query = "INSERT INTO user_block (blocker_id, blocked_id) VALUES ($1, $2) ON CONFLICT (blocker_id, blocked_id) DO UPDATE SET created_at = user_block.created_at RETURNING *";
+  let row = conn.query_one(query, &[&blocker_id, &blocked_id]).await?;
+  Ok(row_to_user_block(row))
+}
+
+pub async fn unblock_user(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  blocker_id: i64,
+  blocked_id: i64,
+) -> Result<UserBlock, tokio_postgres::Error> {
+  let query = "DELETE FROM user_block WHERE blocker_id = $1 AND blocked_id = $2 RETURNING *";
+  let row = conn.query_one(query, &[&blocker_id, &blocked_id]).await?;
+  Ok(row_to_user_block(row))
+}
+
+pub async fn list_blocked_users(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  blocker_id: i64,
+) -> Result<Vec<UserBlock>, tokio_postgres::Error> {
+  let query = "SELECT * FROM user_block WHERE blocker_id = $1 ORDER BY created_at ASC";
+  let rows = conn.query(query, &[&blocker_id]).await?;
+  Ok(rows.into_iter().map(row_to_user_block).collect())
+}
+
+pub async fn is_blocked_between(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  other_user_id: i64,
+) -> Result<bool, tokio_postgres::Error> {
+  let query = "SELECT EXISTS (SELECT 1 FROM user_block WHERE (blocker_id = $1 AND blocked_id = $2) OR (blocker_id = $2 AND blocked_id = $1))";
+  let row = conn.query_one(query, &[&user_id, &other_user_id]).await?;
+  Ok(row.get(0))
+}
+
+fn row_to_user_block(row: tokio_postgres::Row) -> UserBlock {
+  UserBlock {
+    blocker_id: row.get(0),
+    blocked_id: row.get(1),
+    created_at: row.get(2),
+  }
+}
+
+#[async_trait]
+pub trait BlockRepo: Send + Sync {
+  /// Blocks the user, blocking again returns the existing block.
+  async fn block_user(&self, blocker_id: i64, blocked_id: i64) -> Result<UserBlock, DbError>;
+  async fn unblock_user(&self, blocker_id: i64, blocked_id: i64) -> Result<UserBlock, DbError>;
+  /// Lists the users blocked by `blocker_id`, oldest block first.
+  async fn list_blocked_users(&self, blocker_id: i64) -> Result<Vec<UserBlock>, DbError>;
+  /// Whether either user blocked the other.
+  async fn is_blocked_between(&self, user_id: i64, other_user_id: i64) -> Result<bool, DbError>;
+}
+
+#[async_trait]
+impl BlockRepo for PostgresRepo {
+  async fn block_user(&self, blocker_id: i64, blocked_id: i64) -> Result<UserBlock, DbError> {
+    Ok(block_user(&mut self.conn().await?, blocker_id, blocked_id).await?)
+  }
+
+  async fn unblock_user(&self, blocker_id: i64, blocked_id: i64) -> Result<UserBlock, DbError> {
+    Ok(unblock_user(&mut self.conn().await?, blocker_id, blocked_id).await?)
+  }
+
+  async fn list_blocked_users(&self, blocker_id: i64) -> Result<Vec<UserBlock>, DbError> {
+    Ok(list_blocked_users(&mut self.conn().await?, blocker_id).await?)
+  }
+
+  async fn is_blocked_between(&self, user_id: i64, other_user_id: i64) -> Result<bool, DbError> {
+    Ok(is_blocked_between(&mut self.conn().await?, user_id, other_user_id).await?)
+  }
+}
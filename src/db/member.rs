@@ -1,3 +1,5 @@
+use super::{DbError, PostgresRepo};
+use async_trait::async_trait;
 use bb8::PooledConnection;
 use bb8_postgres::PostgresConnectionManager;
 use chrono::DateTime;
@@ -66,6 +68,24 @@ pub async fn count_active_members(
   Ok(count)
 }
 
+pub async fn list_user_memberships(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+) -> Result<Vec<Member>, tokio_postgres::Error> {
+  let query = "SELECT * FROM room_member WHERE user_id = $1 ORDER BY created_at ASC, id ASC";
+  let rows = conn.query(query, &[&user_id]).await?;
+  Ok(rows.into_iter().map(row_to_member).collect())
+}
+
+pub async fn list_active_members(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  room_id: i64,
+) -> Result<Vec<Member>, tokio_postgres::Error> {
+  let query = "SELECT * FROM room_member WHERE room_id = $1 AND deleted_at is NULL ORDER BY created_at ASC, id ASC";
+  let rows = conn.query(query, &[&room_id]).await?;
+  Ok(rows.into_iter().map(row_to_member).collect())
+}
+
 pub async fn update_last_joined_at(
   conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
   room_id: i64,
@@ -76,7 +96,7 @@ pub async fn update_last_joined_at(
   Ok(())
 }
 
-fn row_to_member(row: tokio_postgres::Row) -> Member {
+pub(crate) fn row_to_member(row: tokio_postgres::Row) -> Member {
   let id: i64 = row.get(0);
   let room_id: i64 = row.get(1);
   let user_id: i64 = row.get(2);
@@ -92,3 +112,52 @@ fn row_to_member(row: tokio_postgres::Row) -> Member {
     deleted_at,
   }
 }
+
+#[async_trait]
+pub trait MemberRepo: Send + Sync {
+  async fn create_new_member(&self, room_id: i64, user_id: i64) -> Result<Member, DbError>;
+  async fn get_member(&self, room_id: i64, user_id: i64) -> Result<Member, DbError>;
+  async fn get_member_by_id(&self, id: i64) -> Result<Member, DbError>;
+  async fn delete_member(&self, room_id: i64, user_id: i64) -> Result<i64, DbError>;
+  async fn count_active_members(&self, room_id: i64) -> Result<i64, DbError>;
+  async fn update_last_joined_at(&self, room_id: i64, user_id: i64) -> Result<(), DbError>;
+  /// Lists every membership of the user, the ones left included, oldest first.
+  async fn list_user_memberships(&self, user_id: i64) -> Result<Vec<Member>, DbError>;
+  /// Lists the current members of the room, the longest standing first.
+  async fn list_active_members(&self, room_id: i64) -> Result<Vec<Member>, DbError>;
+}
+
+#[async_trait]
+impl MemberRepo for PostgresRepo {
+  async fn create_new_member(&self, room_id: i64, user_id: i64) -> Result<Member, DbError> {
+    Ok(create_new_member(&mut self.conn().await?, room_id, user_id).await?)
+  }
+
+  async fn get_member(&self, room_id: i64, user_id: i64) -> Result<Member, DbError> {
+    Ok(get_member(&mut self.conn().await?, room_id, user_id).await?)
+  }
+
+  async fn get_member_by_id(&self, id: i64) -> Result<Member, DbError> {
+    Ok(get_member_by_id(&mut self.conn().await?, id).await?)
+  }
+
+  async fn delete_member(&self, room_id: i64, user_id: i64) -> Result<i64, DbError> {
+    Ok(delete_member(&mut self.conn().await?, room_id, user_id).await?)
+  }
+
+  async fn count_active_members(&self, room_id: i64) -> Result<i64, DbError> {
+    Ok(count_active_members(&mut self.conn().await?, room_id).await?)
+  }
+
+  async fn update_last_joined_at(&self, room_id: i64, user_id: i64) -> Result<(), DbError> {
+    Ok(update_last_joined_at(&mut self.conn().await?, room_id, user_id).await?)
+  }
+
+  async fn list_user_memberships(&self, user_id: i64) -> Result<Vec<Member>, DbError> {
+    Ok(list_user_memberships(&mut self.conn().await?, user_id).await?)
+  }
+
+  async fn list_active_members(&self, room_id: i64) -> Result<Vec<Member>, DbError> {
+    Ok(list_active_members(&mut self.conn().await?, room_id).await?)
+  }
+}
@@ -0,0 +1,984 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+
+use super::block::{BlockRepo, UserBlock};
+use super::member::{Member, MemberRepo};
+use super::message::{Message, MessageRepo, RoomMessage};
+use super::room::{direct_room_name, Room, RoomRepo};
+use super::session::{Session, SessionRepo, WsTicket};
+use super::two_factor::{LoginChallenge, TwoFactorRepo, UserTotp};
+use super::user::{
+  AccountDeletion, PasswordReset, User, UserProfile, UserRepo, UserStatus, DELETED_USER_NAME,
+};
+use super::DbError;
+
+/// Keeps every table in process memory, mirroring the constraints of the Postgres schema.
+#[derive(Default)]
+pub struct InMemoryRepo {
+  tables: Mutex<Tables>,
+}
+
+#[derive(Default)]
+struct Tables {
+  users: Vec<User>,
+  rooms: Vec<Room>,
+  members: Vec<Member>,
+  messages: Vec<Message>,
+  sessions: Vec<Session>,
+  ws_tickets: Vec<WsTicket>,
+  totps: Vec<UserTotp>,
+  login_challenges: Vec<LoginChallenge>,
+  password_resets: Vec<PasswordReset>,
+  blocks: Vec<UserBlock>,
+  // last id handed out, one counter serves every table
+  last_id: i64,
+}
+
+impl Tables {
+  fn next_id(&mut self) -> i64 {
+    self.last_id += 1;
+    self.last_id
+  }
+
+  fn user_exists(&self, id: i64) -> bool {
+    self.users.iter().any(|u| u.id == id)
+  }
+
+  fn user_mut(&mut self, id: i64) -> Result<&mut User, DbError> {
+    self
+      .users
+      .iter_mut()
+      .find(|u| u.id == id)
+      .ok_or(DbError::NotFound)
+  }
+
+  fn room_mut(&mut self, id: i64) -> Result<&mut Room, DbError> {
+    self
+      .rooms
+      .iter_mut()
+      .find(|r| r.id == id)
+      .ok_or(DbError::NotFound)
+  }
+
+  fn totp_mut(&mut self, user_id: i64) -> Result<&mut UserTotp, DbError> {
+    self
+      .totps
+      .iter_mut()
+      .find(|t| t.user_id == user_id)
+      .ok_or(DbError::NotFound)
+  }
+
+  fn login_challenge_mut(&mut self, challenge_hash: &str) -> Result<&mut LoginChallenge, DbError> {
+    self
+      .login_challenges
+      .iter_mut()
+      .find(|c| c.challenge_hash == challenge_hash)
+      .ok_or(DbError::NotFound)
+  }
+
+  fn active_member(&self, room_id: i64, user_id: i64) -> Option<&Member> {
+    self
+      .members
+      .iter()
+      .find(|m| m.room_id == room_id && m.user_id == user_id && m.deleted_at.is_none())
+  }
+}
+
+/// Returns the only row matching, like `query_one` does.
+fn one<'a, T: Clone + 'a>(mut rows: impl Iterator<Item = &'a T>) -> Result<T, DbError> {
+  match (rows.next(), rows.next()) {
+    (Some(row), None) => Ok(row.clone()),
+    _ => Err(DbError::NotFound),
+  }
+}
+
+impl InMemoryRepo {
+  fn tables(&self) -> std::sync::MutexGuard<'_, Tables> {
+    self.tables.lock().unwrap()
+  }
+}
+
+#[async_trait]
+impl UserRepo for InMemoryRepo {
+  async fn insert_new_user(
+    &self,
+    name: String,
+    password: String,
+    email: Option<String>,
+  ) -> Result<User, DbError> {
+    let mut tables = self.tables();
+    if tables
+      .users
+      .iter()
+      .any(|u| u.name == name || (email.is_some() && u.email == email))
+    {
+      return Err(DbError::UniqueViolation);
+    }
+    let user = User {
+      id: tables.next_id(),
+      name,
+      password,
+      created_at: Utc::now(),
+      status: None,
+      status_text: None,
+      last_seen_at: None,
+      email,
+      display_name: None,
+      bio: None,
+      avatar_url: None,
+      deleted_at: None,
+      hidden_from_search: false,
+      online_instances: 0,
+    };
+    tables.users.push(user.clone());
+    Ok(user)
+  }
+
+  async fn get_user(&self, name: String, password: String) -> Result<User, DbError> {
+    let tables = self.tables();
+    one(
+      tables
+        .users
+        .iter()
+        .filter(|u| u.name == name && u.password == password),
+    )
+  }
+
+  async fn get_user_by_name(&self, name: String) -> Result<User, DbError> {
+    one(self.tables().users.iter().filter(|u| u.name == name))
+  }
+
+  async fn get_user_by_id(&self, id: i64) -> Result<User, DbError> {
+    one(self.tables().users.iter().filter(|u| u.id == id))
+  }
+
+  async fn get_live_user_by_id(&self, id: i64) -> Result<User, DbError> {
+    one(
+      self
+        .tables()
+        .users
+        .iter()
+        .filter(|u| u.id == id && u.deleted_at.is_none()),
+    )
+  }
+
+  async fn get_user_by_email(&self, email: &str) -> Result<User, DbError> {
+    one(
+      self
+        .tables()
+        .users
+        .iter()
+        .filter(|u| u.email.as_deref() == Some(email)),
+    )
+  }
+
+  async fn update_password(&self, id: i64, password: &str) -> Result<User, DbError> {
+    let mut tables = self.tables();
+    let user = tables.user_mut(id)?;
+    user.password = password.to_owned();
+    Ok(user.clone())
+  }
+
+  async fn create_password_reset(&self, reset: &PasswordReset) -> Result<(), DbError> {
+    let now = Utc::now();
+    let mut tables = self.tables();
+    if !tables.user_exists(reset.user_id) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    tables.password_resets.retain(|r| r.expires_at >= now);
+    if tables
+      .password_resets
+      .iter()
+      .any(|r| r.token_hash == reset.token_hash)
+    {
+      return Err(DbError::UniqueViolation);
+    }
+    tables.password_resets.push(reset.clone());
+    Ok(())
+  }
+
+  async fn take_password_reset(&self, token_hash: &str) -> Result<PasswordReset, DbError> {
+    let mut tables = self.tables();
+    let reset = tables
+      .password_resets
+      .iter()
+      .find(|r| r.token_hash == token_hash)
+      .cloned()
+      .ok_or(DbError::NotFound)?;
+    tables
+      .password_resets
+      .retain(|r| r.user_id != reset.user_id);
+    Ok(reset)
+  }
+
+  async fn update_status(
+    &self,
+    id: i64,
+    status: Option<UserStatus>,
+    status_text: Option<String>,
+  ) -> Result<User, DbError> {
+    let mut tables = self.tables();
+    let user = tables.user_mut(id)?;
+    user.status = status;
+    user.status_text = status_text;
+    Ok(user.clone())
+  }
+
+  async fn update_profile(&self, id: i64, profile: &UserProfile) -> Result<User, DbError> {
+    let mut tables = self.tables();
+    if tables
+      .users
+      .iter()
+      .any(|u| u.id != id && u.name == profile.name)
+    {
+      return Err(DbError::UniqueViolation);
+    }
+    let user = tables.user_mut(id)?;
+    user.name = profile.name.clone();
+    user.display_name = profile.display_name.clone();
+    user.bio = profile.bio.clone();
+    user.avatar_url = profile.avatar_url.clone();
+    user.hidden_from_search = profile.hidden_from_search;
+    Ok(user.clone())
+  }
+
+  async fn search_users(&self, prefix: &str, limit: i64) -> Result<Vec<User>, DbError> {
+    let prefix = prefix.to_lowercase();
+    let mut users: Vec<User> = self
+      .tables()
+      .users
+      .iter()
+      .filter(|u| u.deleted_at.is_none() && !u.hidden_from_search)
+      .filter(|u| {
+        u.name.to_lowercase().starts_with(&prefix)
+          || u
+            .display_name
+            .as_ref()
+            .is_some_and(|name| name.to_lowercase().starts_with(&prefix))
+      })
+      .cloned()
+      .collect();
+    users.sort_by_key(|u| (u.display_name().to_lowercase(), u.id));
+    users.truncate(limit as usize);
+    Ok(users)
+  }
+
+  async fn delete_account(
+    &self,
+    id: i64,
+    name: &str,
+    password: &str,
+  ) -> Result<AccountDeletion, DbError> {
+    // the lock is held throughout and nothing fails after the first change
+    let mut tables = self.tables();
+    if tables.user_mut(id)?.deleted_at.is_some() {
+      return Err(DbError::NotFound);
+    }
+    let now = Utc::now();
+    let mut transferred_rooms = Vec::new();
+    let mut deleted_rooms = Vec::new();
+
+    let owned_room_ids: Vec<i64> = tables
+      .rooms
+      .iter()
+      .filter(|r| r.created_by == id && r.deleted_at.is_none() && !r.is_direct())
+      .map(|r| r.id)
+      .collect();
+    for room_id in owned_room_ids {
+      let heir_id = tables
+        .members
+        .iter()
+        .find(|m| m.room_id == room_id && m.user_id != id && m.deleted_at.is_none())
+        .map(|m| m.user_id);
+      let room = tables.room_mut(room_id)?;
+      match heir_id {
+        Some(heir_id) => {
+          room.created_by = heir_id;
+          transferred_rooms.push((room_id, heir_id));
+        }
+        None => {
+          room.deleted_at = Some(now);
+          deleted_rooms.push(room_id);
+        }
+      }
+    }
+
+    let mut left_memberships = Vec::new();
+    for member in tables
+      .members
+      .iter_mut()
+      .filter(|m| m.user_id == id && m.deleted_at.is_none())
+    {
+      member.deleted_at = Some(now);
+      left_memberships.push(member.clone());
+    }
+    for member in &left_memberships {
+      let empty = !tables
+        .members
+        .iter()
+        .any(|m| m.room_id == member.room_id && m.deleted_at.is_none());
+      let room = tables.room_mut(member.room_id)?;
+      if empty && room.deleted_at.is_none() {
+        room.deleted_at = Some(now);
+        deleted_rooms.push(member.room_id);
+      }
+    }
+
+    tables.totps.retain(|t| t.user_id != id);
+    tables.password_resets.retain(|r| r.user_id != id);
+    tables.blocks.retain(|b| b.blocker_id != id);
+    let user = tables.user_mut(id)?;
+    user.name = name.to_owned();
+    user.password = password.to_owned();
+    user.email = None;
+    user.display_name = Some(DELETED_USER_NAME.to_owned());
+    user.bio = None;
+    user.avatar_url = None;
+    user.status = None;
+    user.status_text = None;
+    user.deleted_at = Some(now);
+    Ok(AccountDeletion {
+      user: user.clone(),
+      transferred_rooms,
+      deleted_rooms,
+      left_memberships,
+    })
+  }
+
+  async fn update_last_seen_at(&self, id: i64) -> Result<(), DbError> {
+    if let Ok(user) = self.tables().user_mut(id) {
+      user.last_seen_at = Some(Utc::now());
+    }
+    Ok(())
+  }
+
+  async fn update_online_instances(&self, id: i64, delta: i32) -> Result<(), DbError> {
+    if let Ok(user) = self.tables().user_mut(id) {
+      user.online_instances += delta;
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl RoomRepo for InMemoryRepo {
+  async fn create_new_room(&self, name: String, created_by: i64) -> Result<Room, DbError> {
+    let mut tables = self.tables();
+    if !tables.user_exists(created_by) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    let room = Room {
+      id: tables.next_id(),
+      name,
+      created_by,
+      created_at: Utc::now(),
+      deleted_at: None,
+      direct_user_a: None,
+      direct_user_b: None,
+    };
+    tables.rooms.push(room.clone());
+    Ok(room)
+  }
+
+  async fn get_room_by_id(&self, id: i64) -> Result<Room, DbError> {
+    one(self.tables().rooms.iter().filter(|r| r.id == id))
+  }
+
+  async fn list_rooms(&self) -> Result<Vec<Room>, DbError> {
+    let mut rooms: Vec<Room> = self
+      .tables()
+      .rooms
+      .iter()
+      .filter(|r| r.deleted_at.is_none() && !r.is_direct())
+      .cloned()
+      .collect();
+    rooms.sort_by_key(|room| std::cmp::Reverse(room.created_at));
+    Ok(rooms)
+  }
+
+  async fn get_or_create_direct_room(
+    &self,
+    created_by: i64,
+    other_user_id: i64,
+  ) -> Result<Room, DbError> {
+    let (user_a, user_b) = if created_by < other_user_id {
+      (created_by, other_user_id)
+    } else {
+      (other_user_id, created_by)
+    };
+    let mut tables = self.tables();
+    if !tables.user_exists(user_a) || !tables.user_exists(user_b) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    if let Some(room) = tables
+      .rooms
+      .iter_mut()
+      .find(|r| r.direct_user_a == Some(user_a) && r.direct_user_b == Some(user_b))
+    {
+      room.deleted_at = None;
+      return Ok(room.clone());
+    }
+    let room = Room {
+      id: tables.next_id(),
+      name: direct_room_name(user_a, user_b),
+      created_by,
+      created_at: Utc::now(),
+      deleted_at: None,
+      direct_user_a: Some(user_a),
+      direct_user_b: Some(user_b),
+    };
+    tables.rooms.push(room.clone());
+    Ok(room)
+  }
+
+  async fn delete_room(&self, id: i64) -> Result<Room, DbError> {
+    let mut tables = self.tables();
+    let room = tables.room_mut(id)?;
+    if room.deleted_at.is_none() {
+      room.deleted_at = Some(Utc::now());
+    }
+    Ok(room.clone())
+  }
+
+  async fn list_owned_rooms(&self, user_id: i64) -> Result<Vec<Room>, DbError> {
+    // rooms are pushed in creation order already
+    Ok(
+      self
+        .tables()
+        .rooms
+        .iter()
+        .filter(|r| r.created_by == user_id && r.deleted_at.is_none() && !r.is_direct())
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn transfer_room(&self, id: i64, new_owner_id: i64) -> Result<Room, DbError> {
+    let mut tables = self.tables();
+    if !tables.user_exists(new_owner_id) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    let room = tables.room_mut(id)?;
+    room.created_by = new_owner_id;
+    Ok(room.clone())
+  }
+}
+
+#[async_trait]
+impl MemberRepo for InMemoryRepo {
+  async fn create_new_member(&self, room_id: i64, user_id: i64) -> Result<Member, DbError> {
+    let mut tables = self.tables();
+    if !tables.rooms.iter().any(|r| r.id == room_id) || !tables.user_exists(user_id) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    if tables.active_member(room_id, user_id).is_some() {
+      return Err(DbError::UniqueViolation);
+    }
+    let now = Utc::now();
+    let member = Member {
+      id: tables.next_id(),
+      room_id,
+      user_id,
+      created_at: now,
+      last_joined_at: now,
+      deleted_at: None,
+    };
+    tables.members.push(member.clone());
+    Ok(member)
+  }
+
+  async fn get_member(&self, room_id: i64, user_id: i64) -> Result<Member, DbError> {
+    self
+      .tables()
+      .active_member(room_id, user_id)
+      .cloned()
+      .ok_or(DbError::NotFound)
+  }
+
+  async fn get_member_by_id(&self, id: i64) -> Result<Member, DbError> {
+    let tables = self.tables();
+    one(
+      tables
+        .members
+        .iter()
+        .filter(|m| m.id == id && m.deleted_at.is_none()),
+    )
+  }
+
+  async fn delete_member(&self, room_id: i64, user_id: i64) -> Result<i64, DbError> {
+    let mut tables = self.tables();
+    let member = tables
+      .members
+      .iter_mut()
+      .find(|m| m.room_id == room_id && m.user_id == user_id && m.deleted_at.is_none())
+      .ok_or(DbError::NotFound)?;
+    member.deleted_at = Some(Utc::now());
+    Ok(member.id)
+  }
+
+  async fn count_active_members(&self, room_id: i64) -> Result<i64, DbError> {
+    let tables = self.tables();
+    let count = tables
+      .members
+      .iter()
+      .filter(|m| m.room_id == room_id && m.deleted_at.is_none())
+      .count();
+    Ok(count as i64)
+  }
+
+  async fn update_last_joined_at(&self, room_id: i64, user_id: i64) -> Result<(), DbError> {
+    let mut tables = self.tables();
+    if let Some(member) = tables
+      .members
+      .iter_mut()
+      .find(|m| m.room_id == room_id && m.user_id == user_id && m.deleted_at.is_none())
+    {
+      member.last_joined_at = Utc::now();
+    }
+    Ok(())
+  }
+
+  async fn list_user_memberships(&self, user_id: i64) -> Result<Vec<Member>, DbError> {
+    Ok(
+      self
+        .tables()
+        .members
+        .iter()
+        .filter(|m| m.user_id == user_id)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn list_active_members(&self, room_id: i64) -> Result<Vec<Member>, DbError> {
+    Ok(
+      self
+        .tables()
+        .members
+        .iter()
+        .filter(|m| m.room_id == room_id && m.deleted_at.is_none())
+        .cloned()
+        .collect(),
+    )
+  }
+}
+
+#[async_trait]
+impl MessageRepo for InMemoryRepo {
+  async fn add_message(&self, room_id: i64, sender_id: i64, msg: &str) -> Result<(), DbError> {
+    let mut tables = self.tables();
+    if !tables.rooms.iter().any(|r| r.id == room_id)
+      || !tables.members.iter().any(|m| m.id == sender_id)
+    {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    let message = Message {
+      id: tables.next_id(),
+      room_id,
+      sender_id,
+      msg: msg.to_owned(),
+      created_at: Utc::now(),
+    };
+    tables.messages.push(message);
+    Ok(())
+  }
+
+  async fn get_unread_messages(
+    &self,
+    room_id: i64,
+    last_seen_at: DateTime<Utc>,
+  ) -> Result<Vec<Message>, DbError> {
+    // messages are pushed in creation order already
+    Ok(
+      self
+        .tables()
+        .messages
+        .iter()
+        .filter(|m| m.room_id == room_id && m.created_at > last_seen_at)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn list_user_messages(&self, user_id: i64) -> Result<Vec<Message>, DbError> {
+    let tables = self.tables();
+    Ok(
+      tables
+        .messages
+        .iter()
+        .filter(|message| {
+          tables
+            .members
+            .iter()
+            .any(|m| m.id == message.sender_id && m.user_id == user_id)
+        })
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn list_room_messages(
+    &self,
+    room_id: i64,
+    viewer_id: i64,
+    before: Option<i64>,
+    limit: i64,
+  ) -> Result<Vec<RoomMessage>, DbError> {
+    let tables = self.tables();
+    let mut messages: Vec<RoomMessage> = tables
+      .messages
+      .iter()
+      .rev()
+      .filter(|m| m.room_id == room_id && before.is_none_or(|before| m.id < before))
+      .filter_map(|m| {
+        let sender = tables
+          .members
+          .iter()
+          .find(|member| member.id == m.sender_id)?;
+        Some(RoomMessage {
+          message: m.clone(),
+          user_id: sender.user_id,
+        })
+      })
+      .filter(|m| {
+        !tables
+          .blocks
+          .iter()
+          .any(|b| b.blocker_id == viewer_id && b.blocked_id == m.user_id)
+      })
+      .take(limit as usize)
+      .collect();
+    messages.reverse();
+    Ok(messages)
+  }
+}
+
+#[async_trait]
+impl SessionRepo for InMemoryRepo {
+  async fn create_session(
+    &self,
+    user_id: i64,
+    refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+  ) -> Result<Session, DbError> {
+    let mut tables = self.tables();
+    if !tables.user_exists(user_id) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    if tables
+      .sessions
+      .iter()
+      .any(|s| s.refresh_token_hash == refresh_token_hash)
+    {
+      return Err(DbError::UniqueViolation);
+    }
+    let now = Utc::now();
+    let session = Session {
+      id: tables.next_id(),
+      user_id,
+      refresh_token_hash: refresh_token_hash.to_owned(),
+      previous_refresh_token_hash: None,
+      created_at: now,
+      last_used_at: now,
+      expires_at,
+      revoked_at: None,
+      user_agent,
+      ip_address,
+    };
+    tables.sessions.push(session.clone());
+    Ok(session)
+  }
+
+  async fn list_user_sessions(&self, user_id: i64) -> Result<Vec<Session>, DbError> {
+    let mut sessions: Vec<Session> = self
+      .tables()
+      .sessions
+      .iter()
+      .filter(|s| s.user_id == user_id && s.is_active())
+      .cloned()
+      .collect();
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.last_used_at));
+    Ok(sessions)
+  }
+
+  async fn touch_session(&self, id: i64) -> Result<(), DbError> {
+    let mut tables = self.tables();
+    if let Some(session) = tables.sessions.iter_mut().find(|s| s.id == id) {
+      session.last_used_at = Utc::now();
+    }
+    Ok(())
+  }
+
+  async fn get_session_by_id(&self, id: i64) -> Result<Session, DbError> {
+    one(self.tables().sessions.iter().filter(|s| s.id == id))
+  }
+
+  async fn find_session_by_refresh_token(
+    &self,
+    refresh_token_hash: &str,
+  ) -> Result<Session, DbError> {
+    let tables = self.tables();
+    one(tables.sessions.iter().filter(|s| {
+      s.refresh_token_hash == refresh_token_hash
+        || s.previous_refresh_token_hash.as_deref() == Some(refresh_token_hash)
+    }))
+  }
+
+  async fn rotate_refresh_token(
+    &self,
+    id: i64,
+    refresh_token_hash: &str,
+    new_refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<Session, DbError> {
+    let mut tables = self.tables();
+    let session = tables
+      .sessions
+      .iter_mut()
+      .find(|s| s.id == id && s.refresh_token_hash == refresh_token_hash && s.revoked_at.is_none())
+      .ok_or(DbError::NotFound)?;
+    session.previous_refresh_token_hash = Some(std::mem::replace(
+      &mut session.refresh_token_hash,
+      new_refresh_token_hash.to_owned(),
+    ));
+    session.last_used_at = Utc::now();
+    session.expires_at = expires_at;
+    Ok(session.clone())
+  }
+
+  async fn revoke_session(&self, user_id: i64, id: i64) -> Result<Session, DbError> {
+    let mut tables = self.tables();
+    let session = tables
+      .sessions
+      .iter_mut()
+      .find(|s| s.id == id && s.user_id == user_id)
+      .ok_or(DbError::NotFound)?;
+    session.revoked_at.get_or_insert_with(Utc::now);
+    Ok(session.clone())
+  }
+
+  async fn revoke_user_sessions(
+    &self,
+    user_id: i64,
+    except_id: Option<i64>,
+  ) -> Result<Vec<i64>, DbError> {
+    let now = Utc::now();
+    let mut tables = self.tables();
+    let mut revoked = Vec::new();
+    for session in tables
+      .sessions
+      .iter_mut()
+      .filter(|s| s.user_id == user_id && s.revoked_at.is_none() && Some(s.id) != except_id)
+    {
+      session.revoked_at = Some(now);
+      revoked.push(session.id);
+    }
+    Ok(revoked)
+  }
+
+  async fn create_ws_ticket(&self, ticket: &WsTicket) -> Result<(), DbError> {
+    let now = Utc::now();
+    let mut tables = self.tables();
+    if !tables.user_exists(ticket.user_id)
+      || !tables.sessions.iter().any(|s| s.id == ticket.session_id)
+    {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    tables.ws_tickets.retain(|t| t.expires_at >= now);
+    if tables
+      .ws_tickets
+      .iter()
+      .any(|t| t.ticket_hash == ticket.ticket_hash)
+    {
+      return Err(DbError::UniqueViolation);
+    }
+    tables.ws_tickets.push(ticket.clone());
+    Ok(())
+  }
+
+  async fn take_ws_ticket(&self, ticket_hash: &str) -> Result<WsTicket, DbError> {
+    let mut tables = self.tables();
+    let index = tables
+      .ws_tickets
+      .iter()
+      .position(|t| t.ticket_hash == ticket_hash)
+      .ok_or(DbError::NotFound)?;
+    Ok(tables.ws_tickets.swap_remove(index))
+  }
+}
+
+#[async_trait]
+impl TwoFactorRepo for InMemoryRepo {
+  async fn start_totp_enrolment(
+    &self,
+    user_id: i64,
+    secret_ciphertext: &str,
+  ) -> Result<UserTotp, DbError> {
+    let mut tables = self.tables();
+    if !tables.user_exists(user_id) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    let totp = UserTotp {
+      user_id,
+      secret_ciphertext: secret_ciphertext.to_owned(),
+      confirmed_at: None,
+      last_used_step: None,
+      recovery_code_hashes: Vec::new(),
+      created_at: Utc::now(),
+    };
+    match tables.totp_mut(user_id) {
+      Ok(existing) if existing.confirmed_at.is_some() => return Err(DbError::UniqueViolation),
+      Ok(existing) => *existing = totp.clone(),
+      Err(_) => tables.totps.push(totp.clone()),
+    }
+    Ok(totp)
+  }
+
+  async fn get_user_totp(&self, user_id: i64) -> Result<UserTotp, DbError> {
+    one(self.tables().totps.iter().filter(|t| t.user_id == user_id))
+  }
+
+  async fn confirm_totp(
+    &self,
+    user_id: i64,
+    step: i64,
+    recovery_code_hashes: &[String],
+  ) -> Result<UserTotp, DbError> {
+    let mut tables = self.tables();
+    let totp = tables.totp_mut(user_id)?;
+    if totp.confirmed_at.is_some() {
+      return Err(DbError::NotFound);
+    }
+    totp.confirmed_at = Some(Utc::now());
+    totp.last_used_step = Some(step);
+    totp.recovery_code_hashes = recovery_code_hashes.to_vec();
+    Ok(totp.clone())
+  }
+
+  async fn use_totp_step(&self, user_id: i64, step: i64) -> Result<UserTotp, DbError> {
+    let mut tables = self.tables();
+    let totp = tables.totp_mut(user_id)?;
+    if totp.confirmed_at.is_none() || totp.last_used_step.is_some_and(|last| last >= step) {
+      return Err(DbError::NotFound);
+    }
+    totp.last_used_step = Some(step);
+    Ok(totp.clone())
+  }
+
+  async fn use_recovery_code(&self, user_id: i64, code_hash: &str) -> Result<UserTotp, DbError> {
+    let mut tables = self.tables();
+    let totp = tables.totp_mut(user_id)?;
+    if totp.confirmed_at.is_none() || !totp.recovery_code_hashes.iter().any(|h| h == code_hash) {
+      return Err(DbError::NotFound);
+    }
+    totp.recovery_code_hashes.retain(|h| h != code_hash);
+    Ok(totp.clone())
+  }
+
+  async fn delete_user_totp(&self, user_id: i64) -> Result<UserTotp, DbError> {
+    let mut tables = self.tables();
+    let index = tables
+      .totps
+      .iter()
+      .position(|t| t.user_id == user_id)
+      .ok_or(DbError::NotFound)?;
+    Ok(tables.totps.swap_remove(index))
+  }
+
+  async fn create_login_challenge(&self, challenge: &LoginChallenge) -> Result<(), DbError> {
+    let now = Utc::now();
+    let mut tables = self.tables();
+    if !tables.user_exists(challenge.user_id) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    tables.login_challenges.retain(|c| c.expires_at >= now);
+    if tables
+      .login_challenge_mut(&challenge.challenge_hash)
+      .is_ok()
+    {
+      return Err(DbError::UniqueViolation);
+    }
+    tables.login_challenges.push(LoginChallenge {
+      failed_attempts: 0,
+      ..challenge.clone()
+    });
+    Ok(())
+  }
+
+  async fn get_login_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError> {
+    Ok(self.tables().login_challenge_mut(challenge_hash)?.clone())
+  }
+
+  async fn record_failed_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError> {
+    let mut tables = self.tables();
+    let challenge = tables.login_challenge_mut(challenge_hash)?;
+    challenge.failed_attempts += 1;
+    Ok(challenge.clone())
+  }
+
+  async fn delete_login_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError> {
+    let mut tables = self.tables();
+    let index = tables
+      .login_challenges
+      .iter()
+      .position(|c| c.challenge_hash == challenge_hash)
+      .ok_or(DbError::NotFound)?;
+    Ok(tables.login_challenges.swap_remove(index))
+  }
+}
+
+#[async_trait]
+impl BlockRepo for InMemoryRepo {
+  async fn block_user(&self, blocker_id: i64, blocked_id: i64) -> Result<UserBlock, DbError> {
+    let mut tables = self.tables();
+    if !tables.user_exists(blocker_id) || !tables.user_exists(blocked_id) {
+      return Err(DbError::ForeignKeyViolation);
+    }
+    if let Some(block) = tables
+      .blocks
+      .iter()
+      .find(|b| b.blocker_id == blocker_id && b.blocked_id == blocked_id)
+    {
+      return Ok(block.clone());
+    }
+    let block = UserBlock {
+      blocker_id,
+      blocked_id,
+      created_at: Utc::now(),
+    };
+    tables.blocks.push(block.clone());
+    Ok(block)
+  }
+
+  async fn unblock_user(&self, blocker_id: i64, blocked_id: i64) -> Result<UserBlock, DbError> {
+    let mut tables = self.tables();
+    let index = tables
+      .blocks
+      .iter()
+      .position(|b| b.blocker_id == blocker_id && b.blocked_id == blocked_id)
+      .ok_or(DbError::NotFound)?;
+    Ok(tables.blocks.remove(index))
+  }
+
+  async fn list_blocked_users(&self, blocker_id: i64) -> Result<Vec<UserBlock>, DbError> {
+    Ok(
+      self
+        .tables()
+        .blocks
+        .iter()
+        .filter(|b| b.blocker_id == blocker_id)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn is_blocked_between(&self, user_id: i64, other_user_id: i64) -> Result<bool, DbError> {
+    Ok(self.tables().blocks.iter().any(|b| {
+      (b.blocker_id == user_id && b.blocked_id == other_user_id)
+        || (b.blocker_id == other_user_id && b.blocked_id == user_id)
+    }))
+  }
+}
@@ -1,3 +1,5 @@
+use super::{DbError, PostgresRepo};
+use async_trait::async_trait;
 use bb8::PooledConnection;
 use bb8_postgres::PostgresConnectionManager;
 use chrono::DateTime;
@@ -13,6 +15,13 @@ pub struct Message {
   pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// A message of a room together with the user behind its sender.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomMessage {
+  pub message: Message,
+  pub user_id: i64,
+}
+
 pub async fn add_message(
   conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
   room_id: i64,
@@ -40,12 +49,95 @@ pub async fn get_unread_messages(
   Ok(messages)
 }
 
+pub async fn list_user_messages(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+) -> Result<Vec<Message>, tokio_postgres::Error> {
+  let query = "SELECT message.* FROM message JOIN room_member ON room_member.id = message.sender_id WHERE room_member.user_id = $1 ORDER BY message.created_at ASC, message.id ASC";
+  let rows = conn.query(query, &[&user_id]).await?;
+  Ok(rows.into_iter().map(row_to_message).collect())
+}
+
+pub async fn list_room_messages(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  room_id: i64,
+  viewer_id: i64,
+  before: Option<i64>,
+  limit: i64,
+) -> Result<Vec<RoomMessage>, tokio_postgres::Error> {
+  let query = "SELECT message.*, room_member.user_id FROM message JOIN room_member ON room_member.id = message.sender_id WHERE message.room_id = $1 AND ($3::BIGINT IS NULL OR message.id < $3) AND NOT EXISTS (SELECT 1 FROM user_block WHERE user_block.blocker_id = $2 AND user_block.blocked_id = room_member.user_id) ORDER BY message.id DESC LIMIT $4";
+  let rows = conn
+    .query(query, &[&room_id, &viewer_id, &before, &limit])
+    .await?;
+  // the newest page is selected, but handed out oldest first
+  Ok(
+    rows
+      .into_iter()
+      .rev()
+      .map(|row| RoomMessage {
+        user_id: row.get(5),
+        message: row_to_message(row),
+      })
+      .collect(),
+  )
+}
+
 fn row_to_message(row: tokio_postgres::Row) -> Message {
   Message {
     id: row.get(0),
     room_id: row.get(1),
     sender_id: row.get(2),
-    msg: row.get(4),
-    created_at: row.get(5),
+    msg: row.get(3),
+    created_at: row.get(4),
+  }
+}
+
+#[async_trait]
+pub trait MessageRepo: Send + Sync {
+  async fn add_message(&self, room_id: i64, sender_id: i64, msg: &str) -> Result<(), DbError>;
+  async fn get_unread_messages(
+    &self,
+    room_id: i64,
+    last_seen_at: DateTime<chrono::Utc>,
+  ) -> Result<Vec<Message>, DbError>;
+  /// Lists every message the user sent, in any room, oldest first.
+  async fn list_user_messages(&self, user_id: i64) -> Result<Vec<Message>, DbError>;
+  /// Lists the last `limit` messages of the room sent before the message `before`, oldest
+  /// first. Messages of users the viewer blocked are left out.
+  async fn list_room_messages(
+    &self,
+    room_id: i64,
+    viewer_id: i64,
+    before: Option<i64>,
+    limit: i64,
+  ) -> Result<Vec<RoomMessage>, DbError>;
+}
+
+#[async_trait]
+impl MessageRepo for PostgresRepo {
+  async fn add_message(&self, room_id: i64, sender_id: i64, msg: &str) -> Result<(), DbError> {
+    Ok(add_message(&mut self.conn().await?, room_id, sender_id, msg).await?)
+  }
+
+  async fn get_unread_messages(
+    &self,
+    room_id: i64,
+    last_seen_at: DateTime<chrono::Utc>,
+  ) -> Result<Vec<Message>, DbError> {
+    Ok(get_unread_messages(&mut self.conn().await?, room_id, last_seen_at).await?)
+  }
+
+  async fn list_user_messages(&self, user_id: i64) -> Result<Vec<Message>, DbError> {
+    Ok(list_user_messages(&mut self.conn().await?, user_id).await?)
+  }
+
+  async fn list_room_messages(
+    &self,
+    room_id: i64,
+    viewer_id: i64,
+    before: Option<i64>,
+    limit: i64,
+  ) -> Result<Vec<RoomMessage>, DbError> {
+    Ok(list_room_messages(&mut self.conn().await?, room_id, viewer_id, before, limit).await?)
   }
 }
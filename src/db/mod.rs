@@ -1,17 +1,138 @@
 use crate::helpers::get_env;
+use crate::ConnectionPool;
 
-use bb8::{ManageConnection, Pool};
+use bb8::{ManageConnection, Pool, PooledConnection, RunError};
 use bb8_postgres::PostgresConnectionManager;
+use std::fmt;
+use std::sync::Arc;
 
 use tokio_postgres::{config::Config, NoTls};
 use tokio_postgres_migration::Migration;
 
+use block::BlockRepo;
+use member::MemberRepo;
+use message::MessageRepo;
+use room::RoomRepo;
+use session::SessionRepo;
+use two_factor::TwoFactorRepo;
+use user::UserRepo;
+
+pub mod block;
 pub mod member;
+pub mod memory;
 pub mod message;
 pub mod room;
+pub mod session;
+pub mod two_factor;
 pub mod user;
 
-const SCRIPTS_UP: [(&str, &str); 4] = [
+#[derive(Debug)]
+pub enum DbError {
+  NotFound,
+  UniqueViolation,
+  ForeignKeyViolation,
+  InvalidInput,
+  Pool(String),
+  Postgres(tokio_postgres::Error),
+}
+
+impl fmt::Display for DbError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DbError::NotFound => write!(f, "row not found"),
+      DbError::UniqueViolation => write!(f, "unique key violation"),
+      DbError::ForeignKeyViolation => write!(f, "foreign key violation"),
+      DbError::InvalidInput => write!(f, "invalid input"),
+      DbError::Pool(e) => write!(f, "connection pool error: {}", e),
+      DbError::Postgres(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<tokio_postgres::Error> for DbError {
+  fn from(err: tokio_postgres::Error) -> Self {
+    let row_count_err = "query returned an unexpected number of rows";
+    match err.code().map(|sql_state| sql_state.code()) {
+      Some("23503") => DbError::ForeignKeyViolation,
+      Some("23505") => DbError::UniqueViolation,
+      Some("P0002") => DbError::InvalidInput,
+      Some(_) => DbError::Postgres(err),
+      None if err.to_string() == row_count_err => DbError::NotFound,
+      None => DbError::Postgres(err),
+    }
+  }
+}
+
+impl From<RunError<tokio_postgres::Error>> for DbError {
+  fn from(err: RunError<tokio_postgres::Error>) -> Self {
+    match err {
+      RunError::User(e) => DbError::from(e),
+      RunError::TimedOut => DbError::Pool("timed out waiting for a connection".to_owned()),
+    }
+  }
+}
+
+/// The storage used by the routes and the lobby, one repository per table.
+#[derive(Clone)]
+pub struct Repositories {
+  pub users: Arc<dyn UserRepo>,
+  pub rooms: Arc<dyn RoomRepo>,
+  pub members: Arc<dyn MemberRepo>,
+  pub messages: Arc<dyn MessageRepo>,
+  pub sessions: Arc<dyn SessionRepo>,
+  pub two_factor: Arc<dyn TwoFactorRepo>,
+  pub blocks: Arc<dyn BlockRepo>,
+  // The pool behind the Postgres repositories, `None` for the in-memory ones.
+  pub pool: Option<ConnectionPool>,
+}
+
+impl Repositories {
+  pub fn postgres(pool: ConnectionPool) -> Repositories {
+    let repo = Arc::new(PostgresRepo { pool: pool.clone() });
+    Repositories {
+      users: repo.clone(),
+      rooms: repo.clone(),
+      members: repo.clone(),
+      messages: repo.clone(),
+      sessions: repo.clone(),
+      two_factor: repo.clone(),
+      blocks: repo,
+      pool: Some(pool),
+    }
+  }
+
+  /// Keeps everything in process memory, for tests and local runs without Postgres.
+  pub fn in_memory() -> Repositories {
+    let repo = Arc::new(memory::InMemoryRepo::default());
+    Repositories {
+      users: repo.clone(),
+      rooms: repo.clone(),
+      members: repo.clone(),
+      messages: repo.clone(),
+      sessions: repo.clone(),
+      two_factor: repo.clone(),
+      blocks: repo,
+      pool: None,
+    }
+  }
+}
+
+/// Implements the repositories on top of the queries of each table module.
+pub struct PostgresRepo {
+  pool: ConnectionPool,
+}
+
+impl PostgresRepo {
+  async fn conn(&self) -> Result<PooledConnection<'_, PostgresConnectionManager<NoTls>>, DbError> {
+    Ok(self.pool.get().await?)
+  }
+}
+
+const MIGRATIONS_TABLE: &str = "schema_migrations";
+
+const SCRIPTS_UP: [(&str, &str); 20] = [
   (
     "users",
     include_str!("../../migrations/2023-07-02-011854_user/up.sql"),
@@ -28,9 +149,76 @@ const SCRIPTS_UP: [(&str, &str); 4] = [
     "message",
     include_str!("../../migrations/2023-07-02-012056_message/up.sql"),
   ),
+  (
+    "user_presence",
+    include_str!("../../migrations/2026-10-18-090000_user_presence/up.sql"),
+  ),
+  (
+    "direct_room",
+    include_str!("../../migrations/2026-10-18-091000_direct_room/up.sql"),
+  ),
+  (
+    "user_session",
+    include_str!("../../migrations/2026-10-19-090000_user_session/up.sql"),
+  ),
+  (
+    "session_client",
+    include_str!("../../migrations/2026-10-19-100000_session_client/up.sql"),
+  ),
+  (
+    "ws_ticket",
+    include_str!("../../migrations/2026-10-19-110000_ws_ticket/up.sql"),
+  ),
+  (
+    "user_totp",
+    include_str!("../../migrations/2026-10-19-120000_user_totp/up.sql"),
+  ),
+  (
+    "login_challenge",
+    include_str!("../../migrations/2026-10-19-120100_login_challenge/up.sql"),
+  ),
+  (
+    "user_email",
+    include_str!("../../migrations/2026-10-19-130000_user_email/up.sql"),
+  ),
+  (
+    "password_reset",
+    include_str!("../../migrations/2026-10-19-130100_password_reset/up.sql"),
+  ),
+  (
+    "user_profile",
+    include_str!("../../migrations/2026-10-19-140000_user_profile/up.sql"),
+  ),
+  (
+    "user_deleted",
+    include_str!("../../migrations/2026-10-19-150000_user_deleted/up.sql"),
+  ),
+  (
+    "user_search",
+    include_str!("../../migrations/2026-10-19-150100_user_search/up.sql"),
+  ),
+  (
+    "user_name_search_index",
+    include_str!("../../migrations/2026-10-19-150200_user_name_search_index/up.sql"),
+  ),
+  (
+    "user_display_name_search_index",
+    include_str!("../../migrations/2026-10-19-150300_user_display_name_search_index/up.sql"),
+  ),
+  (
+    "user_block",
+    include_str!("../../migrations/2026-10-19-160000_user_block/up.sql"),
+  ),
+  (
+    "user_online_instances",
+    include_str!("../../migrations/2026-10-19-170000_user_online_instances/up.sql"),
+  ),
 ];
 
-const SCRIPTS_DOWN: [(&str, &str); 4] = [
+// Nothing runs the down scripts yet, they are listed so that every migration keeps its
+// pair next to the up scripts.
+#[allow(dead_code)]
+const SCRIPTS_DOWN: [(&str, &str); 20] = [
   (
     "users",
     include_str!("../../migrations/2023-07-02-011854_user/down.sql"),
@@ -47,18 +235,78 @@ const SCRIPTS_DOWN: [(&str, &str); 4] = [
     "message",
     include_str!("../../migrations/2023-07-02-012056_message/down.sql"),
   ),
+  (
+    "user_presence",
+    include_str!("../../migrations/2026-10-18-090000_user_presence/down.sql"),
+  ),
+  (
+    "direct_room",
+    include_str!("../../migrations/2026-10-18-091000_direct_room/down.sql"),
+  ),
+  (
+    "user_session",
+    include_str!("../../migrations/2026-10-19-090000_user_session/down.sql"),
+  ),
+  (
+    "session_client",
+    include_str!("../../migrations/2026-10-19-100000_session_client/down.sql"),
+  ),
+  (
+    "ws_ticket",
+    include_str!("../../migrations/2026-10-19-110000_ws_ticket/down.sql"),
+  ),
+  (
+    "user_totp",
+    include_str!("../../migrations/2026-10-19-120000_user_totp/down.sql"),
+  ),
+  (
+    "login_challenge",
+    include_str!("../../migrations/2026-10-19-120100_login_challenge/down.sql"),
+  ),
+  (
+    "user_email",
+    include_str!("../../migrations/2026-10-19-130000_user_email/down.sql"),
+  ),
+  (
+    "password_reset",
+    include_str!("../../migrations/2026-10-19-130100_password_reset/down.sql"),
+  ),
+  (
+    "user_profile",
+    include_str!("../../migrations/2026-10-19-140000_user_profile/down.sql"),
+  ),
+  (
+    "user_deleted",
+    include_str!("../../migrations/2026-10-19-150000_user_deleted/down.sql"),
+  ),
+  (
+    "user_search",
+    include_str!("../../migrations/2026-10-19-150100_user_search/down.sql"),
+  ),
+  (
+    "user_name_search_index",
+    include_str!("../../migrations/2026-10-19-150200_user_name_search_index/down.sql"),
+  ),
+  (
+    "user_display_name_search_index",
+    include_str!("../../migrations/2026-10-19-150300_user_display_name_search_index/down.sql"),
+  ),
+  (
+    "user_block",
+    include_str!("../../migrations/2026-10-19-160000_user_block/down.sql"),
+  ),
+  (
+    "user_online_instances",
+    include_str!("../../migrations/2026-10-19-170000_user_online_instances/down.sql"),
+  ),
 ];
 
-pub async fn setup_conn_pool() -> Pool<PostgresConnectionManager<NoTls>> {
+pub fn db_config() -> Config {
   let postgres_user = get_env("POSTGRES_USER");
   let postgres_password = get_env("POSTGRES_PASSWORD");
   let postgres_host = get_env("POSTGRES_HOST");
   let postgres_port = get_env("POSTGRES_PORT");
   let postgres_db = get_env("POSTGRES_DB");
-  let _params = format!(
-    "user={} password={} host={} port={} dbname={}",
-    postgres_user, postgres_password, postgres_host, postgres_port, postgres_db
-  );
   let mut config = Config::default();
   config
     .host(&postgres_host)
@@ -66,25 +314,48 @@ pub async fn setup_conn_pool() -> Pool<PostgresConnectionManager<NoTls>> {
     .user(&postgres_user)
     .password(postgres_password)
     .dbname(&postgres_db);
+  config
+}
+
+pub async fn setup_conn_pool() -> Pool<PostgresConnectionManager<NoTls>> {
+  setup_conn_pool_with_config(db_config()).await
+}
+
+/// Migrates the database of `config` and builds a connection pool for it.
+pub async fn setup_conn_pool_with_config(config: Config) -> Pool<PostgresConnectionManager<NoTls>> {
   let manager = PostgresConnectionManager::new(config, NoTls);
   let mut connection = manager.connect().await.unwrap();
   match run_migrations(&mut connection).await {
     Ok(_) => (),
     Err(e) => {
-      println!("Error running migrations: {}", e);
+      tracing::error!(error = %e, "error running migrations");
       std::process::exit(1);
     }
   };
 
-  let pool = Pool::builder().build(manager).await.unwrap();
-  return pool;
+  Pool::builder().build(manager).await.unwrap()
 }
 
 pub async fn run_migrations(
   client: &mut tokio_postgres::Client,
 ) -> Result<(), tokio_postgres::Error> {
-  let migration = Migration::new("schema_migrations".to_string());
+  let migration = Migration::new(MIGRATIONS_TABLE.to_string());
   // execute non existing migrations
   migration.up(client, &SCRIPTS_UP).await?;
   Ok(())
 }
+
+/// Returns the migrations the database is missing, using a connection of the pool.
+pub async fn pending_migrations(pool: &ConnectionPool) -> Result<Vec<&'static str>, DbError> {
+  let conn = pool.get().await?;
+  let query = format!("SELECT name FROM {}", MIGRATIONS_TABLE);
+  let rows = conn.query(query.as_str(), &[]).await?;
+  let applied: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+  Ok(
+    SCRIPTS_UP
+      .iter()
+      .map(|(name, _)| *name)
+      .filter(|name| !applied.iter().any(|applied| applied == name))
+      .collect(),
+  )
+}
@@ -1,9 +1,14 @@
+use super::{DbError, PostgresRepo};
+use async_trait::async_trait;
 use bb8::PooledConnection;
 use bb8_postgres::PostgresConnectionManager;
 use chrono::DateTime;
 use serde::{Deserialize, Serialize};
 use tokio_postgres::NoTls;
 
+/// Names of direct rooms start with this prefix, user created rooms may not use it.
+pub const DIRECT_ROOM_NAME_PREFIX: &str = "dm-";
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Room {
   pub id: i64,
@@ -11,25 +16,27 @@ pub struct Room {
   pub created_by: i64,
   pub created_at: chrono::DateTime<chrono::Utc>,
   pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+  // Set only for direct message rooms, `direct_user_a` is always the lower user id
+  pub direct_user_a: Option<i64>,
+  pub direct_user_b: Option<i64>,
 }
 
-pub async fn create_new_room(
-  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
-  name: String,
-  created_by: i64,
-) -> Result<Room, tokio_postgres::Error> {
-  let query = "INSERT INTO room (name, created_by) VALUES ($1, $2)";
-  conn.execute(query, &[&name, &created_by]).await?;
-  let room = get_room(conn, name, created_by).await?;
-  Ok(room)
+impl Room {
+  pub fn is_direct(&self) -> bool {
+    self.direct_user_a.is_some()
+  }
+
+  pub fn is_direct_participant(&self, user_id: i64) -> bool {
+    self.direct_user_a == Some(user_id) || self.direct_user_b == Some(user_id)
+  }
 }
 
-pub async fn get_room(
+pub async fn create_new_room(
   conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
   name: String,
   created_by: i64,
 ) -> Result<Room, tokio_postgres::Error> {
-  let query = "SELECT * FROM room WHERE name = $1 AND created_by = $2";
+  let query = "INSERT INTO room (name, created_by) VALUES ($1, $2) RETURNING *";
   let row = conn.query_one(query, &[&name, &created_by]).await?;
   Ok(row_to_room(row))
 }
@@ -43,6 +50,39 @@ pub async fn get_room_by_id(
   Ok(row_to_room(row))
 }
 
+pub async fn list_rooms(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+) -> Result<Vec<Room>, tokio_postgres::Error> {
+  let query =
+    "SELECT * FROM room WHERE deleted_at is NULL AND direct_user_a is NULL ORDER BY created_at DESC";
+  let rows = conn.query(query, &[]).await?;
+  Ok(rows.into_iter().map(row_to_room).collect())
+}
+
+/// Returns the direct room of the two users, creating it on first use.
+/// A previously deleted direct room is restored instead of creating a new one.
+pub async fn get_or_create_direct_room(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  created_by: i64,
+  other_user_id: i64,
+) -> Result<Room, tokio_postgres::Error> {
+  let (user_a, user_b) = if created_by < other_user_id {
+    (created_by, other_user_id)
+  } else {
+    (other_user_id, created_by)
+  };
+  let query =
+    "INSERT INTO room (name, created_by, direct_user_a, direct_user_b) VALUES ($1, $2, $3, $4)
+    ON CONFLICT (direct_user_a, direct_user_b) DO UPDATE SET deleted_at = NULL";
+  let name = direct_room_name(user_a, user_b);
+  conn
+    .execute(query, &[&name, &created_by, &user_a, &user_b])
+    .await?;
+  let query = "SELECT * FROM room WHERE direct_user_a = $1 AND direct_user_b = $2";
+  let row = conn.query_one(query, &[&user_a, &user_b]).await?;
+  Ok(row_to_room(row))
+}
+
 pub async fn delete_room(
   conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
   id: i64,
@@ -53,17 +93,94 @@ pub async fn delete_room(
   Ok(room)
 }
 
+pub async fn list_owned_rooms(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+) -> Result<Vec<Room>, tokio_postgres::Error> {
+  let query = "SELECT * FROM room WHERE created_by = $1 AND deleted_at is NULL AND direct_user_a is NULL ORDER BY created_at ASC";
+  let rows = conn.query(query, &[&user_id]).await?;
+  Ok(rows.into_iter().map(row_to_room).collect())
+}
+
+pub async fn transfer_room(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+  new_owner_id: i64,
+) -> Result<Room, tokio_postgres::Error> {
+  let query = "UPDATE room SET created_by = $2 WHERE id = $1 RETURNING *";
+  let row = conn.query_one(query, &[&id, &new_owner_id]).await?;
+  Ok(row_to_room(row))
+}
+
+pub(crate) fn direct_room_name(user_a: i64, user_b: i64) -> String {
+  format!("{}{}-{}", DIRECT_ROOM_NAME_PREFIX, user_a, user_b)
+}
+
 fn row_to_room(row: tokio_postgres::Row) -> Room {
   let id: i64 = row.get(0);
   let name: String = row.get(1);
   let created_by: i64 = row.get(2);
   let created_at: DateTime<chrono::Utc> = row.get(3);
   let deleted_at: Option<DateTime<chrono::Utc>> = row.get(4);
+  let direct_user_a: Option<i64> = row.get(5);
+  let direct_user_b: Option<i64> = row.get(6);
   Room {
     id,
     name,
     created_by,
     created_at,
     deleted_at,
+    direct_user_a,
+    direct_user_b,
+  }
+}
+
+#[async_trait]
+pub trait RoomRepo: Send + Sync {
+  async fn create_new_room(&self, name: String, created_by: i64) -> Result<Room, DbError>;
+  async fn get_room_by_id(&self, id: i64) -> Result<Room, DbError>;
+  async fn list_rooms(&self) -> Result<Vec<Room>, DbError>;
+  async fn get_or_create_direct_room(
+    &self,
+    created_by: i64,
+    other_user_id: i64,
+  ) -> Result<Room, DbError>;
+  async fn delete_room(&self, id: i64) -> Result<Room, DbError>;
+  /// Lists the live rooms the user owns, direct rooms have no owner and are left out.
+  async fn list_owned_rooms(&self, user_id: i64) -> Result<Vec<Room>, DbError>;
+  async fn transfer_room(&self, id: i64, new_owner_id: i64) -> Result<Room, DbError>;
+}
+
+#[async_trait]
+impl RoomRepo for PostgresRepo {
+  async fn create_new_room(&self, name: String, created_by: i64) -> Result<Room, DbError> {
+    Ok(create_new_room(&mut self.conn().await?, name, created_by).await?)
+  }
+
+  async fn get_room_by_id(&self, id: i64) -> Result<Room, DbError> {
+    Ok(get_room_by_id(&mut self.conn().await?, id).await?)
+  }
+
+  async fn list_rooms(&self) -> Result<Vec<Room>, DbError> {
+    Ok(list_rooms(&mut self.conn().await?).await?)
+  }
+
+  async fn get_or_create_direct_room(
+    &self,
+    created_by: i64,
+    other_user_id: i64,
+  ) -> Result<Room, DbError> {
+    Ok(get_or_create_direct_room(&mut self.conn().await?, created_by, other_user_id).await?)
+  }
+
+  async fn delete_room(&self, id: i64) -> Result<Room, DbError> {
+    Ok(delete_room(&mut self.conn().await?, id).await?)
+  }
+  async fn list_owned_rooms(&self, user_id: i64) -> Result<Vec<Room>, DbError> {
+    Ok(list_owned_rooms(&mut self.conn().await?, user_id).await?)
+  }
+
+  async fn transfer_room(&self, id: i64, new_owner_id: i64) -> Result<Room, DbError> {
+    Ok(transfer_room(&mut self.conn().await?, id, new_owner_id).await?)
   }
 }
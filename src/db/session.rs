@@ -0,0 +1,317 @@
+use super::{DbError, PostgresRepo};
+use async_trait::async_trait;
+use bb8::PooledConnection;
+use bb8_postgres::PostgresConnectionManager;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::NoTls;
+
+/// A login of a user, kept alive by its rotating refresh token.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+  pub id: i64,
+  pub user_id: i64,
+  pub refresh_token_hash: String,
+  // The hash replaced by the last rotation, presenting it again means the token leaked
+  pub previous_refresh_token_hash: Option<String>,
+  pub created_at: DateTime<Utc>,
+  pub last_used_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+  pub revoked_at: Option<DateTime<Utc>>,
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>,
+}
+
+/// A single-use ticket letting a socket handshake act on behalf of a session.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WsTicket {
+  pub ticket_hash: String,
+  pub user_id: i64,
+  pub session_id: i64,
+  pub expires_at: DateTime<Utc>,
+}
+
+impl Session {
+  pub fn is_active(&self) -> bool {
+    self.revoked_at.is_none() && self.expires_at > Utc::now()
+  }
+}
+
+pub async fn create_session(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  refresh_token_hash: &str,
+  expires_at: DateTime<Utc>,
+  user_agent: Option<String>,
+  ip_address: Option<String>,
+) -> Result<Session, tokio_postgres::Error> {
+  let query = "INSERT INTO user_session (user_id, refresh_token_hash, expires_at, user_agent, ip_address) VALUES ($1, $2, $3, $4, $5) RETURNING *";
+  let row = conn
+    .query_one(
+      query,
+      &[
+        &user_id,
+        &refresh_token_hash,
+        &expires_at,
+        &user_agent,
+        &ip_address,
+      ],
+    )
+    .await?;
+  Ok(row_to_session(row))
+}
+
+pub async fn list_user_sessions(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+) -> Result<Vec<Session>, tokio_postgres::Error> {
+  let query = "SELECT * FROM user_session WHERE user_id = $1 AND revoked_at is NULL AND expires_at > NOW() ORDER BY last_used_at DESC";
+  let rows = conn.query(query, &[&user_id]).await?;
+  Ok(rows.into_iter().map(row_to_session).collect())
+}
+
+pub async fn touch_session(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+) -> Result<(), tokio_postgres::Error> {
+  let query = "UPDATE user_session SET last_used_at = NOW() WHERE id = $1";
+  conn.execute(query, &[&id]).await?;
+  Ok(())
+}
+
+pub async fn get_session_by_id(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+) -> Result<Session, tokio_postgres::Error> {
+  let query = "SELECT * FROM user_session WHERE id = $1";
+  let row = conn.query_one(query, &[&id]).await?;
+  Ok(row_to_session(row))
+}
+
+pub async fn find_session_by_refresh_token(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  refresh_token_hash: &str,
+) -> Result<Session, tokio_postgres::Error> {
+  let query =
+    "SELECT * FROM user_session WHERE refresh_token_hash = $1 OR previous_refresh_token_hash = $1";
+  let row = conn.query_one(query, &[&refresh_token_hash]).await?;
+  Ok(row_to_session(row))
+}
+
+pub async fn rotate_refresh_token(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+  refresh_token_hash: &str,
+  new_refresh_token_hash: &str,
+  expires_at: DateTime<Utc>,
+) -> Result<Session, tokio_postgres::Error> {
+  // only succeeds for the current token, so two concurrent refreshes cannot both win
+  let query = "UPDATE user_session SET previous_refresh_token_hash = refresh_token_hash, refresh_token_hash = $3, last_used_at = NOW(), expires_at = $4 WHERE id = $1 AND refresh_token_hash = $2 AND revoked_at is NULL RETURNING *";
+  let row = conn
+    .query_one(
+      query,
+      &[
+        &id,
+        &refresh_token_hash,
+        &new_refresh_token_hash,
+        &expires_at,
+      ],
+    )
+    .await?;
+  Ok(row_to_session(row))
+}
+
+pub async fn revoke_session(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  id: i64,
+) -> Result<Session, tokio_postgres::Error> {
+  let query = "UPDATE user_session SET revoked_at = COALESCE(revoked_at, NOW()) WHERE id = $1 AND user_id = $2 RETURNING *";
+  let row = conn.query_one(query, &[&id, &user_id]).await?;
+  Ok(row_to_session(row))
+}
+
+pub async fn revoke_user_sessions(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  except_id: Option<i64>,
+) -> Result<Vec<i64>, tokio_postgres::Error> {
+  let query = "UPDATE user_session SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at is NULL AND id IS DISTINCT FROM $2 RETURNING id";
+  let rows = conn.query(query, &[&user_id, &except_id]).await?;
+  Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+pub async fn create_ws_ticket(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  ticket: &WsTicket,
+) -> Result<(), tokio_postgres::Error> {
+  // tickets that were never used pile up otherwise
+  conn
+    .execute("DELETE FROM ws_ticket WHERE expires_at < NOW()", &[])
+    .await?;
+  let query =
+    "INSERT INTO ws_ticket (ticket_hash, user_id, session_id, expires_at) VALUES ($1, $2, $3, $4)";
+  conn
+    .execute(
+      query,
+      &[
+        &ticket.ticket_hash,
+        &ticket.user_id,
+        &ticket.session_id,
+        &ticket.expires_at,
+      ],
+    )
+    .await?;
+  Ok(())
+}
+
+pub async fn take_ws_ticket(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  ticket_hash: &str,
+) -> Result<WsTicket, tokio_postgres::Error> {
+  let query = "DELETE FROM ws_ticket WHERE ticket_hash = $1 RETURNING *";
+  let row = conn.query_one(query, &[&ticket_hash]).await?;
+  Ok(WsTicket {
+    ticket_hash: row.get(0),
+    user_id: row.get(1),
+    session_id: row.get(2),
+    expires_at: row.get(3),
+  })
+}
+
+fn row_to_session(row: tokio_postgres::Row) -> Session {
+  Session {
+    id: row.get(0),
+    user_id: row.get(1),
+    refresh_token_hash: row.get(2),
+    previous_refresh_token_hash: row.get(3),
+    created_at: row.get(4),
+    last_used_at: row.get(5),
+    expires_at: row.get(6),
+    revoked_at: row.get(7),
+    user_agent: row.get(8),
+    ip_address: row.get(9),
+  }
+}
+
+#[async_trait]
+pub trait SessionRepo: Send + Sync {
+  async fn create_session(
+    &self,
+    user_id: i64,
+    refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+  ) -> Result<Session, DbError>;
+  /// Lists the live sessions of the user, most recently used first.
+  async fn list_user_sessions(&self, user_id: i64) -> Result<Vec<Session>, DbError>;
+  async fn touch_session(&self, id: i64) -> Result<(), DbError>;
+  async fn get_session_by_id(&self, id: i64) -> Result<Session, DbError>;
+  /// Matches the current as well as the previous refresh token of a session.
+  async fn find_session_by_refresh_token(
+    &self,
+    refresh_token_hash: &str,
+  ) -> Result<Session, DbError>;
+  async fn rotate_refresh_token(
+    &self,
+    id: i64,
+    refresh_token_hash: &str,
+    new_refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<Session, DbError>;
+  /// Revokes a session of the user, `NotFound` when the user does not own it.
+  async fn revoke_session(&self, user_id: i64, id: i64) -> Result<Session, DbError>;
+  /// Revokes every live session of the user but `except_id`, returning the revoked ids.
+  async fn revoke_user_sessions(
+    &self,
+    user_id: i64,
+    except_id: Option<i64>,
+  ) -> Result<Vec<i64>, DbError>;
+  async fn create_ws_ticket(&self, ticket: &WsTicket) -> Result<(), DbError>;
+  /// Removes the ticket and returns it, so it can only be taken once. Expired tickets
+  /// are returned as well, the caller checks `expires_at`.
+  async fn take_ws_ticket(&self, ticket_hash: &str) -> Result<WsTicket, DbError>;
+}
+
+#[async_trait]
+impl SessionRepo for PostgresRepo {
+  async fn create_session(
+    &self,
+    user_id: i64,
+    refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+  ) -> Result<Session, DbError> {
+    Ok(
+      create_session(
+        &mut self.conn().await?,
+        user_id,
+        refresh_token_hash,
+        expires_at,
+        user_agent,
+        ip_address,
+      )
+      .await?,
+    )
+  }
+
+  async fn list_user_sessions(&self, user_id: i64) -> Result<Vec<Session>, DbError> {
+    Ok(list_user_sessions(&mut self.conn().await?, user_id).await?)
+  }
+
+  async fn touch_session(&self, id: i64) -> Result<(), DbError> {
+    Ok(touch_session(&mut self.conn().await?, id).await?)
+  }
+
+  async fn get_session_by_id(&self, id: i64) -> Result<Session, DbError> {
+    Ok(get_session_by_id(&mut self.conn().await?, id).await?)
+  }
+
+  async fn find_session_by_refresh_token(
+    &self,
+    refresh_token_hash: &str,
+  ) -> Result<Session, DbError> {
+    Ok(find_session_by_refresh_token(&mut self.conn().await?, refresh_token_hash).await?)
+  }
+
+  async fn rotate_refresh_token(
+    &self,
+    id: i64,
+    refresh_token_hash: &str,
+    new_refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+  ) -> Result<Session, DbError> {
+    Ok(
+      rotate_refresh_token(
+        &mut self.conn().await?,
+        id,
+        refresh_token_hash,
+        new_refresh_token_hash,
+        expires_at,
+      )
+      .await?,
+    )
+  }
+
+  async fn revoke_session(&self, user_id: i64, id: i64) -> Result<Session, DbError> {
+    Ok(revoke_session(&mut self.conn().await?, user_id, id).await?)
+  }
+
+  async fn revoke_user_sessions(
+    &self,
+    user_id: i64,
+    except_id: Option<i64>,
+  ) -> Result<Vec<i64>, DbError> {
+    Ok(revoke_user_sessions(&mut self.conn().await?, user_id, except_id).await?)
+  }
+
+  async fn create_ws_ticket(&self, ticket: &WsTicket) -> Result<(), DbError> {
+    Ok(create_ws_ticket(&mut self.conn().await?, ticket).await?)
+  }
+
+  async fn take_ws_ticket(&self, ticket_hash: &str) -> Result<WsTicket, DbError> {
+    Ok(take_ws_ticket(&mut self.conn().await?, ticket_hash).await?)
+  }
+}
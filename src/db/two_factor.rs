@@ -0,0 +1,250 @@
+use super::{DbError, PostgresRepo};
+use async_trait::async_trait;
+use bb8::PooledConnection;
+use bb8_postgres::PostgresConnectionManager;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::NoTls;
+
+/// The TOTP authenticator of a user, enrolled but not in use until `confirmed_at` is set.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UserTotp {
+  pub user_id: i64,
+  pub secret_ciphertext: String,
+  pub confirmed_at: Option<DateTime<Utc>>,
+  // Time step of the last accepted code, a code is never accepted twice
+  pub last_used_step: Option<i64>,
+  pub recovery_code_hashes: Vec<String>,
+  pub created_at: DateTime<Utc>,
+}
+
+/// A login whose password was right, waiting for the second factor.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LoginChallenge {
+  pub challenge_hash: String,
+  pub user_id: i64,
+  pub expires_at: DateTime<Utc>,
+  pub failed_attempts: i32,
+}
+
+pub async fn start_totp_enrolment(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  secret_ciphertext: &str,
+) -> Result<UserTotp, tokio_postgres::Error> {
+  // a pending enrolment is replaced, a confirmed one is kept and no row is returned
+  let query = "INSERT INTO user_totp (user_id, secret_ciphertext) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET secret_ciphertext = EXCLUDED.secret_ciphertext, last_used_step = NULL, recovery_code_hashes = '{}', created_at = NOW() WHERE user_totp.confirmed_at IS NULL RETURNING *";
+  let row = conn
+    .query_one(query, &[&user_id, &secret_ciphertext])
+    .await?;
+  Ok(row_to_user_totp(row))
+}
+
+pub async fn get_user_totp(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+) -> Result<UserTotp, tokio_postgres::Error> {
+  let query = "SELECT * FROM user_totp WHERE user_id = $1";
+  let row = conn.query_one(query, &[&user_id]).await?;
+  Ok(row_to_user_totp(row))
+}
+
+pub async fn confirm_totp(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  step: i64,
+  recovery_code_hashes: &[String],
+) -> Result<UserTotp, tokio_postgres::Error> {
+  let query = "UPDATE user_totp SET confirmed_at = NOW(), last_used_step = $2, recovery_code_hashes = $3 WHERE user_id = $1 AND confirmed_at IS NULL RETURNING *";
+  let row = conn
+    .query_one(query, &[&user_id, &step, &recovery_code_hashes])
+    .await?;
+  Ok(row_to_user_totp(row))
+}
+
+pub async fn use_totp_step(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  step: i64,
+) -> Result<UserTotp, tokio_postgres::Error> {
+  let query = "UPDATE user_totp SET last_used_step = $2 WHERE user_id = $1 AND confirmed_at IS NOT NULL AND (last_used_step IS NULL OR last_used_step < $2) RETURNING *";
+  let row = conn.query_one(query, &[&user_id, &step]).await?;
+  Ok(row_to_user_totp(row))
+}
+
+pub async fn use_recovery_code(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+  code_hash: &str,
+) -> Result<UserTotp, tokio_postgres::Error> {
+  let query = "UPDATE user_totp SET recovery_code_hashes = array_remove(recovery_code_hashes, $2) WHERE user_id = $1 AND confirmed_at IS NOT NULL AND $2 = ANY(recovery_code_hashes) RETURNING *";
+  let row = conn.query_one(query, &[&user_id, &code_hash]).await?;
+  Ok(row_to_user_totp(row))
+}
+
+pub async fn delete_user_totp(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  user_id: i64,
+) -> Result<UserTotp, tokio_postgres::Error> {
+  let query = "DELETE FROM user_totp WHERE user_id = $1 RETURNING *";
+  let row = conn.query_one(query, &[&user_id]).await?;
+  Ok(row_to_user_totp(row))
+}
+
+pub async fn create_login_challenge(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  challenge: &LoginChallenge,
+) -> Result<(), tokio_postgres::Error> {
+  // abandoned logins pile up otherwise
+  conn
+    .execute("DELETE FROM login_challenge WHERE expires_at < NOW()", &[])
+    .await?;
+  let query =
+    "INSERT INTO login_challenge (challenge_hash, user_id, expires_at) VALUES ($1, $2, $3)";
+  conn
+    .execute(
+      query,
+      &[
+        &challenge.challenge_hash,
+        &challenge.user_id,
+        &challenge.expires_at,
+      ],
+    )
+    .await?;
+  Ok(())
+}
+
+pub async fn get_login_challenge(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  challenge_hash: &str,
+) -> Result<LoginChallenge, tokio_postgres::Error> {
+  let query = "SELECT * FROM login_challenge WHERE challenge_hash = $1";
+  let row = conn.query_one(query, &[&challenge_hash]).await?;
+  Ok(row_to_login_challenge(row))
+}
+
+pub async fn record_failed_challenge(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  challenge_hash: &str,
+) -> Result<LoginChallenge, tokio_postgres::Error> {
+  let query = "UPDATE login_challenge SET failed_attempts = failed_attempts + 1 WHERE challenge_hash = $1 RETURNING *";
+  let row = conn.query_one(query, &[&challenge_hash]).await?;
+  Ok(row_to_login_challenge(row))
+}
+
+pub async fn delete_login_challenge(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  challenge_hash: &str,
+) -> Result<LoginChallenge, tokio_postgres::Error> {
+  let query = "DELETE FROM login_challenge WHERE challenge_hash = $1 RETURNING *";
+  let row = conn.query_one(query, &[&challenge_hash]).await?;
+  Ok(row_to_login_challenge(row))
+}
+
+fn row_to_user_totp(row: tokio_postgres::Row) -> UserTotp {
+  UserTotp {
+    user_id: row.get(0),
+    secret_ciphertext: row.get(1),
+    confirmed_at: row.get(2),
+    last_used_step: row.get(3),
+    recovery_code_hashes: row.get(4),
+    created_at: row.get(5),
+  }
+}
+
+fn row_to_login_challenge(row: tokio_postgres::Row) -> LoginChallenge {
+  LoginChallenge {
+    challenge_hash: row.get(0),
+    user_id: row.get(1),
+    expires_at: row.get(2),
+    failed_attempts: row.get(3),
+  }
+}
+
+#[async_trait]
+pub trait TwoFactorRepo: Send + Sync {
+  /// Stores a new, unconfirmed secret. `UniqueViolation` when the user already has a
+  /// confirmed authenticator.
+  async fn start_totp_enrolment(
+    &self,
+    user_id: i64,
+    secret_ciphertext: &str,
+  ) -> Result<UserTotp, DbError>;
+  async fn get_user_totp(&self, user_id: i64) -> Result<UserTotp, DbError>;
+  /// Turns a pending enrolment on, `NotFound` when there is none.
+  async fn confirm_totp(
+    &self,
+    user_id: i64,
+    step: i64,
+    recovery_code_hashes: &[String],
+  ) -> Result<UserTotp, DbError>;
+  /// Records the time step of an accepted code, `NotFound` when it is not newer than
+  /// the last one, i.e. the code is replayed.
+  async fn use_totp_step(&self, user_id: i64, step: i64) -> Result<UserTotp, DbError>;
+  /// Crosses off a recovery code, `NotFound` when it is unknown or already used.
+  async fn use_recovery_code(&self, user_id: i64, code_hash: &str) -> Result<UserTotp, DbError>;
+  async fn delete_user_totp(&self, user_id: i64) -> Result<UserTotp, DbError>;
+  async fn create_login_challenge(&self, challenge: &LoginChallenge) -> Result<(), DbError>;
+  async fn get_login_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError>;
+  async fn record_failed_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError>;
+  /// Removes the challenge, so only one request can complete the login.
+  async fn delete_login_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError>;
+}
+
+#[async_trait]
+impl TwoFactorRepo for PostgresRepo {
+  async fn start_totp_enrolment(
+    &self,
+    user_id: i64,
+    secret_ciphertext: &str,
+  ) -> Result<UserTotp, DbError> {
+    match start_totp_enrolment(&mut self.conn().await?, user_id, secret_ciphertext).await {
+      Ok(totp) => Ok(totp),
+      Err(e) => match DbError::from(e) {
+        DbError::NotFound => Err(DbError::UniqueViolation),
+        e => Err(e),
+      },
+    }
+  }
+
+  async fn get_user_totp(&self, user_id: i64) -> Result<UserTotp, DbError> {
+    Ok(get_user_totp(&mut self.conn().await?, user_id).await?)
+  }
+
+  async fn confirm_totp(
+    &self,
+    user_id: i64,
+    step: i64,
+    recovery_code_hashes: &[String],
+  ) -> Result<UserTotp, DbError> {
+    Ok(confirm_totp(&mut self.conn().await?, user_id, step, recovery_code_hashes).await?)
+  }
+
+  async fn use_totp_step(&self, user_id: i64, step: i64) -> Result<UserTotp, DbError> {
+    Ok(use_totp_step(&mut self.conn().await?, user_id, step).await?)
+  }
+
+  async fn use_recovery_code(&self, user_id: i64, code_hash: &str) -> Result<UserTotp, DbError> {
+    Ok(use_recovery_code(&mut self.conn().await?, user_id, code_hash).await?)
+  }
+
+  async fn delete_user_totp(&self, user_id: i64) -> Result<UserTotp, DbError> {
+    Ok(delete_user_totp(&mut self.conn().await?, user_id).await?)
+  }
+
+  async fn create_login_challenge(&self, challenge: &LoginChallenge) -> Result<(), DbError> {
+    Ok(create_login_challenge(&mut self.conn().await?, challenge).await?)
+  }
+
+  async fn get_login_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError> {
+    Ok(get_login_challenge(&mut self.conn().await?, challenge_hash).await?)
+  }
+
+  async fn record_failed_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError> {
+    Ok(record_failed_challenge(&mut self.conn().await?, challenge_hash).await?)
+  }
+
+  async fn delete_login_challenge(&self, challenge_hash: &str) -> Result<LoginChallenge, DbError> {
+    Ok(delete_login_challenge(&mut self.conn().await?, challenge_hash).await?)
+  }
+}
@@ -1,3 +1,6 @@
+use super::member::{row_to_member, Member};
+use super::{DbError, PostgresRepo};
+use async_trait::async_trait;
 use bb8::PooledConnection;
 use bb8_postgres::PostgresConnectionManager;
 use chrono::DateTime;
@@ -10,17 +13,87 @@ pub struct User {
   pub name: String,
   pub password: String,
   pub created_at: chrono::DateTime<chrono::Utc>,
+  // Explicitly chosen status, `None` means it is derived from live sockets only
+  pub status: Option<UserStatus>,
+  pub status_text: Option<String>,
+  pub last_seen_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub email: Option<String>,
+  pub display_name: Option<String>,
+  pub bio: Option<String>,
+  pub avatar_url: Option<String>,
+  pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub hidden_from_search: bool,
+  // Number of instances holding a live socket of the user
+  pub online_instances: i32,
+}
+
+/// Shown instead of the names of deleted users.
+pub const DELETED_USER_NAME: &str = "Deleted user";
+
+impl User {
+  /// The name shown to other users, the login name unless a display name is set.
+  pub fn display_name(&self) -> &str {
+    self.display_name.as_deref().unwrap_or(&self.name)
+  }
+}
+
+/// The editable part of a user.
+#[derive(Clone, Debug)]
+pub struct UserProfile {
+  pub name: String,
+  pub display_name: Option<String>,
+  pub bio: Option<String>,
+  pub avatar_url: Option<String>,
+  pub hidden_from_search: bool,
+}
+
+/// A single-use token letting the user choose a new password.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasswordReset {
+  pub token_hash: String,
+  pub user_id: i64,
+  pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UserStatus {
+  Online,
+  Away,
+  DoNotDisturb,
+  Offline,
+}
+
+impl UserStatus {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      UserStatus::Online => "online",
+      UserStatus::Away => "away",
+      UserStatus::DoNotDisturb => "dnd",
+      UserStatus::Offline => "offline",
+    }
+  }
+
+  fn from_db(value: &str) -> Option<UserStatus> {
+    match value {
+      "online" => Some(UserStatus::Online),
+      "away" => Some(UserStatus::Away),
+      "dnd" => Some(UserStatus::DoNotDisturb),
+      "offline" => Some(UserStatus::Offline),
+      _ => None,
+    }
+  }
 }
 
 pub async fn insert_new_user(
   conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
   name: String,
   password: String,
+  email: Option<String>,
 ) -> Result<User, tokio_postgres::Error> {
-  let query = "INSERT INTO users (name, password) VALUES ($1, $2)";
-  conn.execute(query, &[&name, &password]).await?;
-  let user = get_user(conn, name, password).await?;
-  Ok(user)
+  let query = "INSERT INTO users (name, password, email) VALUES ($1, $2, $3) RETURNING *";
+  let row = conn.query_one(query, &[&name, &password, &email]).await?;
+  Ok(row_to_user(row))
 }
 
 pub async fn get_user(
@@ -51,15 +124,382 @@ pub async fn get_user_by_id(
   Ok(row_to_user(row))
 }
 
+pub async fn get_live_user_by_id(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+) -> Result<User, tokio_postgres::Error> {
+  let query = "SELECT * FROM users WHERE id = $1 AND deleted_at is NULL";
+  let row = conn.query_one(query, &[&id]).await?;
+  Ok(row_to_user(row))
+}
+
+pub async fn get_user_by_email(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  email: &str,
+) -> Result<User, tokio_postgres::Error> {
+  let query = "SELECT * FROM users WHERE email = $1";
+  let row = conn.query_one(query, &[&email]).await?;
+  Ok(row_to_user(row))
+}
+
+pub async fn update_password(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+  password: &str,
+) -> Result<User, tokio_postgres::Error> {
+  let query = "UPDATE users SET password = $2 WHERE id = $1 RETURNING *";
+  let row = conn.query_one(query, &[&id, &password]).await?;
+  Ok(row_to_user(row))
+}
+
+pub async fn create_password_reset(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  reset: &PasswordReset,
+) -> Result<(), tokio_postgres::Error> {
+  // requested but never used tokens pile up otherwise
+  conn
+    .execute("DELETE FROM password_reset WHERE expires_at < NOW()", &[])
+    .await?;
+  let query = "INSERT INTO password_reset (token_hash, user_id, expires_at) VALUES ($1, $2, $3)";
+  conn
+    .execute(
+      query,
+      &[&reset.token_hash, &reset.user_id, &reset.expires_at],
+    )
+    .await?;
+  Ok(())
+}
+
+pub async fn take_password_reset(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  token_hash: &str,
+) -> Result<Vec<PasswordReset>, tokio_postgres::Error> {
+  // every token of the user goes, the one presented among them
+  let query = "DELETE FROM password_reset WHERE user_id = (SELECT user_id FROM password_reset WHERE token_hash = $1) RETURNING *";
+  let rows = conn.query(query, &[&token_hash]).await?;
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| PasswordReset {
+        token_hash: row.get(0),
+        user_id: row.get(1),
+        expires_at: row.get(2),
+      })
+      .collect(),
+  )
+}
+
+pub async fn update_status(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+  status: Option<UserStatus>,
+  status_text: Option<String>,
+) -> Result<User, tokio_postgres::Error> {
+  let query = "UPDATE users SET status = $2, status_text = $3 WHERE id = $1";
+  let status = status.map(|s| s.as_str());
+  conn.execute(query, &[&id, &status, &status_text]).await?;
+  get_user_by_id(conn, id).await
+}
+
+pub async fn update_profile(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+  profile: &UserProfile,
+) -> Result<User, tokio_postgres::Error> {
+  let query = "UPDATE users SET name = $2, display_name = $3, bio = $4, avatar_url = $5, hidden_from_search = $6 WHERE id = $1 RETURNING *";
+  let row = conn
+    .query_one(
+      query,
+      &[
+        &id,
+        &profile.name,
+        &profile.display_name,
+        &profile.bio,
+        &profile.avatar_url,
+        &profile.hidden_from_search,
+      ],
+    )
+    .await?;
+  Ok(row_to_user(row))
+}
+
+pub async fn search_users(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  prefix: &str,
+  limit: i64,
+) -> Result<Vec<User>, tokio_postgres::Error> {
+  let query = "SELECT * FROM users WHERE deleted_at is NULL AND NOT hidden_from_search AND (lower(name) LIKE $1 OR lower(display_name) LIKE $1) ORDER BY lower(COALESCE(display_name, name)), id LIMIT $2";
+  let pattern = format!("{}%", escape_like(&prefix.to_lowercase()));
+  let rows = conn.query(query, &[&pattern, &limit]).await?;
+  Ok(rows.into_iter().map(row_to_user).collect())
+}
+
+// the user's input must not bring its own wildcards
+fn escape_like(text: &str) -> String {
+  text
+    .replace('\\', "\\\\")
+    .replace('%', "\\%")
+    .replace('_', "\\_")
+}
+
+/// What deleting an account changed, returned once it is committed.
+pub struct AccountDeletion {
+  pub user: User,
+  // (room id, new owner id) of the owned rooms handed to their longest standing member
+  pub transferred_rooms: Vec<(i64, i64)>,
+  // owned rooms nobody was left in, then the rooms the user was the last member of
+  pub deleted_rooms: Vec<i64>,
+  // the memberships the user had when the account was deleted
+  pub left_memberships: Vec<Member>,
+}
+
+/// Runs every step of an account deletion in one transaction.
+pub async fn delete_account(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+  name: &str,
+  password: &str,
+) -> Result<AccountDeletion, tokio_postgres::Error> {
+  let transaction = conn.transaction().await?;
+  let mut transferred_rooms = Vec::new();
+  let mut deleted_rooms = Vec::new();
+
+  let query = "SELECT id FROM room WHERE created_by = $1 AND deleted_at is NULL AND direct_user_a is NULL ORDER BY created_at ASC FOR UPDATE";
+  let owned_rooms = transaction.query(query, &[&id]).await?;
+  for row in owned_rooms {
+    let room_id: i64 = row.get(0);
+    let query = "SELECT user_id FROM room_member WHERE room_id = $1 AND user_id <> $2 AND deleted_at is NULL ORDER BY created_at ASC, id ASC LIMIT 1";
+    match transaction.query_opt(query, &[&room_id, &id]).await? {
+      Some(heir) => {
+        let heir_id: i64 = heir.get(0);
+        transaction
+          .execute(
+            "UPDATE room SET created_by = $2 WHERE id = $1",
+            &[&room_id, &heir_id],
+          )
+          .await?;
+        transferred_rooms.push((room_id, heir_id));
+      }
+      None => {
+        transaction
+          .execute(
+            "UPDATE room SET deleted_at = NOW() WHERE id = $1",
+            &[&room_id],
+          )
+          .await?;
+        deleted_rooms.push(room_id);
+      }
+    }
+  }
+
+  let query = "UPDATE room_member SET deleted_at = NOW() WHERE user_id = $1 AND deleted_at is NULL RETURNING *";
+  let rows = transaction.query(query, &[&id]).await?;
+  let left_memberships: Vec<Member> = rows.into_iter().map(row_to_member).collect();
+  let left_room_ids: Vec<i64> = left_memberships.iter().map(|m| m.room_id).collect();
+  let query = "UPDATE room SET deleted_at = NOW() WHERE id = ANY($1) AND deleted_at is NULL AND NOT EXISTS (SELECT 1 FROM room_member WHERE room_member.room_id = room.id AND room_member.deleted_at is NULL) RETURNING id";
+  for row in transaction.query(query, &[&left_room_ids]).await? {
+    deleted_rooms.push(row.get(0));
+  }
+
+  transaction
+    .execute("DELETE FROM user_totp WHERE user_id = $1", &[&id])
+    .await?;
+  // a reset token must not bring the account back
+  transaction
+    .execute("DELETE FROM password_reset WHERE user_id = $1", &[&id])
+    .await?;
+  // whom the user blocked is personal data, being blocked by others is not
+  transaction
+    .execute("DELETE FROM user_block WHERE blocker_id = $1", &[&id])
+    .await?;
+  let query = "UPDATE users SET name = $2, password = $3, email = NULL, display_name = $4, bio = NULL, avatar_url = NULL, status = NULL, status_text = NULL, deleted_at = NOW() WHERE id = $1 AND deleted_at is NULL RETURNING *";
+  let row = transaction
+    .query_one(query, &[&id, &name, &password, &DELETED_USER_NAME])
+    .await?;
+  transaction.commit().await?;
+
+  Ok(AccountDeletion {
+    user: row_to_user(row),
+    transferred_rooms,
+    deleted_rooms,
+    left_memberships,
+  })
+}
+
+pub async fn update_last_seen_at(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+) -> Result<(), tokio_postgres::Error> {
+  let query = "UPDATE users SET last_seen_at = NOW() WHERE id = $1";
+  conn.execute(query, &[&id]).await?;
+  Ok(())
+}
+
+pub async fn update_online_instances(
+  conn: &mut PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+  id: i64,
+  delta: i32,
+) -> Result<(), tokio_postgres::Error> {
+  let query = "UPDATE users SET online_instances = online_instances + $2 WHERE id = $1";
+  conn.execute(query, &[&id, &delta]).await?;
+  Ok(())
+}
+
 fn row_to_user(row: tokio_postgres::Row) -> User {
   let id: i64 = row.get(0);
   let name: String = row.get(1);
   let password: String = row.get(2);
   let created_at: DateTime<chrono::Utc> = row.get(3);
+  let status: Option<String> = row.get(4);
+  let status_text: Option<String> = row.get(5);
+  let last_seen_at: Option<DateTime<chrono::Utc>> = row.get(6);
+  let email: Option<String> = row.get(7);
+  let display_name: Option<String> = row.get(8);
+  let bio: Option<String> = row.get(9);
+  let avatar_url: Option<String> = row.get(10);
+  let deleted_at: Option<DateTime<chrono::Utc>> = row.get(11);
+  let hidden_from_search: bool = row.get(12);
+  let online_instances: i32 = row.get(13);
   User {
     id,
     name,
     password,
     created_at,
+    status: status.as_deref().and_then(UserStatus::from_db),
+    status_text,
+    last_seen_at,
+    email,
+    display_name,
+    bio,
+    avatar_url,
+    deleted_at,
+    hidden_from_search,
+    online_instances,
+  }
+}
+
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+  async fn insert_new_user(
+    &self,
+    name: String,
+    password: String,
+    email: Option<String>,
+  ) -> Result<User, DbError>;
+  async fn get_user(&self, name: String, password: String) -> Result<User, DbError>;
+  async fn get_user_by_name(&self, name: String) -> Result<User, DbError>;
+  async fn get_user_by_id(&self, id: i64) -> Result<User, DbError>;
+  /// Like `get_user_by_id`, but deleted accounts are `NotFound`.
+  async fn get_live_user_by_id(&self, id: i64) -> Result<User, DbError>;
+  async fn get_user_by_email(&self, email: &str) -> Result<User, DbError>;
+  async fn update_password(&self, id: i64, password: &str) -> Result<User, DbError>;
+  async fn create_password_reset(&self, reset: &PasswordReset) -> Result<(), DbError>;
+  /// Removes every reset token of the user `token_hash` belongs to, returning that one,
+  /// `NotFound` when it is unknown. Expired tokens are returned too.
+  async fn take_password_reset(&self, token_hash: &str) -> Result<PasswordReset, DbError>;
+  async fn update_status(
+    &self,
+    id: i64,
+    status: Option<UserStatus>,
+    status_text: Option<String>,
+  ) -> Result<User, DbError>;
+  /// Replaces the profile of the user, `UniqueViolation` when the name is taken.
+  async fn update_profile(&self, id: i64, profile: &UserProfile) -> Result<User, DbError>;
+  /// Finds live users whose name or display name starts with `prefix`, ignoring case
+  /// and leaving out the users hidden from search.
+  async fn search_users(&self, prefix: &str, limit: i64) -> Result<Vec<User>, DbError>;
+  /// Deletes the account in one go: owned rooms go to their longest standing member or are
+  /// deleted, the user leaves every room, and the user is stripped of every personal detail
+  /// and marked deleted. The row stays, so the messages of the user keep their sender.
+  async fn delete_account(
+    &self,
+    id: i64,
+    name: &str,
+    password: &str,
+  ) -> Result<AccountDeletion, DbError>;
+  async fn update_last_seen_at(&self, id: i64) -> Result<(), DbError>;
+  /// Adds `delta` to the number of instances holding a live socket of the user.
+  async fn update_online_instances(&self, id: i64, delta: i32) -> Result<(), DbError>;
+}
+
+#[async_trait]
+impl UserRepo for PostgresRepo {
+  async fn insert_new_user(
+    &self,
+    name: String,
+    password: String,
+    email: Option<String>,
+  ) -> Result<User, DbError> {
+    Ok(insert_new_user(&mut self.conn().await?, name, password, email).await?)
+  }
+
+  async fn get_user(&self, name: String, password: String) -> Result<User, DbError> {
+    Ok(get_user(&mut self.conn().await?, name, password).await?)
+  }
+
+  async fn get_user_by_name(&self, name: String) -> Result<User, DbError> {
+    Ok(get_user_by_name(&mut self.conn().await?, name).await?)
+  }
+
+  async fn get_user_by_id(&self, id: i64) -> Result<User, DbError> {
+    Ok(get_user_by_id(&mut self.conn().await?, id).await?)
+  }
+
+  async fn get_live_user_by_id(&self, id: i64) -> Result<User, DbError> {
+    Ok(get_live_user_by_id(&mut self.conn().await?, id).await?)
+  }
+
+  async fn get_user_by_email(&self, email: &str) -> Result<User, DbError> {
+    Ok(get_user_by_email(&mut self.conn().await?, email).await?)
+  }
+
+  async fn update_password(&self, id: i64, password: &str) -> Result<User, DbError> {
+    Ok(update_password(&mut self.conn().await?, id, password).await?)
+  }
+
+  async fn create_password_reset(&self, reset: &PasswordReset) -> Result<(), DbError> {
+    Ok(create_password_reset(&mut self.conn().await?, reset).await?)
+  }
+
+  async fn take_password_reset(&self, token_hash: &str) -> Result<PasswordReset, DbError> {
+    take_password_reset(&mut self.conn().await?, token_hash)
+      .await?
+      .into_iter()
+      .find(|reset| reset.token_hash == token_hash)
+      .ok_or(DbError::NotFound)
+  }
+
+  async fn update_status(
+    &self,
+    id: i64,
+    status: Option<UserStatus>,
+    status_text: Option<String>,
+  ) -> Result<User, DbError> {
+    Ok(update_status(&mut self.conn().await?, id, status, status_text).await?)
+  }
+
+  async fn update_profile(&self, id: i64, profile: &UserProfile) -> Result<User, DbError> {
+    Ok(update_profile(&mut self.conn().await?, id, profile).await?)
+  }
+
+  async fn search_users(&self, prefix: &str, limit: i64) -> Result<Vec<User>, DbError> {
+    Ok(search_users(&mut self.conn().await?, prefix, limit).await?)
+  }
+
+  async fn delete_account(
+    &self,
+    id: i64,
+    name: &str,
+    password: &str,
+  ) -> Result<AccountDeletion, DbError> {
+    Ok(delete_account(&mut self.conn().await?, id, name, password).await?)
+  }
+
+  async fn update_last_seen_at(&self, id: i64) -> Result<(), DbError> {
+    Ok(update_last_seen_at(&mut self.conn().await?, id).await?)
+  }
+
+  async fn update_online_instances(&self, id: i64, delta: i32) -> Result<(), DbError> {
+    Ok(update_online_instances(&mut self.conn().await?, id, delta).await?)
   }
 }
@@ -1,70 +1,209 @@
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use crate::db::DbError;
+use axum::{
+  body::{boxed, Full},
+  http::{header::CONTENT_LENGTH, Request, StatusCode},
+  middleware::Next,
+  response::{IntoResponse, Response},
+  Json,
+};
 use serde::Serialize;
+use tower_http::request_id::RequestId;
+
+/// Stable, machine-readable error codes shared by the HTTP API and the socket error
+/// frames. Clients match on these, so existing codes must never be renamed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+  BadRequest,
+  // a socket frame that could not be parsed
+  InvalidFrame,
+  // a socket message for a room the socket is not subscribed to
+  NotSubscribed,
+  // a chat message longer than `MAX_MESSAGE_LEN` characters
+  MessageTooLong,
+  WeakPassword,
+  InvalidResetToken,
+  Unauthorized,
+  InvalidCredentials,
+  TokenExpired,
+  InvalidRefreshToken,
+  SessionRevoked,
+  // a websocket ticket that is unknown, expired or already used
+  InvalidTicket,
+  InvalidTotpCode,
+  // a second login step with an unknown, expired or exhausted challenge token
+  InvalidLoginChallenge,
+  NotRoomOwner,
+  NotAMember,
+  // the current password given to change it is wrong
+  WrongPassword,
+  // a direct message between users where one blocked the other
+  UserBlocked,
+  Forbidden,
+  NotFound,
+  UserNotFound,
+  RoomNotFound,
+  MemberNotFound,
+  SessionNotFound,
+  TotpNotEnrolled,
+  BlockNotFound,
+  Conflict,
+  NameTaken,
+  EmailTaken,
+  TotpAlreadyEnabled,
+  // too many requests or socket messages, retry later
+  RateLimited,
+  InternalError,
+  DatabaseError,
+}
+
+impl ErrorCode {
+  pub fn status(self) -> StatusCode {
+    match self {
+      ErrorCode::BadRequest
+      | ErrorCode::InvalidFrame
+      | ErrorCode::NotSubscribed
+      | ErrorCode::MessageTooLong
+      | ErrorCode::WeakPassword
+      | ErrorCode::InvalidResetToken => StatusCode::BAD_REQUEST,
+      ErrorCode::Unauthorized
+      | ErrorCode::InvalidCredentials
+      | ErrorCode::TokenExpired
+      | ErrorCode::InvalidRefreshToken
+      | ErrorCode::SessionRevoked
+      | ErrorCode::InvalidTicket
+      | ErrorCode::InvalidTotpCode
+      | ErrorCode::InvalidLoginChallenge => StatusCode::UNAUTHORIZED,
+      ErrorCode::NotRoomOwner
+      | ErrorCode::NotAMember
+      | ErrorCode::WrongPassword
+      | ErrorCode::UserBlocked
+      | ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+      ErrorCode::NotFound
+      | ErrorCode::UserNotFound
+      | ErrorCode::RoomNotFound
+      | ErrorCode::MemberNotFound
+      | ErrorCode::SessionNotFound
+      | ErrorCode::TotpNotEnrolled
+      | ErrorCode::BlockNotFound => StatusCode::NOT_FOUND,
+      ErrorCode::Conflict
+      | ErrorCode::NameTaken
+      | ErrorCode::EmailTaken
+      | ErrorCode::TotpAlreadyEnabled => StatusCode::CONFLICT,
+      ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+      ErrorCode::InternalError | ErrorCode::DatabaseError => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+}
 
 #[derive(Debug)]
 pub struct ServiceError {
-  code: StatusCode,
+  code: ErrorCode,
   message: String,
 }
 
 impl ServiceError {
-  pub fn new(code: StatusCode, message: impl Into<String>) -> Self {
+  pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
     Self {
       code,
       message: message.into(),
     }
   }
 
+  pub fn code(&self) -> ErrorCode {
+    self.code
+  }
+
   pub fn message(&self) -> &str {
     &self.message
   }
 }
 
 impl IntoResponse for ServiceError {
-  fn into_response(self) -> axum::response::Response {
-    (
-      self.code,
-      Json(ResponseMessage {
-        message: self.message,
-      }),
-    )
-      .into_response()
+  fn into_response(self) -> Response {
+    let body = ErrorBody {
+      code: self.code,
+      message: self.message,
+      request_id: None,
+    };
+    let mut response = (self.code.status(), Json(&body)).into_response();
+    // `attach_request_id` renders the body again once the request id is known
+    response.extensions_mut().insert(body);
+    response
   }
 }
 
-#[derive(Serialize)]
-struct ResponseMessage {
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorBody {
+  code: ErrorCode,
   message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  request_id: Option<String>,
+}
+
+/// Adds the id of the request to the body of every `ServiceError` response.
+pub async fn attach_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+  let request_id = request
+    .extensions()
+    .get::<RequestId>()
+    .and_then(|id| id.header_value().to_str().ok())
+    .map(str::to_owned);
+  let mut response = next.run(request).await;
+  let body = match response.extensions_mut().remove::<ErrorBody>() {
+    Some(body) => ErrorBody { request_id, ..body },
+    None => return response,
+  };
+  // the router already set the length of the body rendered without the request id
+  response.headers_mut().remove(CONTENT_LENGTH);
+  *response.body_mut() = boxed(Full::from(serde_json::to_vec(&body).unwrap()));
+  response
 }
 
 pub fn internal_error_to_service_error<E>(err: E) -> ServiceError
 where
   E: std::error::Error,
 {
-  ServiceError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+  ServiceError::new(ErrorCode::InternalError, err.to_string())
 }
 
-pub fn db_error_to_service_error(err: tokio_postgres::Error) -> ServiceError {
-  let _row_count_err = "query returned an unexpected number of rows";
-  match err.code() {
-    Some(sql_state) => match sql_state.code() {
-      "23503" => ServiceError::new(StatusCode::BAD_REQUEST, "foreign key violation"),
-      "23505" => ServiceError::new(StatusCode::BAD_REQUEST, "unique key violation"),
-      "P0002" => ServiceError::new(StatusCode::BAD_REQUEST, "Invalid input"),
-      _ => ServiceError::new(
-        StatusCode::INTERNAL_SERVER_ERROR,
+pub fn db_error_to_service_error(err: DbError) -> ServiceError {
+  match err {
+    DbError::ForeignKeyViolation => {
+      ServiceError::new(ErrorCode::BadRequest, "foreign key violation")
+    }
+    DbError::UniqueViolation => ServiceError::new(ErrorCode::Conflict, "unique key violation"),
+    DbError::InvalidInput => ServiceError::new(ErrorCode::BadRequest, "Invalid input"),
+    DbError::NotFound => ServiceError::new(ErrorCode::NotFound, "Not found"),
+    DbError::Pool(e) => ServiceError::new(ErrorCode::DatabaseError, e),
+    DbError::Postgres(e) => match e.code() {
+      Some(sql_state) => ServiceError::new(
+        ErrorCode::DatabaseError,
         "Database error: ".to_owned() + sql_state.code(),
       ),
-    },
-    _ => match err.to_string().as_str() {
-      _row_count_err => ServiceError::new(
-        StatusCode::BAD_REQUEST,
-        "Bad Request: err: row_count_err, most likely data is not found",
-      ),
-      _ => ServiceError::new(
-        StatusCode::INTERNAL_SERVER_ERROR,
-        "Database error: ".to_owned() + &err.to_string(),
+      None => ServiceError::new(
+        ErrorCode::DatabaseError,
+        "Database error: ".to_owned() + &e.to_string(),
       ),
     },
   }
 }
+
+/// Like `db_error_to_service_error`, but a missing row becomes `code`.
+pub fn not_found_as(
+  code: ErrorCode,
+  message: &'static str,
+) -> impl FnOnce(DbError) -> ServiceError {
+  move |err| match err {
+    DbError::NotFound => ServiceError::new(code, message),
+    err => db_error_to_service_error(err),
+  }
+}
+
+/// Like `db_error_to_service_error`, but a unique key violation becomes `code`.
+pub fn conflict_as(code: ErrorCode, message: &'static str) -> impl FnOnce(DbError) -> ServiceError {
+  move |err| match err {
+    DbError::UniqueViolation => ServiceError::new(code, message),
+    err => db_error_to_service_error(err),
+  }
+}
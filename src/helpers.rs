@@ -4,3 +4,13 @@ pub fn get_env(key: &str) -> String {
     Err(e) => panic!("couldn't interpret {}: {}", key, e),
   }
 }
+
+pub fn get_env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+  match std::env::var(key) {
+    Ok(val) => match val.parse() {
+      Ok(val) => val,
+      Err(_) => panic!("couldn't interpret {}: invalid value {:?}", key, val),
+    },
+    Err(_) => default,
+  }
+}
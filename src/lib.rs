@@ -1,11 +1,18 @@
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
 use tokio_postgres::NoTls;
+mod app;
 pub mod auth;
 pub mod db;
 pub mod errors;
 pub mod helpers;
+pub mod logging;
+pub mod mail;
+pub mod metrics;
+pub mod rate_limit;
 pub mod routes;
 pub mod ws;
 
+pub use app::{app, serve, Config, RouteGroups};
+
 pub type ConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
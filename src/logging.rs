@@ -0,0 +1,70 @@
+use crate::helpers::get_env_or;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::EnvFilter;
+
+// Chat messages are private, so their bodies only show up in the logs when asked for.
+static LOG_MESSAGE_BODIES: AtomicBool = AtomicBool::new(false);
+
+pub struct LogConfig {
+  // `EnvFilter` directives, e.g. `info` or `rust_tokio_chat_app=debug,tower_http=info`
+  pub level: String,
+  pub json: bool,
+  pub message_bodies: bool,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    LogConfig {
+      level: "info".to_owned(),
+      json: false,
+      message_bodies: false,
+    }
+  }
+}
+
+impl LogConfig {
+  /// Reads `LOG_LEVEL`, `LOG_FORMAT` (`text` or `json`) and `LOG_MESSAGE_BODIES`.
+  pub fn from_env() -> Self {
+    let default = LogConfig::default();
+    let json = match get_env_or("LOG_FORMAT", "text".to_owned()).as_str() {
+      "text" => false,
+      "json" => true,
+      format => panic!("couldn't interpret LOG_FORMAT: unknown format {:?}", format),
+    };
+    LogConfig {
+      level: get_env_or("LOG_LEVEL", default.level),
+      json,
+      message_bodies: get_env_or("LOG_MESSAGE_BODIES", default.message_bodies),
+    }
+  }
+}
+
+/// Installs the global subscriber, does nothing when the embedding app already has one.
+pub fn init(config: &LogConfig) {
+  LOG_MESSAGE_BODIES.store(config.message_bodies, Ordering::Relaxed);
+  let filter = EnvFilter::new(&config.level);
+  let builder = tracing_subscriber::fmt().with_env_filter(filter);
+  let _ = if config.json {
+    builder.json().try_init()
+  } else {
+    builder.try_init()
+  };
+}
+
+/// Wraps a chat message for logging, it is redacted unless message bodies are enabled.
+pub fn body(message: &str) -> Body<'_> {
+  Body(message)
+}
+
+pub struct Body<'a>(&'a str);
+
+impl fmt::Display for Body<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if LOG_MESSAGE_BODIES.load(Ordering::Relaxed) {
+      write!(f, "{:?}", self.0)
+    } else {
+      write!(f, "<redacted {} bytes>", self.0.len())
+    }
+  }
+}
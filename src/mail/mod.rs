@@ -0,0 +1,85 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::info;
+
+pub mod smtp;
+
+/// A plain text mail to a single recipient.
+#[derive(Clone, Debug)]
+pub struct Mail {
+  pub to: String,
+  pub subject: String,
+  pub body: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "couldn't send mail: {}", self.0)
+  }
+}
+
+impl std::error::Error for MailError {}
+
+/// Delivers the mails of the server, such as password reset tokens.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+  async fn send(&self, mail: Mail) -> Result<(), MailError>;
+}
+
+/// Writes mails to the log instead of sending them, for local runs. The bodies carry
+/// secrets such as reset tokens, so this is not meant for production.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+  async fn send(&self, mail: Mail) -> Result<(), MailError> {
+    info!(to = %mail.to, subject = %mail.subject, body = %mail.body, "mail not sent, logged only");
+    Ok(())
+  }
+}
+
+/// Stores every mail as a file in a directory, one `.eml` file per mail.
+pub struct FileMailer {
+  dir: PathBuf,
+  sent: AtomicU64,
+}
+
+impl FileMailer {
+  pub fn new(dir: impl Into<PathBuf>) -> FileMailer {
+    FileMailer {
+      dir: dir.into(),
+      sent: AtomicU64::new(0),
+    }
+  }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+  async fn send(&self, mail: Mail) -> Result<(), MailError> {
+    let now = Utc::now();
+    let path = self.dir.join(format!(
+      "{}-{}.eml",
+      now.timestamp_micros(),
+      self.sent.fetch_add(1, Ordering::Relaxed)
+    ));
+    let contents = format!(
+      "To: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n{}",
+      mail.to,
+      mail.subject,
+      now.to_rfc2822(),
+      mail.body
+    );
+    tokio::fs::create_dir_all(&self.dir)
+      .await
+      .map_err(|e| MailError(e.to_string()))?;
+    tokio::fs::write(path, contents)
+      .await
+      .map_err(|e| MailError(e.to_string()))
+  }
+}
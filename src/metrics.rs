@@ -0,0 +1,190 @@
+use crate::ConnectionPool;
+use prometheus::{
+  Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+  Registry, TextEncoder,
+};
+
+use crate::ws::lobby::Lobby;
+
+/// Prometheus metrics of the chat server, every instance has its own registry.
+pub struct Metrics {
+  registry: Registry,
+  pub messages_received: IntCounter,
+  pub messages_broadcast: IntCounter,
+  pub messages_persisted: IntCounter,
+  pub persistence_failures: IntCounter,
+  // labelled by the receiver that fell behind: `socket` or `subscription`
+  pub broadcast_lag_events: IntCounterVec,
+  // labelled by `reason`
+  pub auth_failures: IntCounterVec,
+  // labelled by the `limit` that was hit: `ip`, `user` or `socket`
+  pub rate_limited: IntCounterVec,
+  // labelled by `method`, `route` and `status`
+  pub http_request_duration: HistogramVec,
+  // sampled from the lobby and the pool on every scrape
+  room_sockets: IntGaugeVec,
+  sockets: IntGauge,
+  active_rooms: IntGauge,
+  pool_connections: IntGauge,
+  pool_idle_connections: IntGauge,
+}
+
+impl Default for Metrics {
+  fn default() -> Self {
+    Metrics::new()
+  }
+}
+
+impl Metrics {
+  pub fn new() -> Metrics {
+    let registry = Registry::new();
+    let metrics = Metrics {
+      messages_received: IntCounter::new(
+        "chat_messages_received_total",
+        "Chat messages received from sockets",
+      )
+      .unwrap(),
+      messages_broadcast: IntCounter::new(
+        "chat_messages_broadcast_total",
+        "Room events published to the room bus",
+      )
+      .unwrap(),
+      messages_persisted: IntCounter::new(
+        "chat_messages_persisted_total",
+        "Chat messages saved in the database",
+      )
+      .unwrap(),
+      persistence_failures: IntCounter::new(
+        "chat_persistence_failures_total",
+        "Chat messages that could not be saved in the database",
+      )
+      .unwrap(),
+      broadcast_lag_events: IntCounterVec::new(
+        Opts::new(
+          "chat_broadcast_lag_events_total",
+          "Times a room receiver fell behind and skipped events",
+        ),
+        &["receiver"],
+      )
+      .unwrap(),
+      auth_failures: IntCounterVec::new(
+        Opts::new(
+          "chat_auth_failures_total",
+          "Rejected authentication attempts",
+        ),
+        &["reason"],
+      )
+      .unwrap(),
+      rate_limited: IntCounterVec::new(
+        Opts::new(
+          "chat_rate_limited_total",
+          "Requests and socket messages rejected by a rate limit",
+        ),
+        &["limit"],
+      )
+      .unwrap(),
+      http_request_duration: HistogramVec::new(
+        HistogramOpts::new(
+          "http_request_duration_seconds",
+          "HTTP request latency by route",
+        ),
+        &["method", "route", "status"],
+      )
+      .unwrap(),
+      room_sockets: IntGaugeVec::new(
+        Opts::new(
+          "chat_room_sockets",
+          "Live sockets per room on this instance",
+        ),
+        &["room_id"],
+      )
+      .unwrap(),
+      sockets: IntGauge::new("chat_sockets", "Live sockets on this instance").unwrap(),
+      active_rooms: IntGauge::new(
+        "chat_active_rooms",
+        "Rooms with at least one live socket on this instance",
+      )
+      .unwrap(),
+      pool_connections: IntGauge::new(
+        "db_pool_connections",
+        "Connections currently held by the database pool",
+      )
+      .unwrap(),
+      pool_idle_connections: IntGauge::new(
+        "db_pool_idle_connections",
+        "Idle connections of the database pool",
+      )
+      .unwrap(),
+      registry,
+    };
+    metrics.register();
+    metrics
+  }
+
+  fn register(&self) {
+    let collectors: [Box<dyn prometheus::core::Collector>; 13] = [
+      Box::new(self.messages_received.clone()),
+      Box::new(self.messages_broadcast.clone()),
+      Box::new(self.messages_persisted.clone()),
+      Box::new(self.persistence_failures.clone()),
+      Box::new(self.broadcast_lag_events.clone()),
+      Box::new(self.auth_failures.clone()),
+      Box::new(self.rate_limited.clone()),
+      Box::new(self.http_request_duration.clone()),
+      Box::new(self.room_sockets.clone()),
+      Box::new(self.sockets.clone()),
+      Box::new(self.active_rooms.clone()),
+      Box::new(self.pool_connections.clone()),
+      Box::new(self.pool_idle_connections.clone()),
+    ];
+    for collector in collectors {
+      self.registry.register(collector).unwrap();
+    }
+  }
+
+  pub fn auth_failure(&self, reason: &str) {
+    self.auth_failures.with_label_values(&[reason]).inc();
+  }
+
+  pub fn rate_limited(&self, limit: &str) {
+    self.rate_limited.with_label_values(&[limit]).inc();
+  }
+
+  pub fn broadcast_lag(&self, receiver: &str) {
+    self
+      .broadcast_lag_events
+      .with_label_values(&[receiver])
+      .inc();
+  }
+
+  /// Samples the lobby and the pool, then renders every metric in the text format.
+  pub fn render(&self, lobby: &Lobby, pool: Option<&ConnectionPool>) -> String {
+    let stats = lobby.stats();
+    self.active_rooms.set(stats.active_rooms as i64);
+    self.sockets.set(stats.sockets as i64);
+    self.room_sockets.reset();
+    {
+      let rooms = lobby.rooms.lock().unwrap();
+      for (room_id, room) in rooms.iter() {
+        let sockets: usize = room.clients.values().sum();
+        self
+          .room_sockets
+          .with_label_values(&[&room_id.to_string()])
+          .set(sockets as i64);
+      }
+    }
+    if let Some(pool) = pool {
+      let state = pool.state();
+      self.pool_connections.set(state.connections as i64);
+      self
+        .pool_idle_connections
+        .set(state.idle_connections as i64);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+      .encode(&self.registry.gather(), &mut buffer)
+      .unwrap();
+    String::from_utf8(buffer).unwrap()
+  }
+}
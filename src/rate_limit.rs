@@ -0,0 +1,216 @@
+use crate::errors::{ErrorCode, ServiceError};
+use crate::helpers::get_env_or;
+use crate::routes::SharedState;
+use axum::{
+  extract::{ConnectInfo, State},
+  http::{header::RETRY_AFTER, HeaderValue, Request},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket holding up to `burst` tokens, refilled by `per_minute` tokens a minute.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+  pub burst: u32,
+  pub per_minute: u32,
+}
+
+impl RateLimit {
+  // Reads `<prefix>_BURST` and `<prefix>_PER_MINUTE`, a rate of 0 disables the limit.
+  fn from_env(prefix: &str, default: Option<RateLimit>) -> Option<RateLimit> {
+    let per_minute = get_env_or(
+      &format!("{}_PER_MINUTE", prefix),
+      default.map_or(0, |limit| limit.per_minute),
+    );
+    let burst = get_env_or(
+      &format!("{}_BURST", prefix),
+      default.map_or(per_minute, |limit| limit.burst),
+    );
+    (per_minute > 0).then_some(RateLimit { burst, per_minute })
+  }
+
+  // time it takes to earn `tokens` back
+  fn refill_time(&self, tokens: f64) -> Duration {
+    Duration::from_secs_f64(tokens * 60.0 / self.per_minute as f64)
+  }
+}
+
+/// Limits of the HTTP routes and the sockets, `None` disables a limit.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitConfig {
+  // Routes that work without a login, keyed by the IP address of the client.
+  pub ip: Option<RateLimit>,
+  // Routes behind the login, keyed by the user id.
+  pub user: Option<RateLimit>,
+  // Chat messages sent on the sockets, keyed by the room member.
+  pub socket_messages: Option<RateLimit>,
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    RateLimitConfig {
+      ip: Some(RateLimit {
+        burst: 30,
+        per_minute: 30,
+      }),
+      user: Some(RateLimit {
+        burst: 120,
+        per_minute: 600,
+      }),
+      socket_messages: Some(RateLimit {
+        burst: 20,
+        per_minute: 120,
+      }),
+    }
+  }
+}
+
+impl RateLimitConfig {
+  /// Reads `RATE_LIMIT_IP_*`, `RATE_LIMIT_USER_*` and `WS_MESSAGE_RATE_*`, each a
+  /// `_BURST` and a `_PER_MINUTE`.
+  pub fn from_env() -> Self {
+    let default = RateLimitConfig::default();
+    RateLimitConfig {
+      ip: RateLimit::from_env("RATE_LIMIT_IP", default.ip),
+      user: RateLimit::from_env("RATE_LIMIT_USER", default.user),
+      socket_messages: RateLimit::from_env("WS_MESSAGE_RATE", default.socket_messages),
+    }
+  }
+}
+
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+struct Buckets<K> {
+  buckets: HashMap<K, Bucket>,
+  swept_at: Instant,
+}
+
+/// Token buckets of a single limit, one per key.
+pub struct RateLimiter<K> {
+  limit: RateLimit,
+  buckets: Mutex<Buckets<K>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+  pub fn new(limit: RateLimit) -> RateLimiter<K> {
+    RateLimiter {
+      limit,
+      buckets: Mutex::new(Buckets {
+        buckets: HashMap::new(),
+        swept_at: Instant::now(),
+      }),
+    }
+  }
+
+  /// Takes a token of `key`, or returns how long to wait for the next one.
+  pub fn check(&self, key: K) -> Result<(), Duration> {
+    let now = Instant::now();
+    let burst = self.limit.burst as f64;
+    let full_after = self.limit.refill_time(burst);
+    let mut buckets = self.buckets.lock().unwrap();
+    // a bucket that had time to fill up again is the same as a missing one
+    if now.duration_since(buckets.swept_at) >= full_after {
+      buckets
+        .buckets
+        .retain(|_, bucket| now.duration_since(bucket.updated_at) < full_after);
+      buckets.swept_at = now;
+    }
+    let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+      tokens: burst,
+      updated_at: now,
+    });
+    let earned =
+      now.duration_since(bucket.updated_at).as_secs_f64() * self.limit.per_minute as f64 / 60.0;
+    bucket.tokens = (bucket.tokens + earned).min(burst);
+    bucket.updated_at = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(self.limit.refill_time(1.0 - bucket.tokens))
+    }
+  }
+}
+
+/// The limiters of the HTTP routes.
+pub struct RateLimits {
+  ip: Option<RateLimiter<IpAddr>>,
+  user: Option<RateLimiter<i64>>,
+}
+
+impl RateLimits {
+  pub fn new(config: &RateLimitConfig) -> RateLimits {
+    RateLimits {
+      ip: config.ip.map(RateLimiter::new),
+      user: config.user.map(RateLimiter::new),
+    }
+  }
+}
+
+impl Default for RateLimits {
+  fn default() -> Self {
+    RateLimits::new(&RateLimitConfig::default())
+  }
+}
+
+/// Limits the routes that work without a login by the address of the client. The
+/// address is only known when the server is run with connect info, as `serve` does.
+pub async fn limit_by_ip<T>(
+  State(state): State<SharedState>,
+  request: Request<T>,
+  next: Next<T>,
+) -> Response {
+  let ip = request
+    .extensions()
+    .get::<ConnectInfo<SocketAddr>>()
+    .map(|ConnectInfo(addr)| addr.ip());
+  if let (Some(limiter), Some(ip)) = (&state.rate_limits.ip, ip) {
+    if let Err(retry_after) = limiter.check(ip) {
+      state.lobby.metrics.rate_limited("ip");
+      return too_many_requests(retry_after);
+    }
+  }
+  next.run(request).await
+}
+
+/// Limits the routes behind the login by user, has to run after `guard`.
+pub async fn limit_by_user<T>(
+  State(state): State<SharedState>,
+  request: Request<T>,
+  next: Next<T>,
+) -> Response {
+  let user_id = request.extensions().get::<i64>().copied();
+  if let (Some(limiter), Some(user_id)) = (&state.rate_limits.user, user_id) {
+    if let Err(retry_after) = limiter.check(user_id) {
+      state.lobby.metrics.rate_limited("user");
+      return too_many_requests(retry_after);
+    }
+  }
+  next.run(request).await
+}
+
+/// Whole seconds to wait, never 0 so clients do not retry right away.
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+  retry_after.as_secs_f64().ceil().max(1.0) as u64
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+  let secs = retry_after_secs(retry_after);
+  let mut response = ServiceError::new(
+    ErrorCode::RateLimited,
+    format!("Too many requests, retry in {} seconds", secs),
+  )
+  .into_response();
+  response
+    .headers_mut()
+    .insert(RETRY_AFTER, HeaderValue::from(secs));
+  response
+}
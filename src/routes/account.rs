@@ -0,0 +1,173 @@
+use super::models::DeleteAccountRequest;
+use super::session::revoke_user_sessions;
+use super::SharedState;
+use crate::auth::new_token;
+use crate::db::DbError;
+use crate::errors::{db_error_to_service_error, ErrorCode, ServiceError};
+use crate::ws::{ClientWsMessage, ClientWsMessageType};
+use axum::http::header::CONTENT_DISPOSITION;
+use axum::response::IntoResponse;
+use axum::{extract::Extension, extract::State, Json};
+use chrono::Utc;
+use ring::constant_time::verify_slices_are_equal;
+use std::collections::HashMap;
+use tracing::info;
+
+/// Returns everything stored about the user as a JSON file: the profile, every room
+/// membership and every message the user sent.
+pub async fn export_account(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+) -> Result<impl IntoResponse, ServiceError> {
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let two_factor_enabled = match state.repos.two_factor.get_user_totp(user_id).await {
+    Ok(totp) => totp.confirmed_at.is_some(),
+    Err(DbError::NotFound) => false,
+    Err(e) => return Err(db_error_to_service_error(e)),
+  };
+  let members = state
+    .repos
+    .members
+    .list_user_memberships(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let messages = state
+    .repos
+    .messages
+    .list_user_messages(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+
+  let mut rooms = HashMap::new();
+  for member in &members {
+    if !rooms.contains_key(&member.room_id) {
+      let room = state
+        .repos
+        .rooms
+        .get_room_by_id(member.room_id)
+        .await
+        .map_err(db_error_to_service_error)?;
+      rooms.insert(room.id, room);
+    }
+  }
+  let memberships: Vec<_> = members
+    .iter()
+    .map(|member| {
+      let room = &rooms[&member.room_id];
+      serde_json::json!({
+        "roomId": room.id,
+        "roomName": room.name,
+        "direct": room.is_direct(),
+        "owner": !room.is_direct() && room.created_by == user_id,
+        "joinedAt": member.created_at,
+        "lastJoinedAt": member.last_joined_at,
+        "leftAt": member.deleted_at,
+      })
+    })
+    .collect();
+  let messages: Vec<_> = messages
+    .iter()
+    .map(|message| {
+      serde_json::json!({
+        "id": message.id,
+        "roomId": message.room_id,
+        "message": message.msg,
+        "sentAt": message.created_at,
+      })
+    })
+    .collect();
+
+  let export = serde_json::json!({
+    "exportedAt": Utc::now(),
+    "profile": {
+      "id": user.id,
+      "name": user.name,
+      "displayName": user.display_name,
+      "bio": user.bio,
+      "avatarUrl": user.avatar_url,
+      "email": user.email,
+      "status": user.status,
+      "statusText": user.status_text,
+      "twoFactorEnabled": two_factor_enabled,
+      "createdAt": user.created_at,
+      "lastSeenAt": user.last_seen_at,
+    },
+    "memberships": memberships,
+    "messages": messages,
+  });
+  let disposition = format!("attachment; filename=\"chat-export-{}.json\"", user.id);
+  Ok(([(CONTENT_DISPOSITION, disposition)], Json(export)))
+}
+
+/// Deletes the account of the user. Every session is revoked first, then in one go the
+/// user leaves every room and owned rooms go to their longest standing member or are
+/// deleted when nobody is left. The messages stay, sent by a "Deleted user".
+pub async fn delete_account(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Json(request): Json<DeleteAccountRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  if verify_slices_are_equal(user.password.as_bytes(), request.password.as_bytes()).is_err() {
+    state.lobby.metrics.auth_failure("wrong_password");
+    return Err(ServiceError::new(
+      ErrorCode::WrongPassword,
+      "Password is wrong",
+    ));
+  }
+
+  // the account stays live should the deletion fail, but nobody is logged in to it anymore
+  let revoked = revoke_user_sessions(&state, user_id, None).await?;
+  // random credentials nobody knows, the name stays unique
+  let (name, _) = new_token();
+  let (password, _) = new_token();
+  let deletion = state
+    .repos
+    .users
+    .delete_account(user_id, &format!("deleted-{}", name), &password)
+    .await
+    .map_err(db_error_to_service_error)?;
+
+  for member in &deletion.left_memberships {
+    state.lobby.publish(
+      member.room_id,
+      &ClientWsMessage {
+        member_id: member.id,
+        user_id,
+        member_name: user.display_name().to_owned(),
+        message_type: ClientWsMessageType::Leave,
+        message: "account was deleted".to_owned(),
+        db_skip_write: true,
+      },
+    );
+  }
+  let transferred_rooms: Vec<serde_json::Value> = deletion
+    .transferred_rooms
+    .iter()
+    .map(|(room_id, new_owner_id)| {
+      info!(room_id, new_owner_id, "room ownership transferred");
+      serde_json::json!({
+        "roomId": room_id,
+        "newOwnerId": new_owner_id,
+      })
+    })
+    .collect();
+  info!(user_id, "account deleted");
+
+  Ok(Json(serde_json::json!({
+    "id": user_id,
+    "transferredRooms": transferred_rooms,
+    "deletedRooms": deletion.deleted_rooms,
+    "revokedSessions": revoked,
+  })))
+}
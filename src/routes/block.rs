@@ -0,0 +1,102 @@
+use super::models::BlockUserRequest;
+use super::SharedState;
+use crate::errors::{db_error_to_service_error, not_found_as, ErrorCode, ServiceError};
+use axum::{extract::Extension, extract::Path, extract::State, Json};
+
+pub async fn list_blocks(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let blocks = state
+    .repos
+    .blocks
+    .list_blocked_users(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let mut result = Vec::with_capacity(blocks.len());
+  for block in blocks {
+    let user = state
+      .repos
+      .users
+      .get_user_by_id(block.blocked_id)
+      .await
+      .map_err(db_error_to_service_error)?;
+    result.push(serde_json::json!({
+      "userId": user.id,
+      "name": user.name,
+      "displayName": user.display_name(),
+      "blockedAt": block.created_at,
+    }));
+  }
+  Ok(Json(serde_json::json!({ "blocks": result })))
+}
+
+/// Blocks a user, blocking someone already blocked keeps the first block.
+pub async fn block_user(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Json(block_user_request): Json<BlockUserRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let blocked_id = block_user_request.user_id;
+  if blocked_id == user_id {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      "Cannot block yourself",
+    ));
+  }
+  let user = state
+    .repos
+    .users
+    .get_live_user_by_id(blocked_id)
+    .await
+    .map_err(not_found_as(ErrorCode::UserNotFound, "User does not exist"))?;
+  let block = state
+    .repos
+    .blocks
+    .block_user(user_id, blocked_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  refresh_socket_blocks(&state, user_id).await?;
+  Ok(Json(serde_json::json!({
+    "userId": user.id,
+    "name": user.name,
+    "displayName": user.display_name(),
+    "blockedAt": block.created_at,
+  })))
+}
+
+pub async fn unblock_user(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Path(blocked_id): Path<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let block = state
+    .repos
+    .blocks
+    .unblock_user(user_id, blocked_id)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::BlockNotFound,
+      "User is not blocked",
+    ))?;
+  refresh_socket_blocks(&state, user_id).await?;
+  Ok(Json(serde_json::json!({
+    "userId": block.blocked_id,
+    "blockedAt": block.created_at,
+  })))
+}
+
+/// Lets the live sockets of the user pick up the changed blocks.
+async fn refresh_socket_blocks(state: &SharedState, user_id: i64) -> Result<(), ServiceError> {
+  let blocks = state
+    .repos
+    .blocks
+    .list_blocked_users(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  state.lobby.update_blocked_users(
+    user_id,
+    blocks.iter().map(|block| block.blocked_id).collect(),
+  );
+  Ok(())
+}
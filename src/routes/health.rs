@@ -0,0 +1,52 @@
+use super::SharedState;
+use crate::db::pending_migrations;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use std::time::Duration;
+
+// Readiness fails instead of waiting for the pool's own connection timeout.
+const DATABASE_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The process is up and serving requests.
+pub async fn live() -> Json<serde_json::Value> {
+  Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// The instance can take traffic: the database is reachable and migrated, and the
+/// server is not shutting down. Answers 503 otherwise.
+pub async fn ready(State(state): State<SharedState>) -> (StatusCode, Json<serde_json::Value>) {
+  let database = match &state.repos.pool {
+    Some(pool) => {
+      match tokio::time::timeout(DATABASE_CHECK_TIMEOUT, pending_migrations(pool)).await {
+        Ok(Ok(pending)) if pending.is_empty() => Ok("ok".to_owned()),
+        Ok(Ok(pending)) => Err(format!("pending migrations: {}", pending.join(", "))),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_owned()),
+      }
+    }
+    None => Ok("inMemory".to_owned()),
+  };
+  let shutting_down = state.lobby.is_shutting_down();
+  let is_ready = database.is_ok() && !shutting_down;
+  let stats = state.lobby.stats();
+
+  let code = if is_ready {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  (
+    code,
+    Json(serde_json::json!({
+      "status": if is_ready { "ready" } else { "notReady" },
+      "shuttingDown": shutting_down,
+      "database": database.unwrap_or_else(|e| e),
+      "lobby": {
+        "activeRooms": stats.active_rooms,
+        "sockets": stats.sockets,
+        "onlineUsers": stats.online_users,
+      },
+    })),
+  )
+}
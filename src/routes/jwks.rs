@@ -0,0 +1,10 @@
+use super::SharedState;
+use axum::extract::State;
+use axum::Json;
+use jsonwebtoken::jwk::JwkSet;
+
+/// Publishes the public keys access tokens are verified with, so other services can
+/// check chat tokens without the signing key. Empty while tokens are signed with HS256.
+pub async fn jwks(State(state): State<SharedState>) -> Json<JwkSet> {
+  Json(state.auth.keys.jwks())
+}
@@ -0,0 +1,38 @@
+use super::SharedState;
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::time::Instant;
+
+/// Renders the metrics of this instance in the Prometheus text format.
+pub async fn metrics(State(state): State<SharedState>) -> impl IntoResponse {
+  let body = state
+    .lobby
+    .metrics
+    .render(&state.lobby, state.repos.pool.as_ref());
+  ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Records the latency of every request by its route pattern, so that path parameters
+/// don't end up as separate series.
+pub async fn track_latency<T>(
+  State(state): State<SharedState>,
+  request: Request<T>,
+  next: Next<T>,
+) -> Response {
+  let start = Instant::now();
+  let method = request.method().to_string();
+  let route = match request.extensions().get::<MatchedPath>() {
+    Some(path) => path.as_str().to_owned(),
+    None => request.uri().path().to_owned(),
+  };
+  let response = next.run(request).await;
+  state
+    .lobby
+    .metrics
+    .http_request_duration
+    .with_label_values(&[&method, &route, response.status().as_str()])
+    .observe(start.elapsed().as_secs_f64());
+  response
+}
@@ -1,12 +1,28 @@
+use crate::auth::AuthConfig;
+use crate::db::Repositories;
+use crate::mail::Mailer;
+use crate::rate_limit::RateLimits;
 use crate::ws::lobby::Lobby;
-use crate::ConnectionPool;
 use std::sync::Arc;
 
+pub mod account;
+pub mod block;
+pub mod health;
+pub mod jwks;
+pub mod metrics;
 pub mod models;
+pub mod password;
 pub mod room;
+pub mod session;
+pub mod two_factor;
 pub mod user;
 
+/// State shared by every route.
+#[derive(Clone)]
 pub struct SharedState {
-  pub pool: ConnectionPool,
+  pub repos: Repositories,
   pub lobby: Arc<Lobby>,
+  pub auth: Arc<AuthConfig>,
+  pub mailer: Arc<dyn Mailer>,
+  pub rate_limits: Arc<RateLimits>,
 }
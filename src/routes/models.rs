@@ -1,3 +1,4 @@
+use crate::db::user::UserStatus;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -5,6 +6,8 @@ use serde::{Deserialize, Serialize};
 pub struct NewUserRequest {
   pub name: String,
   pub password: String,
+  // where password reset tokens are sent
+  pub email: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,6 +17,51 @@ pub struct LoginRequest {
   pub password: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct RefreshRequest {
+  pub refresh_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct TotpLoginRequest {
+  pub challenge_token: String,
+  pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct TotpCodeRequest {
+  pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct ChangePasswordRequest {
+  pub current_password: String,
+  pub new_password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct DeleteAccountRequest {
+  pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct PasswordResetRequest {
+  pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct ResetPasswordRequest {
+  pub token: String,
+  pub new_password: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct CreateRoomRequest {
@@ -22,6 +70,51 @@ pub struct CreateRoomRequest {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
+pub struct InviteUserRequest {
+  pub user_id: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct ListMessagesQuery {
+  // id of the oldest message seen so far, the page ends right before it
+  pub before: Option<i64>,
+  pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
 pub struct RemoveUserRequest {
   pub user_name: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct UpdateStatusRequest {
+  pub status: UserStatus,
+  pub status_text: Option<String>,
+}
+
+/// Fields left out stay as they are, an empty string clears an optional one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct UpdateProfileRequest {
+  pub name: Option<String>,
+  pub display_name: Option<String>,
+  pub bio: Option<String>,
+  pub avatar_url: Option<String>,
+  pub hidden_from_search: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct BlockUserRequest {
+  pub user_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct SearchUsersQuery {
+  pub q: String,
+  pub limit: Option<i64>,
+}
@@ -0,0 +1,150 @@
+use super::models::{ChangePasswordRequest, PasswordResetRequest, ResetPasswordRequest};
+use super::session::revoke_user_sessions;
+use super::user::normalize_email;
+use super::SharedState;
+use crate::auth::{hash_token, new_token, SessionId};
+use crate::db::user::PasswordReset;
+use crate::db::DbError;
+use crate::errors::{db_error_to_service_error, not_found_as, ErrorCode, ServiceError};
+use crate::mail::Mail;
+use axum::{extract::Extension, extract::State, http::StatusCode, Json};
+use chrono::Utc;
+use ring::constant_time::verify_slices_are_equal;
+use tracing::warn;
+
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// Changes the password of the user. Every other session is revoked and its sockets
+/// closed, the current one stays signed in.
+pub async fn change_password(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Extension(SessionId(session_id)): Extension<SessionId>,
+  Json(request): Json<ChangePasswordRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  if verify_slices_are_equal(
+    user.password.as_bytes(),
+    request.current_password.as_bytes(),
+  )
+  .is_err()
+  {
+    state.lobby.metrics.auth_failure("wrong_password");
+    return Err(ServiceError::new(
+      ErrorCode::WrongPassword,
+      "Current password is wrong",
+    ));
+  }
+  check_new_password(&request.new_password)?;
+
+  state
+    .repos
+    .users
+    .update_password(user_id, &request.new_password)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let revoked = revoke_user_sessions(&state, user_id, Some(session_id)).await?;
+  Ok(Json(serde_json::json!({ "revokedSessions": revoked })))
+}
+
+/// Mails a reset token to the address when it belongs to a user. The response is the
+/// same either way, so it does not tell which addresses have an account.
+pub async fn request_password_reset(
+  State(state): State<SharedState>,
+  Json(request): Json<PasswordResetRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ServiceError> {
+  let email = normalize_email(&request.email)?;
+  match state.repos.users.get_user_by_email(&email).await {
+    Ok(user) => {
+      let (token, token_hash) = new_token();
+      let ttl = state.auth.password_reset_ttl;
+      state
+        .repos
+        .users
+        .create_password_reset(&PasswordReset {
+          token_hash,
+          user_id: user.id,
+          expires_at: Utc::now() + chrono::Duration::from_std(ttl).unwrap(),
+        })
+        .await
+        .map_err(db_error_to_service_error)?;
+      let mail = Mail {
+        to: email,
+        subject: "Reset your password".to_owned(),
+        body: format!(
+          "Hi {},\r\n\r\nuse this token to choose a new password within {} minutes:\r\n\r\n{}\r\n\r\nIf you did not ask for it, ignore this mail and your password stays the same.\r\n",
+          user.name,
+          ttl.as_secs() / 60,
+          token
+        ),
+      };
+      // sending in the background keeps the response as fast as for unknown addresses
+      let mailer = state.mailer.clone();
+      tokio::spawn(async move {
+        if let Err(e) = mailer.send(mail).await {
+          warn!(user_id = user.id, error = %e, "error sending password reset mail");
+        }
+      });
+    }
+    Err(DbError::NotFound) => {}
+    Err(e) => return Err(db_error_to_service_error(e)),
+  }
+  Ok((
+    StatusCode::ACCEPTED,
+    Json(serde_json::json!({
+      "message": "If the address belongs to an account, a reset token was sent to it",
+    })),
+  ))
+}
+
+/// Sets a new password with a reset token, which signs the user out everywhere.
+pub async fn reset_password(
+  State(state): State<SharedState>,
+  Json(request): Json<ResetPasswordRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  check_new_password(&request.new_password)?;
+  let invalid_token = "Reset token is unknown, expired or was already used";
+  let reset = state
+    .repos
+    .users
+    .take_password_reset(&hash_token(&request.token))
+    .await
+    .map_err(not_found_as(ErrorCode::InvalidResetToken, invalid_token))?;
+  if reset.expires_at <= Utc::now() {
+    return Err(ServiceError::new(
+      ErrorCode::InvalidResetToken,
+      invalid_token,
+    ));
+  }
+
+  let user = state
+    .repos
+    .users
+    .update_password(reset.user_id, &request.new_password)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let revoked = revoke_user_sessions(&state, user.id, None).await?;
+  Ok(Json(serde_json::json!({
+    "id": user.id,
+    "name": user.name,
+    "revokedSessions": revoked,
+  })))
+}
+
+fn check_new_password(password: &str) -> Result<(), ServiceError> {
+  if password.chars().count() < MIN_PASSWORD_LEN {
+    return Err(ServiceError::new(
+      ErrorCode::WeakPassword,
+      format!(
+        "Password must be at least {} characters long",
+        MIN_PASSWORD_LEN
+      ),
+    ));
+  }
+  Ok(())
+}
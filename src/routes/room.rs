@@ -1,29 +1,49 @@
-use super::models::{CreateRoomRequest, RemoveUserRequest};
-
-use crate::db::member::{count_active_members, create_new_member, delete_member, get_member};
-use crate::db::room::{create_new_room, delete_room, get_room_by_id};
-use crate::db::user::{get_user_by_id, get_user_by_name};
-
-use crate::errors::ServiceError;
-use crate::errors::{db_error_to_service_error, internal_error_to_service_error};
-use crate::ws::lobby::{upgrade_to_websocket, Lobby};
-use crate::ws::{ClientWsMessage, ClientWsMessageType};
-use crate::ConnectionPool;
-use axum::http::StatusCode;
+use super::models::{CreateRoomRequest, InviteUserRequest, ListMessagesQuery, RemoveUserRequest};
+use super::SharedState;
+use crate::auth::{SessionId, WS_PROTOCOL};
+
+use crate::db::room::{Room, DIRECT_ROOM_NAME_PREFIX};
+use crate::errors::{db_error_to_service_error, not_found_as, ErrorCode, ServiceError};
+use crate::ws::lobby::{resolve_room_member, upgrade_to_websocket, SocketUser};
+use crate::ws::multiplex::upgrade_to_multiplexed_websocket;
+use crate::ws::{next_session_id, ClientWsMessage, ClientWsMessageType};
 use axum::response::IntoResponse;
-use axum::{extract::Extension, extract::Path, extract::State, extract::WebSocketUpgrade, Json};
-use std::sync::Arc;
+use axum::{
+  extract::Extension, extract::Path, extract::Query, extract::State, extract::WebSocketUpgrade,
+  Json,
+};
+use tracing::{info, info_span, Instrument};
+
+const DEFAULT_HISTORY_LIMIT: i64 = 50;
+const MAX_HISTORY_LIMIT: i64 = 100;
 
 pub async fn create_room(
-  State(pool): State<ConnectionPool>,
+  State(state): State<SharedState>,
   Extension(user_id): Extension<i64>,
   Json(create_room_request): Json<CreateRoomRequest>,
 ) -> Result<Json<serde_json::Value>, ServiceError> {
-  let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-  let room = create_new_room(&mut conn, create_room_request.name, user_id)
+  if create_room_request
+    .name
+    .starts_with(DIRECT_ROOM_NAME_PREFIX)
+  {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      format!(
+        "Room names starting with {} are reserved for direct messages",
+        DIRECT_ROOM_NAME_PREFIX
+      ),
+    ));
+  }
+  let room = state
+    .repos
+    .rooms
+    .create_new_room(create_room_request.name, user_id)
     .await
     .map_err(db_error_to_service_error)?;
-  let member = create_new_member(&mut conn, room.id, user_id)
+  let member = state
+    .repos
+    .members
+    .create_new_member(room.id, user_id)
     .await
     .map_err(db_error_to_service_error)?;
 
@@ -36,98 +56,339 @@ pub async fn create_room(
   })))
 }
 
+pub async fn list_rooms(
+  State(state): State<SharedState>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let rooms = state
+    .repos
+    .rooms
+    .list_rooms()
+    .await
+    .map_err(db_error_to_service_error)?;
+  let rooms: Vec<serde_json::Value> = rooms
+    .into_iter()
+    .map(|room| {
+      serde_json::json!({
+        "roomId": room.id,
+        "roomName": room.name,
+        "createdBy": room.created_by,
+        "createdAt": room.created_at,
+      })
+    })
+    .collect();
+  Ok(Json(serde_json::json!({ "rooms": rooms })))
+}
+
+pub async fn open_direct_room(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Path(other_user_id): Path<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  if other_user_id == user_id {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      "Cannot open a direct message with yourself",
+    ));
+  }
+  let other_user = state
+    .repos
+    .users
+    .get_live_user_by_id(other_user_id)
+    .await
+    .map_err(not_found_as(ErrorCode::UserNotFound, "User does not exist"))?;
+  if state
+    .repos
+    .blocks
+    .is_blocked_between(user_id, other_user_id)
+    .await
+    .map_err(db_error_to_service_error)?
+  {
+    return Err(ServiceError::new(
+      ErrorCode::UserBlocked,
+      "Direct messages between these users are blocked",
+    ));
+  }
+  let room = state
+    .repos
+    .rooms
+    .get_or_create_direct_room(user_id, other_user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  // both users are always members of their direct room
+  for member_user_id in [user_id, other_user_id] {
+    if state
+      .repos
+      .members
+      .get_member(room.id, member_user_id)
+      .await
+      .is_err()
+    {
+      state
+        .repos
+        .members
+        .create_new_member(room.id, member_user_id)
+        .await
+        .map_err(db_error_to_service_error)?;
+    }
+  }
+
+  Ok(Json(serde_json::json!({
+  "roomId": room.id,
+  "roomName": room.name,
+  "createdAt": room.created_at,
+  "otherUserId": other_user.id,
+  "otherUserName": other_user.name,
+  })))
+}
+
 pub async fn join_room(
   ws: WebSocketUpgrade,
-  State(pool): State<ConnectionPool>,
-  Extension(lobby): Extension<Arc<Lobby>>,
+  State(state): State<SharedState>,
   Extension(user_id): Extension<i64>,
+  Extension(SessionId(session_id)): Extension<SessionId>,
   Path(room_id): Path<i64>,
 ) -> Result<impl IntoResponse, ServiceError> {
-  let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-  let room = get_room_by_id(&mut conn, room_id)
+  let (room, member) = resolve_room_member(&state.repos, room_id, user_id).await?;
+  let user = socket_user(&state, user_id).await?;
+
+  let span = info_span!(
+    "ws_session",
+    session_id = next_session_id(),
+    user_id,
+    room_id = room.id,
+    member_id = member.id,
+  );
+  // Create web socket conn
+  Ok(ws.protocols([WS_PROTOCOL]).on_upgrade(move |socket| {
+    upgrade_to_websocket(socket, state.lobby, user_id, session_id, room, member, user)
+      .instrument(span)
+  }))
+}
+
+/// Opens a single socket multiplexing every room the user subscribes to.
+pub async fn connect(
+  ws: WebSocketUpgrade,
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Extension(SessionId(session_id)): Extension<SessionId>,
+) -> Result<impl IntoResponse, ServiceError> {
+  let user = socket_user(&state, user_id).await?;
+
+  let span = info_span!("ws_session", session_id = next_session_id(), user_id);
+  Ok(ws.protocols([WS_PROTOCOL]).on_upgrade(move |socket| {
+    upgrade_to_multiplexed_websocket(socket, state.lobby, user_id, session_id, user)
+      .instrument(span)
+  }))
+}
+
+/// Loads what the sockets of the user need: its display name and whom it blocked.
+async fn socket_user(state: &SharedState, user_id: i64) -> Result<SocketUser, ServiceError> {
+  let user = state
+    .repos
+    .users
+    .get_live_user_by_id(user_id)
+    .await
+    .map_err(not_found_as(ErrorCode::UserNotFound, "User does not exist"))?;
+  let blocks = state
+    .repos
+    .blocks
+    .list_blocked_users(user_id)
     .await
     .map_err(db_error_to_service_error)?;
-  if room.deleted_at != None {
+  Ok(SocketUser {
+    display_name: user.display_name().to_owned(),
+    blocked_user_ids: blocks.iter().map(|block| block.blocked_id).collect(),
+  })
+}
+
+/// Pages backwards through the messages of a room the user is a member of. Messages of users
+/// it blocked are left out, like on its sockets.
+pub async fn list_messages(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Path(room_id): Path<i64>,
+  Query(query): Query<ListMessagesQuery>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+  if !(1..=MAX_HISTORY_LIMIT).contains(&limit) {
     return Err(ServiceError::new(
-      StatusCode::BAD_REQUEST,
-      "Room is deleted",
+      ErrorCode::BadRequest,
+      format!("Limit must be between 1 and {}", MAX_HISTORY_LIMIT),
     ));
   }
-  let member = match get_member(&mut conn, room_id, user_id).await {
-    Ok(member) => member,
-    Err(_) => create_new_member(&mut conn, room.id, user_id)
-      .await
-      .map_err(db_error_to_service_error)?,
-  };
-  let user = get_user_by_id(&mut conn, user_id)
+  let room = member_room(&state, room_id, user_id).await?;
+  let messages = state
+    .repos
+    .messages
+    .list_room_messages(room.id, user_id, query.before, limit)
     .await
     .map_err(db_error_to_service_error)?;
-
-  // Create web socket conn
-  Ok(ws.on_upgrade(move |socket| {
-    upgrade_to_websocket(socket, lobby, user_id, room, member, user.name)
-  }))
+  let messages: Vec<serde_json::Value> = messages
+    .into_iter()
+    .map(|m| {
+      serde_json::json!({
+        "id": m.message.id,
+        "memberId": m.message.sender_id,
+        "userId": m.user_id,
+        "message": m.message.msg,
+        "sentAt": m.message.created_at,
+      })
+    })
+    .collect();
+  Ok(Json(serde_json::json!({ "messages": messages })))
 }
 
-pub async fn leave_room(
-  State(pool): State<ConnectionPool>,
+pub async fn invite_user(
+  State(state): State<SharedState>,
   Extension(user_id): Extension<i64>,
-  Extension(lobby): Extension<Arc<Lobby>>,
   Path(room_id): Path<i64>,
+  Json(invite_user_request): Json<InviteUserRequest>,
 ) -> Result<Json<serde_json::Value>, ServiceError> {
-  let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-  let room = get_room_by_id(&mut conn, room_id)
+  let room = member_room(&state, room_id, user_id).await?;
+  if room.is_direct() {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      "Cannot invite to a direct message room",
+    ));
+  }
+  let invited_user = state
+    .repos
+    .users
+    .get_live_user_by_id(invite_user_request.user_id)
     .await
-    .map_err(db_error_to_service_error);
-  if let Err(_) = room {
+    .map_err(not_found_as(ErrorCode::UserNotFound, "User does not exist"))?;
+  // blocking works both ways here, like for direct messages
+  if state
+    .repos
+    .blocks
+    .is_blocked_between(user_id, invited_user.id)
+    .await
+    .map_err(db_error_to_service_error)?
+  {
     return Err(ServiceError::new(
-      StatusCode::BAD_REQUEST,
-      "Room does not exist",
+      ErrorCode::UserBlocked,
+      "Invites between these users are blocked",
     ));
   }
-  let room = room.unwrap();
+  if state
+    .repos
+    .members
+    .get_member(room.id, invited_user.id)
+    .await
+    .is_ok()
+  {
+    return Err(ServiceError::new(
+      ErrorCode::Conflict,
+      "User is already a member of the room",
+    ));
+  }
+  let member = state
+    .repos
+    .members
+    .create_new_member(room.id, invited_user.id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  info!(
+    user_id,
+    room_id,
+    invited_user_id = invited_user.id,
+    "invited user"
+  );
 
-  let deleted_member_id = delete_member(&mut conn, room.id, user_id)
+  Ok(Json(serde_json::json!({
+  "roomId": room.id,
+  "roomName": room.name,
+  "userId": invited_user.id,
+  "memberId": member.id,
+  "memberCreatedAt": member.created_at,
+  })))
+}
+
+/// Loads a room that is not deleted, for a user who is one of its members. Unlike joining,
+/// this never makes the user a member.
+async fn member_room(
+  state: &SharedState,
+  room_id: i64,
+  user_id: i64,
+) -> Result<Room, ServiceError> {
+  let room = state
+    .repos
+    .rooms
+    .get_room_by_id(room_id)
     .await
-    .map_err(db_error_to_service_error);
-  if let Err(_) = deleted_member_id {
+    .map_err(not_found_as(ErrorCode::RoomNotFound, "Room does not exist"))?;
+  if room.deleted_at.is_some() {
     return Err(ServiceError::new(
-      StatusCode::BAD_REQUEST,
-      "User is not a member of the room",
+      ErrorCode::RoomNotFound,
+      "Room is deleted",
     ));
   }
-  let deleted_member_id = deleted_member_id.unwrap();
+  state
+    .repos
+    .members
+    .get_member(room.id, user_id)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::NotAMember,
+      "User is not a member of the room",
+    ))?;
+  Ok(room)
+}
+
+pub async fn leave_room(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Path(room_id): Path<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let room = state
+    .repos
+    .rooms
+    .get_room_by_id(room_id)
+    .await
+    .map_err(not_found_as(ErrorCode::RoomNotFound, "Room does not exist"))?;
+
+  let deleted_member_id = state
+    .repos
+    .members
+    .delete_member(room.id, user_id)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::NotAMember,
+      "User is not a member of the room",
+    ))?;
 
-  let active_member_count = count_active_members(&mut conn, room.id)
+  let active_member_count = state
+    .repos
+    .members
+    .count_active_members(room.id)
     .await
     .map_err(db_error_to_service_error)?;
   if active_member_count == 0 {
-    delete_room(&mut conn, room_id)
+    state
+      .repos
+      .rooms
+      .delete_room(room_id)
       .await
       .map_err(db_error_to_service_error)?;
   }
-  let user = get_user_by_id(&mut conn, user_id)
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
     .await
     .map_err(db_error_to_service_error)?;
-  let user_name = user.name.clone();
-  {
-    let mut lobby_mutex = lobby.rooms.lock().unwrap();
-    let room = lobby_mutex.get_mut(&room_id).unwrap();
-    room
-      .tx
-      .send(
-        serde_json::to_string(&ClientWsMessage {
-          member_id: deleted_member_id,
-          member_name: user_name,
-          message_type: ClientWsMessageType::Leave,
-          message: "left the room by user's request".to_owned(),
-          db_skip_write: true,
-        })
-        .unwrap(),
-      )
-      .unwrap();
-    println!("Sent leave message to user {:}", user_id);
-  }
+  state.lobby.publish(
+    room_id,
+    &ClientWsMessage {
+      member_id: deleted_member_id,
+      user_id,
+      member_name: user.display_name().to_owned(),
+      message_type: ClientWsMessageType::Leave,
+      message: "left the room by user's request".to_owned(),
+      db_skip_write: true,
+    },
+  );
+  info!(user_id, room_id, "sent leave notice");
 
   Ok(Json(serde_json::json!({
   "roomName": room.name,
@@ -138,80 +399,75 @@ pub async fn leave_room(
 }
 
 pub async fn remove_member(
-  State(pool): State<ConnectionPool>,
-  Extension(lobby): Extension<Arc<Lobby>>,
+  State(state): State<SharedState>,
   Extension(user_id): Extension<i64>,
   Path(room_id): Path<i64>,
   Json(remove_user_request): Json<RemoveUserRequest>,
 ) -> Result<Json<serde_json::Value>, ServiceError> {
-  let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-  let room = get_room_by_id(&mut conn, room_id)
+  let room = state
+    .repos
+    .rooms
+    .get_room_by_id(room_id)
     .await
-    .map_err(db_error_to_service_error);
-  if let Err(_) = room {
+    .map_err(not_found_as(ErrorCode::RoomNotFound, "Room does not exist"))?;
+
+  if user_id != room.created_by {
     return Err(ServiceError::new(
-      StatusCode::BAD_REQUEST,
-      "Room does not exist",
+      ErrorCode::NotRoomOwner,
+      "Only the room owner can remove members",
     ));
   }
-  let room = room.unwrap();
-
-  if user_id != room.created_by {
+  if room.is_direct() {
     return Err(ServiceError::new(
-      StatusCode::UNAUTHORIZED,
-      "Unauthorized".to_string(),
+      ErrorCode::BadRequest,
+      "Cannot remove members from a direct message room",
     ));
   }
   let member_name = remove_user_request.user_name;
-  let user = get_user_by_name(&mut conn, member_name)
+  let user = state
+    .repos
+    .users
+    .get_user_by_name(member_name)
     .await
-    .map_err(db_error_to_service_error)?;
+    .map_err(not_found_as(ErrorCode::UserNotFound, "User does not exist"))?;
 
-  let deleted_member_id = delete_member(&mut conn, room.id, user.id)
+  let deleted_member_id = state
+    .repos
+    .members
+    .delete_member(room.id, user.id)
     .await
-    .map_err(db_error_to_service_error);
-  if let Err(_) = deleted_member_id {
-    return Err(ServiceError::new(
-      StatusCode::BAD_REQUEST,
+    .map_err(not_found_as(
+      ErrorCode::MemberNotFound,
       "User is not a member of the room",
-    ));
-  }
-  let deleted_member_id = deleted_member_id.unwrap();
+    ))?;
 
-  let active_member_count = count_active_members(&mut conn, room.id)
+  let active_member_count = state
+    .repos
+    .members
+    .count_active_members(room.id)
     .await
     .map_err(db_error_to_service_error)?;
   if active_member_count == 0 {
-    delete_room(&mut conn, room_id)
+    state
+      .repos
+      .rooms
+      .delete_room(room_id)
       .await
       .map_err(db_error_to_service_error)?;
   }
 
-  let user_name = user.name.clone();
-  {
-    let mut lobby_mutex = lobby.rooms.lock().unwrap();
-    match lobby_mutex.get_mut(&room_id) {
-      Some(room) => {
-        room
-          .tx
-          .send(
-            serde_json::to_string(&ClientWsMessage {
-              member_id: deleted_member_id,
-              member_name: user_name,
-              message_type: ClientWsMessageType::Leave,
-              message: "user is kicked".to_owned(),
-              db_skip_write: true,
-            })
-            .unwrap(),
-          )
-          .unwrap();
-        println!("Sent kick message to user {:}", user_id);
-      }
-      _ => {
-        // this means user created the room but noone joined it
-      }
-    }
-  }
+  state.lobby.publish(
+    room_id,
+    &ClientWsMessage {
+      member_id: deleted_member_id,
+      user_id: user.id,
+      member_name: user.display_name().to_owned(),
+      message_type: ClientWsMessageType::Leave,
+      message: "user is kicked".to_owned(),
+      db_skip_write: true,
+    },
+  );
+  info!(user_id = user.id, room_id, "sent kick notice");
 
   Ok(Json(serde_json::json!({
   "roomName": room.name,
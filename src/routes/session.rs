@@ -0,0 +1,128 @@
+use super::models::RefreshRequest;
+use super::SharedState;
+use crate::auth::{issue_ws_ticket, refresh_session, SessionId, Ticket, Tokens};
+use crate::db::session::Session;
+use crate::errors::{db_error_to_service_error, not_found_as, ErrorCode, ServiceError};
+use axum::{extract::Extension, extract::Path, extract::State, Json};
+
+pub async fn refresh(
+  State(state): State<SharedState>,
+  Json(refresh_request): Json<RefreshRequest>,
+) -> Result<Json<Tokens>, ServiceError> {
+  Ok(Json(
+    refresh_session(&state, &refresh_request.refresh_token).await?,
+  ))
+}
+
+/// Trades the bearer token for a single-use ticket to open a socket with.
+pub async fn ws_ticket(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Extension(SessionId(session_id)): Extension<SessionId>,
+) -> Result<Json<Ticket>, ServiceError> {
+  Ok(Json(issue_ws_ticket(&state, user_id, session_id).await?))
+}
+
+/// Revokes the session of the access token, its refresh token stops working as well.
+pub async fn logout(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Extension(SessionId(session_id)): Extension<SessionId>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let session = state
+    .repos
+    .sessions
+    .revoke_session(user_id, session_id)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::SessionNotFound,
+      "Session does not exist",
+    ))?;
+  state.lobby.revoke_session(session.id);
+  Ok(Json(serde_json::json!({
+    "sessionId": session.id,
+    "revokedAt": session.revoked_at,
+  })))
+}
+
+/// Revokes every session of the user, including the current one.
+pub async fn logout_all(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let revoked = revoke_user_sessions(&state, user_id, None).await?;
+  Ok(Json(serde_json::json!({ "revokedSessions": revoked })))
+}
+
+/// Revokes every session of the user but `except_id` and closes their sockets,
+/// returning how many were revoked.
+pub(super) async fn revoke_user_sessions(
+  state: &SharedState,
+  user_id: i64,
+  except_id: Option<i64>,
+) -> Result<usize, ServiceError> {
+  let revoked = state
+    .repos
+    .sessions
+    .revoke_user_sessions(user_id, except_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  for session_id in &revoked {
+    state.lobby.revoke_session(*session_id);
+  }
+  Ok(revoked.len())
+}
+
+/// Lists the devices the user is logged in on.
+pub async fn list_sessions(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Extension(SessionId(session_id)): Extension<SessionId>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let sessions = state
+    .repos
+    .sessions
+    .list_user_sessions(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let sessions: Vec<serde_json::Value> = sessions
+    .iter()
+    .map(|session| session_to_json(session, session_id))
+    .collect();
+  Ok(Json(serde_json::json!({ "sessions": sessions })))
+}
+
+/// Revokes one session of the user and closes the sockets opened with it.
+pub async fn revoke_session(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let session = state
+    .repos
+    .sessions
+    .revoke_session(user_id, id)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::SessionNotFound,
+      "Session does not exist",
+    ))?;
+  let closed_sockets = state.lobby.revoke_session(session.id);
+  Ok(Json(serde_json::json!({
+    "sessionId": session.id,
+    "revokedAt": session.revoked_at,
+    "closedSockets": closed_sockets,
+  })))
+}
+
+fn session_to_json(session: &Session, current_session_id: i64) -> serde_json::Value {
+  serde_json::json!({
+    "id": session.id,
+    "createdAt": session.created_at,
+    "lastUsedAt": session.last_used_at,
+    "expiresAt": session.expires_at,
+    "userAgent": session.user_agent,
+    "ipAddress": session.ip_address,
+    "current": session.id == current_session_id,
+  })
+}
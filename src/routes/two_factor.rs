@@ -0,0 +1,114 @@
+use super::models::TotpCodeRequest;
+use super::SharedState;
+use crate::auth::totp::{
+  base32_encode, check_second_factor, hash_recovery_code, new_recovery_codes, new_secret,
+  open_secret, provisioning_uri, verify_code,
+};
+use crate::errors::{
+  conflict_as, db_error_to_service_error, not_found_as, ErrorCode, ServiceError,
+};
+use axum::{extract::Extension, extract::State, Json};
+
+/// Starts enrolling an authenticator, replacing an enrolment that was never confirmed.
+pub async fn enrol_totp(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let secret = new_secret();
+  state
+    .repos
+    .two_factor
+    .start_totp_enrolment(user_id, &state.auth.totp_cipher.seal(user_id, &secret))
+    .await
+    .map_err(conflict_as(
+      ErrorCode::TotpAlreadyEnabled,
+      "Two-factor authentication is already enabled",
+    ))?;
+  Ok(Json(serde_json::json!({
+    "secret": base32_encode(&secret),
+    "provisioningUri": provisioning_uri(&secret, &user.name),
+  })))
+}
+
+/// Turns two-factor authentication on with a first code of the authenticator. The
+/// recovery codes are only ever shown in this response.
+pub async fn confirm_totp(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Json(request): Json<TotpCodeRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let totp = state
+    .repos
+    .two_factor
+    .get_user_totp(user_id)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::TotpNotEnrolled,
+      "No authenticator is being enrolled",
+    ))?;
+  if totp.confirmed_at.is_some() {
+    return Err(ServiceError::new(
+      ErrorCode::TotpAlreadyEnabled,
+      "Two-factor authentication is already enabled",
+    ));
+  }
+  let secret = open_secret(&state, &totp)?;
+  let step = verify_code(&secret, request.code.trim())
+    .ok_or_else(|| ServiceError::new(ErrorCode::InvalidTotpCode, "Invalid authentication code"))?;
+
+  let recovery_codes = new_recovery_codes();
+  let hashes: Vec<String> = recovery_codes
+    .iter()
+    .map(|code| hash_recovery_code(code))
+    .collect();
+  let totp = state
+    .repos
+    .two_factor
+    .confirm_totp(user_id, step, &hashes)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::TotpNotEnrolled,
+      "No authenticator is being enrolled",
+    ))?;
+  Ok(Json(serde_json::json!({
+    "enabledAt": totp.confirmed_at,
+    "recoveryCodes": recovery_codes,
+  })))
+}
+
+/// Turns two-factor authentication off, which takes a code of the authenticator or a
+/// recovery code once it is enabled.
+pub async fn disable_totp(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Json(request): Json<TotpCodeRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let totp = state
+    .repos
+    .two_factor
+    .get_user_totp(user_id)
+    .await
+    .map_err(not_found_as(
+      ErrorCode::TotpNotEnrolled,
+      "Two-factor authentication is not enabled",
+    ))?;
+  if totp.confirmed_at.is_some() && !check_second_factor(&state, &totp, &request.code).await? {
+    return Err(ServiceError::new(
+      ErrorCode::InvalidTotpCode,
+      "Invalid authentication code",
+    ));
+  }
+  state
+    .repos
+    .two_factor
+    .delete_user_totp(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  Ok(Json(serde_json::json!({ "twoFactorEnabled": false })))
+}
@@ -1,53 +1,342 @@
-use super::models::{LoginRequest, NewUserRequest};
-use crate::auth::create_jwt;
-use crate::db::user::{get_user as get_user_from_db, get_user_by_id, insert_new_user};
-use crate::errors::{db_error_to_service_error, internal_error_to_service_error, ServiceError};
-use crate::ConnectionPool;
-use axum::{extract::Extension, extract::State, Json};
+use super::models::{
+  LoginRequest, NewUserRequest, SearchUsersQuery, TotpLoginRequest, UpdateProfileRequest,
+  UpdateStatusRequest,
+};
+use super::SharedState;
+use crate::auth::totp::{complete_login_challenge, start_login_challenge};
+use crate::auth::{start_session, ClientInfo, Tokens};
+use crate::db::user::{User, UserProfile, UserStatus};
+use crate::db::DbError;
+use crate::errors::{
+  conflict_as, db_error_to_service_error, not_found_as, ErrorCode, ServiceError,
+};
+use crate::ws::lobby::Lobby;
+use axum::{extract::Extension, extract::Path, extract::Query, extract::State, Json};
+
+const MAX_STATUS_TEXT_LEN: usize = 255;
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_NAME_LEN: usize = 64;
+const MAX_BIO_LEN: usize = 1000;
+const MAX_AVATAR_URL_LEN: usize = 2048;
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 50;
 
 pub async fn signup(
-  State(pool): State<ConnectionPool>,
+  State(state): State<SharedState>,
+  client: ClientInfo,
   Json(user): Json<NewUserRequest>,
 ) -> Result<Json<serde_json::Value>, ServiceError> {
-  let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-  let user = insert_new_user(&mut conn, user.name, user.password)
+  let email = user.email.as_deref().map(normalize_email).transpose()?;
+  if let Some(email) = &email {
+    match state.repos.users.get_user_by_email(email).await {
+      Ok(_) => {
+        return Err(ServiceError::new(
+          ErrorCode::EmailTaken,
+          "Email address is already in use",
+        ))
+      }
+      Err(DbError::NotFound) => {}
+      Err(e) => return Err(db_error_to_service_error(e)),
+    }
+  }
+  let user = state
+    .repos
+    .users
+    .insert_new_user(user.name, user.password, email)
     .await
-    .map_err(db_error_to_service_error)?;
-  Ok(Json(serde_json::json!({
-    "id": user.id,
-    "name": user.name,
-    "createdAt": user.created_at,
-    "authToken": create_jwt(user.id).unwrap()
-  })))
+    .map_err(conflict_as(
+      ErrorCode::NameTaken,
+      "User name is already taken",
+    ))?;
+  let tokens = start_session(&state, user.id, client).await?;
+  Ok(Json(signed_in(&user, tokens)))
 }
 
 pub async fn login(
-  State(pool): State<ConnectionPool>,
+  State(state): State<SharedState>,
+  client: ClientInfo,
   Json(user): Json<LoginRequest>,
 ) -> Result<Json<serde_json::Value>, ServiceError> {
-  let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-  let user = get_user_from_db(&mut conn, user.name, user.password)
+  let user = match state.repos.users.get_user(user.name, user.password).await {
+    Ok(user) => user,
+    Err(e) => {
+      state.lobby.metrics.auth_failure("invalid_credentials");
+      return Err(not_found_as(
+        ErrorCode::InvalidCredentials,
+        "Invalid user name or password",
+      )(e));
+    }
+  };
+  match state.repos.two_factor.get_user_totp(user.id).await {
+    Ok(totp) if totp.confirmed_at.is_some() => {
+      let (challenge_token, expires_in) = start_login_challenge(&state, user.id).await?;
+      return Ok(Json(serde_json::json!({
+        "twoFactorRequired": true,
+        "challengeToken": challenge_token,
+        "expiresIn": expires_in,
+      })));
+    }
+    Ok(_) | Err(DbError::NotFound) => {}
+    Err(e) => return Err(db_error_to_service_error(e)),
+  }
+  let tokens = start_session(&state, user.id, client).await?;
+  Ok(Json(signed_in(&user, tokens)))
+}
+
+/// Second step of a login for users with two-factor authentication, trading the
+/// challenge token and a code of the authenticator, or a recovery code, for tokens.
+pub async fn login_totp(
+  State(state): State<SharedState>,
+  client: ClientInfo,
+  Json(login): Json<TotpLoginRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let user_id = complete_login_challenge(&state, &login.challenge_token, &login.code).await?;
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
     .await
     .map_err(db_error_to_service_error)?;
-  Ok(Json(serde_json::json!({
+  let tokens = start_session(&state, user.id, client).await?;
+  Ok(Json(signed_in(&user, tokens)))
+}
+
+/// Lowercases the address, rejecting anything that does not look like one.
+pub(super) fn normalize_email(email: &str) -> Result<String, ServiceError> {
+  let email = email.trim().to_lowercase();
+  let valid = match email.split_once('@') {
+    Some((local, domain)) => {
+      !local.is_empty()
+        && domain.contains('.')
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+    }
+    None => false,
+  };
+  if !valid || email.len() > MAX_EMAIL_LEN {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      "Invalid email address",
+    ));
+  }
+  Ok(email)
+}
+
+fn signed_in(user: &User, tokens: Tokens) -> serde_json::Value {
+  serde_json::json!({
     "id": user.id,
     "name": user.name,
+    "displayName": user.display_name(),
     "createdAt": user.created_at,
-    "authToken": create_jwt(user.id).unwrap()
-  })))
+    "authToken": tokens.auth_token,
+    "refreshToken": tokens.refresh_token,
+    "expiresIn": tokens.expires_in,
+  })
 }
 
 pub async fn get_user(
-  State(pool): State<ConnectionPool>,
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
+    .await
+    .map_err(db_error_to_service_error)?;
+  Ok(Json(own_profile(&user, &state.lobby)))
+}
+
+pub async fn get_user_by_user_id(
+  State(state): State<SharedState>,
+  Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let user = state
+    .repos
+    .users
+    .get_live_user_by_id(id)
+    .await
+    .map_err(not_found_as(ErrorCode::UserNotFound, "User does not exist"))?;
+  Ok(Json(user_with_presence(&user, &state.lobby)))
+}
+
+/// Finds users by the start of their name or display name, users hidden from search
+/// are left out.
+pub async fn search_users(
+  State(state): State<SharedState>,
+  Query(query): Query<SearchUsersQuery>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  let prefix = query.q.trim();
+  if prefix.is_empty() {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      "Search query must not be empty",
+    ));
+  }
+  let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+  if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      format!("Limit must be between 1 and {}", MAX_SEARCH_LIMIT),
+    ));
+  }
+  let users = state
+    .repos
+    .users
+    .search_users(prefix, limit)
+    .await
+    .map_err(db_error_to_service_error)?;
+  let users: Vec<_> = users
+    .iter()
+    .map(|user| user_with_presence(user, &state.lobby))
+    .collect();
+  Ok(Json(serde_json::json!({ "users": users })))
+}
+
+pub async fn update_status(
+  State(state): State<SharedState>,
+  Extension(user_id): Extension<i64>,
+  Json(update_status_request): Json<UpdateStatusRequest>,
+) -> Result<Json<serde_json::Value>, ServiceError> {
+  // online is the default when connected, so choosing it clears the explicit status
+  let status = match update_status_request.status {
+    UserStatus::Online => None,
+    UserStatus::Away | UserStatus::DoNotDisturb => Some(update_status_request.status),
+    UserStatus::Offline => {
+      return Err(ServiceError::new(
+        ErrorCode::BadRequest,
+        "Status can only be set to online, away or doNotDisturb",
+      ));
+    }
+  };
+  let status_text = update_status_request
+    .status_text
+    .map(|text| text.trim().to_owned())
+    .filter(|text| !text.is_empty());
+  if let Some(text) = &status_text {
+    if text.chars().count() > MAX_STATUS_TEXT_LEN {
+      return Err(ServiceError::new(
+        ErrorCode::BadRequest,
+        "Status text is too long",
+      ));
+    }
+  }
+
+  let user = state
+    .repos
+    .users
+    .update_status(user_id, status, status_text)
+    .await
+    .map_err(db_error_to_service_error)?;
+  Ok(Json(user_with_presence(&user, &state.lobby)))
+}
+
+/// Edits the profile of the user. Renaming checks the new login name is free, live
+/// sockets use the new display name from their next message on.
+pub async fn update_profile(
+  State(state): State<SharedState>,
   Extension(user_id): Extension<i64>,
+  Json(request): Json<UpdateProfileRequest>,
 ) -> Result<Json<serde_json::Value>, ServiceError> {
-  let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-  let user = get_user_by_id(&mut conn, user_id)
+  let user = state
+    .repos
+    .users
+    .get_user_by_id(user_id)
     .await
     .map_err(db_error_to_service_error)?;
-  Ok(Json(serde_json::json!({
+  let mut profile = UserProfile {
+    name: user.name,
+    display_name: user.display_name,
+    bio: user.bio,
+    avatar_url: user.avatar_url,
+    hidden_from_search: user.hidden_from_search,
+  };
+  if let Some(name) = request.name {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+      return Err(ServiceError::new(
+        ErrorCode::BadRequest,
+        format!("Name must be 1 to {} characters long", MAX_NAME_LEN),
+      ));
+    }
+    profile.name = name.to_owned();
+  }
+  if let Some(display_name) = request.display_name {
+    profile.display_name = profile_text(display_name, MAX_NAME_LEN, "Display name")?;
+  }
+  if let Some(bio) = request.bio {
+    profile.bio = profile_text(bio, MAX_BIO_LEN, "Bio")?;
+  }
+  if let Some(hidden_from_search) = request.hidden_from_search {
+    profile.hidden_from_search = hidden_from_search;
+  }
+  if let Some(avatar_url) = request.avatar_url {
+    profile.avatar_url = profile_text(avatar_url, MAX_AVATAR_URL_LEN, "Avatar URL")?;
+    if let Some(url) = &profile.avatar_url {
+      if !(url.starts_with("https://") || url.starts_with("http://")) {
+        return Err(ServiceError::new(
+          ErrorCode::BadRequest,
+          "Avatar URL must be an http or https URL",
+        ));
+      }
+    }
+  }
+
+  let user = state
+    .repos
+    .users
+    .update_profile(user_id, &profile)
+    .await
+    .map_err(conflict_as(
+      ErrorCode::NameTaken,
+      "User name is already taken",
+    ))?;
+  state
+    .lobby
+    .update_display_name(user.id, user.display_name().to_owned());
+  Ok(Json(own_profile(&user, &state.lobby)))
+}
+
+// Trims an optional profile field, an empty value clears it.
+fn profile_text(text: String, max_len: usize, field: &str) -> Result<Option<String>, ServiceError> {
+  let text = text.trim();
+  if text.chars().count() > max_len {
+    return Err(ServiceError::new(
+      ErrorCode::BadRequest,
+      format!("{} is too long", field),
+    ));
+  }
+  Ok(Some(text.to_owned()).filter(|text| !text.is_empty()))
+}
+
+// The user is online while it has at least one live socket on this instance or any
+// other one, an explicitly chosen status only applies on top of that. The lobby is
+// asked too since the count of instances is saved in the background.
+fn presence(user: &User, lobby: &Lobby) -> UserStatus {
+  if !lobby.is_online(user.id) && user.online_instances <= 0 {
+    return UserStatus::Offline;
+  }
+  user.status.unwrap_or(UserStatus::Online)
+}
+
+// Adds the settings only the user itself gets to see.
+fn own_profile(user: &User, lobby: &Lobby) -> serde_json::Value {
+  let mut body = user_with_presence(user, lobby);
+  body["email"] = serde_json::json!(user.email);
+  body["hiddenFromSearch"] = serde_json::json!(user.hidden_from_search);
+  body
+}
+
+fn user_with_presence(user: &User, lobby: &Lobby) -> serde_json::Value {
+  serde_json::json!({
     "id": user.id,
     "name": user.name,
+    "displayName": user.display_name(),
+    "bio": user.bio,
+    "avatarUrl": user.avatar_url,
     "createdAt": user.created_at,
-  })))
+    "status": presence(user, lobby),
+    "statusText": user.status_text,
+    "lastSeenAt": user.last_seen_at,
+  })
 }
@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{Arc, Mutex},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use super::lobby::{
+  revoke_local_session, update_local_blocked_users, update_local_display_name, RoomState,
+  SessionSockets, SocketUser,
+};
+use super::ClientWsMessage;
+
+pub mod postgres;
+pub mod redis;
+
+/// Rooms with live sockets on this instance, each one with the broadcast channel of its sockets.
+pub type LocalRooms = Arc<Mutex<HashMap<i64, RoomState>>>;
+
+/// Login sessions with live sockets on this instance, keyed by session id.
+pub type LocalSessions = Arc<Mutex<HashMap<i64, SessionSockets>>>;
+
+/// Users with live sockets on this instance, keyed by user id.
+pub type LocalUsers = Arc<Mutex<HashMap<i64, SocketUser>>>;
+
+/// The state of the lobby on this instance, the buses apply the events of the other
+/// instances to it.
+#[derive(Clone, Default)]
+pub struct LocalState {
+  pub rooms: LocalRooms,
+  pub sessions: LocalSessions,
+  pub users: LocalUsers,
+}
+
+/// Fans the events of a room out to every socket of the room.
+pub trait RoomBus: Send + Sync {
+  /// Delivers the serialized `ClientWsMessage` to the sockets of the room on this instance
+  /// and to every other instance sharing the bus.
+  fn publish(&self, room_id: i64, event: String);
+
+  /// Closes the sockets of a revoked session on every other instance sharing the bus, the
+  /// lobby closes the ones on this instance itself.
+  fn publish_session_revoked(&self, _session_id: i64) {}
+
+  /// Hands the changed blocks of a user to its sockets on every other instance sharing the
+  /// bus, the lobby updates the ones on this instance itself.
+  fn publish_blocks_changed(&self, _user_id: i64, _blocked_user_ids: &HashSet<i64>) {}
+
+  /// Hands the new display name of a user to its sockets on every other instance sharing
+  /// the bus, the lobby updates the ones on this instance itself.
+  fn publish_display_name_changed(&self, _user_id: i64, _display_name: &str) {}
+}
+
+/// Keeps room events inside the process, for a single instance deployment.
+pub struct InProcessBus {
+  rooms: LocalRooms,
+}
+
+impl InProcessBus {
+  pub fn new(rooms: LocalRooms) -> InProcessBus {
+    InProcessBus { rooms }
+  }
+}
+
+impl RoomBus for InProcessBus {
+  fn publish(&self, room_id: i64, event: String) {
+    deliver_local(&self.rooms, room_id, event);
+  }
+}
+
+/// Sends the event only to the sockets of the room on this instance.
+pub(crate) fn deliver_local(rooms: &LocalRooms, room_id: i64, event: String) {
+  if let Some(room_state) = rooms.lock().unwrap().get(&room_id) {
+    // a room without receivers has no socket left to tell
+    let _ = room_state.tx.send(event);
+  }
+}
+
+/// A bus relaying events between instances, its transport moves the payloads of a `Relay`.
+pub trait RelayBus: Send + Sync {
+  fn relay(&self) -> &Relay;
+}
+
+impl<T: RelayBus> RoomBus for T {
+  fn publish(&self, room_id: i64, event: String) {
+    self.relay().publish(room_id, event);
+  }
+
+  fn publish_session_revoked(&self, session_id: i64) {
+    self.relay().publish_session_revoked(session_id);
+  }
+
+  fn publish_blocks_changed(&self, user_id: i64, blocked_user_ids: &HashSet<i64>) {
+    self
+      .relay()
+      .publish_blocks_changed(user_id, blocked_user_ids);
+  }
+
+  fn publish_display_name_changed(&self, user_id: i64, display_name: &str) {
+    self
+      .relay()
+      .publish_display_name_changed(user_id, display_name);
+  }
+}
+
+/// What the buses relaying events between instances share: events of this instance are
+/// tagged with its id and queued for the transport, events the transport receives are
+/// applied to the local state unless this instance published them.
+#[derive(Clone)]
+pub struct Relay {
+  instance_id: String,
+  local: LocalState,
+  tx: mpsc::UnboundedSender<String>,
+}
+
+impl Relay {
+  /// Returns the relay and the payloads the transport has to send, in publishing order.
+  pub(crate) fn new(local: LocalState) -> (Relay, mpsc::UnboundedReceiver<String>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let relay = Relay {
+      instance_id: new_instance_id(),
+      local,
+      tx,
+    };
+    (relay, rx)
+  }
+
+  pub(crate) fn publish(&self, room_id: i64, event: String) {
+    deliver_local(&self.local.rooms, room_id, event.clone());
+    self.send(RemoteEventKind::Room { room_id, event });
+  }
+
+  pub(crate) fn publish_session_revoked(&self, session_id: i64) {
+    self.send(RemoteEventKind::SessionRevoked { session_id });
+  }
+
+  pub(crate) fn publish_blocks_changed(&self, user_id: i64, blocked_user_ids: &HashSet<i64>) {
+    self.send(RemoteEventKind::BlocksChanged {
+      user_id,
+      blocked_user_ids: blocked_user_ids.clone(),
+    });
+  }
+
+  pub(crate) fn publish_display_name_changed(&self, user_id: i64, display_name: &str) {
+    self.send(RemoteEventKind::DisplayNameChanged {
+      user_id,
+      display_name: display_name.to_owned(),
+    });
+  }
+
+  fn send(&self, kind: RemoteEventKind) {
+    let payload = serde_json::to_string(&RemoteEvent {
+      instance_id: self.instance_id.clone(),
+      kind,
+    })
+    .unwrap();
+    // the transport only stops with the bus
+    let _ = self.tx.send(payload);
+  }
+
+  /// Applies an event received from the transport to the local sockets of its room, session
+  /// or user. Bad payloads are logged and skipped.
+  pub(crate) fn deliver(&self, payload: &str) {
+    let remote_event = match serde_json::from_str::<RemoteEvent>(payload) {
+      Ok(remote_event) => remote_event,
+      Err(e) => {
+        warn!(error = %e, bytes = payload.len(), "error parsing remote event");
+        return;
+      }
+    };
+    // events of this instance were applied locally already
+    if remote_event.instance_id == self.instance_id {
+      return;
+    }
+    let (room_id, event) = match remote_event.kind {
+      RemoteEventKind::Room { room_id, event } => (room_id, event),
+      RemoteEventKind::SessionRevoked { session_id } => {
+        revoke_local_session(&self.local.sessions, session_id);
+        return;
+      }
+      RemoteEventKind::BlocksChanged {
+        user_id,
+        blocked_user_ids,
+      } => {
+        update_local_blocked_users(&self.local.users, user_id, blocked_user_ids);
+        return;
+      }
+      RemoteEventKind::DisplayNameChanged {
+        user_id,
+        display_name,
+      } => {
+        update_local_display_name(&self.local.users, user_id, display_name);
+        return;
+      }
+    };
+    let mut msg = match serde_json::from_str::<ClientWsMessage>(&event) {
+      Ok(msg) => msg,
+      Err(e) => {
+        warn!(error = %e, room_id, "error parsing remote event message");
+        return;
+      }
+    };
+    // the instance that published the event already saved it
+    msg.db_skip_write = true;
+    deliver_local(
+      &self.local.rooms,
+      room_id,
+      serde_json::to_string(&msg).unwrap(),
+    );
+  }
+}
+
+/// Event relayed between instances.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteEvent {
+  instance_id: String,
+  #[serde(flatten)]
+  kind: RemoteEventKind,
+}
+
+// Untagged, so room events keep the shape they had before sessions and users were relayed.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RemoteEventKind {
+  #[serde(rename_all = "camelCase")]
+  Room {
+    room_id: i64,
+    // serialized `ClientWsMessage`
+    event: String,
+  },
+  #[serde(rename_all = "camelCase")]
+  SessionRevoked { session_id: i64 },
+  #[serde(rename_all = "camelCase")]
+  BlocksChanged {
+    user_id: i64,
+    blocked_user_ids: HashSet<i64>,
+  },
+  #[serde(rename_all = "camelCase")]
+  DisplayNameChanged { user_id: i64, display_name: String },
+}
+
+fn new_instance_id() -> String {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_nanos();
+  format!("{}-{:x}", std::process::id(), nanos)
+}
@@ -0,0 +1,102 @@
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use futures_util::stream::StreamExt;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_postgres::{config::Config, AsyncMessage, NoTls};
+use tracing::{error, info, warn};
+
+use super::{LocalState, Relay, RelayBus};
+
+// Postgres channel every instance publishes room events to and listens on.
+const CHANNEL: &str = "room_events";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Relays room events between instances with Postgres `pg_notify` and `LISTEN`.
+pub struct PostgresBus {
+  relay: Relay,
+}
+
+impl PostgresBus {
+  /// Starts the task sending the notifications of this instance, one at a time so that room
+  /// events keep their order, and the task listening for the notifications of the others.
+  pub fn spawn(
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    config: Config,
+    local: LocalState,
+  ) -> PostgresBus {
+    let (relay, mut rx) = Relay::new(local);
+    tokio::spawn(async move {
+      while let Some(payload) = rx.recv().await {
+        let conn = match pool.get().await {
+          Ok(conn) => conn,
+          Err(e) => {
+            error!(error = %e, "error getting db connection for room event");
+            continue;
+          }
+        };
+        if let Err(e) = conn
+          .execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+          .await
+        {
+          error!(error = %e, "error publishing room event");
+        }
+      }
+    });
+    spawn_listener(config, relay.clone());
+    PostgresBus { relay }
+  }
+}
+
+impl RelayBus for PostgresBus {
+  fn relay(&self) -> &Relay {
+    &self.relay
+  }
+}
+
+/// Listens for room events of the other instances, the connection is reestablished
+/// whenever it is lost.
+fn spawn_listener(config: Config, relay: Relay) {
+  tokio::spawn(async move {
+    loop {
+      if let Err(e) = listen(&config, &relay).await {
+        warn!(error = %e, "room event listener failed");
+      }
+      tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+  });
+}
+
+async fn listen(config: &Config, relay: &Relay) -> Result<(), tokio_postgres::Error> {
+  let (client, mut connection) = config.connect(NoTls).await?;
+  let (notification_tx, mut notification_rx) = mpsc::unbounded_channel();
+  // notifications only arrive while the connection is polled
+  let driver = tokio::spawn(async move {
+    let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+    while let Some(message) = messages.next().await {
+      match message {
+        Ok(AsyncMessage::Notification(notification)) => {
+          if notification_tx.send(notification).is_err() {
+            break;
+          }
+        }
+        Ok(_) => {}
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  });
+  client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+  info!("listening for room events");
+
+  while let Some(notification) = notification_rx.recv().await {
+    relay.deliver(notification.payload());
+  }
+  match driver.await {
+    Ok(result) => result,
+    Err(e) => {
+      error!(error = %e, "room event listener connection task failed");
+      Ok(())
+    }
+  }
+}
@@ -0,0 +1,94 @@
+use futures_util::stream::StreamExt;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client, RedisError};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use super::{LocalState, Relay, RelayBus};
+
+// Redis channel every instance publishes room events to and subscribes to.
+const CHANNEL: &str = "room_events";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Relays room events between instances with Redis pub/sub.
+pub struct RedisBus {
+  relay: Relay,
+}
+
+impl RedisBus {
+  /// Connects to Redis and starts the task publishing the events of this instance, one at a
+  /// time so that room events keep their order, and the task subscribed to the others.
+  pub async fn connect(url: &str, local: LocalState) -> Result<RedisBus, RedisError> {
+    let client = Client::open(url)?;
+    let conn = client.get_multiplexed_tokio_connection().await?;
+    let (relay, rx) = Relay::new(local);
+    spawn_publisher(client.clone(), conn, rx);
+    spawn_subscriber(client, relay.clone());
+    Ok(RedisBus { relay })
+  }
+}
+
+impl RelayBus for RedisBus {
+  fn relay(&self) -> &Relay {
+    &self.relay
+  }
+}
+
+fn spawn_publisher(
+  client: Client,
+  conn: MultiplexedConnection,
+  mut rx: mpsc::UnboundedReceiver<String>,
+) {
+  tokio::spawn(async move {
+    let mut conn = Some(conn);
+    while let Some(payload) = rx.recv().await {
+      let publisher = match conn.as_mut() {
+        Some(publisher) => publisher,
+        None => match client.get_multiplexed_tokio_connection().await {
+          Ok(publisher) => conn.insert(publisher),
+          Err(e) => {
+            error!(error = %e, "error connecting to redis for room event");
+            continue;
+          }
+        },
+      };
+      let published: Result<i64, RedisError> = publisher.publish(CHANNEL, payload).await;
+      if let Err(e) = published {
+        error!(error = %e, "error publishing room event");
+        // reconnect for the next event
+        conn = None;
+      }
+    }
+  });
+}
+
+/// Subscribes to the room events of the other instances, the subscription is reestablished
+/// whenever it is lost.
+fn spawn_subscriber(client: Client, relay: Relay) {
+  tokio::spawn(async move {
+    loop {
+      match subscribe(&client, &relay).await {
+        Ok(()) => warn!("room event subscription was closed"),
+        Err(e) => warn!(error = %e, "room event subscriber failed"),
+      }
+      tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+  });
+}
+
+/// Returns once the connection is lost.
+async fn subscribe(client: &Client, relay: &Relay) -> Result<(), RedisError> {
+  let mut pubsub = client.get_async_connection().await?.into_pubsub();
+  pubsub.subscribe(CHANNEL).await?;
+  info!("subscribed to room events");
+
+  let mut messages = pubsub.on_message();
+  while let Some(message) = messages.next().await {
+    match message.get_payload::<String>() {
+      Ok(payload) => relay.deliver(&payload),
+      Err(e) => warn!(error = %e, "skipping a room event that is not a string"),
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+
+use super::ServerTaskTerminationReason;
+use crate::helpers::get_env_or;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig {
+  // How often the server pings every socket.
+  pub ping_interval: Duration,
+  // Pings left without any answer before the socket is considered dead.
+  pub max_missed_pongs: u32,
+  // Time without any message from the client before it is disconnected, `None` disables it.
+  pub idle_timeout: Option<Duration>,
+}
+
+impl Default for HeartbeatConfig {
+  fn default() -> Self {
+    HeartbeatConfig {
+      ping_interval: Duration::from_secs(30),
+      max_missed_pongs: 2,
+      idle_timeout: Some(Duration::from_secs(30 * 60)),
+    }
+  }
+}
+
+impl HeartbeatConfig {
+  /// Reads `WS_PING_INTERVAL_SECS`, `WS_MAX_MISSED_PONGS` and `WS_IDLE_TIMEOUT_SECS`,
+  /// an idle timeout of 0 disables it. The ping interval and missed pongs must be at least 1.
+  pub fn from_env() -> Self {
+    let default = HeartbeatConfig::default();
+    let ping_interval_secs =
+      get_env_at_least_one("WS_PING_INTERVAL_SECS", default.ping_interval.as_secs());
+    let max_missed_pongs = get_env_at_least_one("WS_MAX_MISSED_PONGS", default.max_missed_pongs);
+    let idle_timeout_secs = get_env_or(
+      "WS_IDLE_TIMEOUT_SECS",
+      default.idle_timeout.map_or(0, |timeout| timeout.as_secs()),
+    );
+    HeartbeatConfig {
+      ping_interval: Duration::from_secs(ping_interval_secs),
+      max_missed_pongs,
+      idle_timeout: (idle_timeout_secs > 0).then(|| Duration::from_secs(idle_timeout_secs)),
+    }
+  }
+}
+
+// A zero ping interval panics the ticker and zero missed pongs drops every socket on its first ping.
+fn get_env_at_least_one<T>(key: &str, default: T) -> T
+where
+  T: std::str::FromStr + PartialOrd + From<u8>,
+{
+  let val = get_env_or(key, default);
+  if val < T::from(1) {
+    panic!("couldn't interpret {}: must be at least 1", key);
+  }
+  val
+}
+
+/// Liveness of a single socket, shared by the tasks reading from and writing to it.
+pub struct Heartbeat {
+  config: HeartbeatConfig,
+  missed_pongs: AtomicU32,
+  last_activity: Mutex<Instant>,
+}
+
+impl Heartbeat {
+  pub fn new(config: HeartbeatConfig) -> Heartbeat {
+    Heartbeat {
+      config,
+      missed_pongs: AtomicU32::new(0),
+      last_activity: Mutex::new(Instant::now()),
+    }
+  }
+
+  /// Interval for the ping ticks, the first tick fires one period from now.
+  pub fn ping_ticker(&self) -> Interval {
+    let ping_interval = self.config.ping_interval;
+    let mut ticker = interval_at(Instant::now() + ping_interval, ping_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+  }
+
+  /// Any frame from the client proves the connection is still alive.
+  pub fn frame_received(&self) {
+    self.missed_pongs.store(0, Ordering::Relaxed);
+  }
+
+  /// Text and binary frames count as user activity for the idle timeout.
+  pub fn activity(&self) {
+    self.frame_received();
+    *self.last_activity.lock().unwrap() = Instant::now();
+  }
+
+  /// Called on every ping tick before the ping is sent.
+  /// Returns the reason to terminate the socket when it is dead or idle.
+  pub fn check(&self) -> Option<ServerTaskTerminationReason> {
+    if let Some(idle_timeout) = self.config.idle_timeout {
+      if self.last_activity.lock().unwrap().elapsed() >= idle_timeout {
+        return Some(ServerTaskTerminationReason::IdleTimeout);
+      }
+    }
+    let missed_pongs = self.missed_pongs.fetch_add(1, Ordering::Relaxed);
+    if missed_pongs >= self.config.max_missed_pongs {
+      return Some(ServerTaskTerminationReason::HeartbeatTimeout);
+    }
+    None
+  }
+}
@@ -1,12 +1,14 @@
-use axum::extract::ws::{Message, WebSocket};
-use bb8::Pool;
-use bb8_postgres::PostgresConnectionManager;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 
-use super::{ClientWsMessage, ClientWsMessageType, ServerTaskTerminationReason};
-use tokio_postgres::NoTls;
+use super::bus::{InProcessBus, LocalRooms, LocalSessions, LocalState, LocalUsers, RoomBus};
+use super::heartbeat::{Heartbeat, HeartbeatConfig};
+use super::{
+  ClientWsMessage, ClientWsMessageType, ServerFrame, ServerTaskTerminationReason, MAX_MESSAGE_LEN,
+};
 
 use std::{
   collections::{HashMap, HashSet},
+  sync::atomic::{AtomicBool, Ordering},
   sync::{Arc, Mutex},
 };
 
@@ -15,350 +17,931 @@ use futures_util::{
   SinkExt,
 };
 
+use std::future::Future;
 use std::ops::ControlFlow;
-use tokio::sync::broadcast;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::db::member::{update_last_joined_at, Member};
-use crate::db::message::add_message;
-use crate::errors::internal_error_to_service_error;
+use crate::db::member::Member;
+use crate::db::Repositories;
+use crate::errors::{db_error_to_service_error, not_found_as, ErrorCode};
+use crate::logging::body;
+use crate::metrics::Metrics;
+use crate::rate_limit::{retry_after_secs, RateLimit, RateLimitConfig, RateLimiter};
 
 use crate::{db::room::Room, errors::ServiceError};
 
 pub struct Lobby {
   // We require unique usernames. This tracks which usernames have been taken.
-  pub rooms: Mutex<HashMap<i64, RoomState>>,
-  pub pool: Pool<PostgresConnectionManager<NoTls>>,
+  pub rooms: LocalRooms,
+  // Number of live sockets per user id, used to derive user presence.
+  pub connections: Mutex<HashMap<i64, usize>>,
+  // Live sockets per login session, so revoking a session closes them.
+  sessions: LocalSessions,
+  // The users with a live socket, kept current by profile and block edits.
+  socket_users: LocalUsers,
+  pub repos: Repositories,
+  pub heartbeat: HeartbeatConfig,
+  pub metrics: Arc<Metrics>,
+  // Chat messages a room member may send on its sockets, `None` when unlimited.
+  message_rate: Option<RateLimiter<i64>>,
+  // Set once the server is shutting down, before the sockets are closed.
+  shutting_down: AtomicBool,
+  // Holds the retry hint for clients once the server is shutting down.
+  shutdown: watch::Sender<Option<Duration>>,
+  // Notified when the last live socket is closed.
+  all_disconnected: Notify,
+  // Tasks writing to the database in the background, awaited on shutdown.
+  background_tasks: Mutex<Vec<JoinHandle<()>>>,
+  // Fans room events out to the sockets of this and, depending on the bus, other instances.
+  bus: Arc<dyn RoomBus>,
+}
+
+/// Live state of the lobby on this instance.
+pub struct LobbyStats {
+  pub active_rooms: usize,
+  pub sockets: usize,
+  pub online_users: usize,
+}
+
+/// What the sockets of a user need to know about it.
+pub struct SocketUser {
+  pub display_name: String,
+  // messages of these users are not delivered to the user
+  pub blocked_user_ids: HashSet<i64>,
+}
+
+/// The live sockets of a login session on this instance.
+pub struct SessionSockets {
+  revoked: watch::Sender<bool>,
+  sockets: usize,
 }
 
 pub struct RoomState {
-  // The set of all connected clients.
-  pub clients: HashSet<i64>,
+  // Number of connected sockets per user id.
+  pub clients: HashMap<i64, usize>,
 
   // The name of the room.
   pub name: String,
   pub tx: broadcast::Sender<String>,
+  // Chat messages waiting for the task saving them, which publishes each one once saved.
+  writer: Option<mpsc::UnboundedSender<ClientWsMessage>>,
 }
 
 impl Lobby {
-  pub fn new(pool: Pool<PostgresConnectionManager<NoTls>>) -> Lobby {
+  pub fn new(repos: Repositories, heartbeat: HeartbeatConfig) -> Lobby {
+    let rooms = LocalRooms::default();
     Lobby {
-      rooms: Mutex::new(HashMap::new()),
-      pool,
+      rooms: rooms.clone(),
+      connections: Mutex::new(HashMap::new()),
+      sessions: LocalSessions::default(),
+      socket_users: LocalUsers::default(),
+      repos,
+      heartbeat,
+      metrics: Arc::new(Metrics::new()),
+      message_rate: RateLimitConfig::default()
+        .socket_messages
+        .map(RateLimiter::new),
+      shutting_down: AtomicBool::new(false),
+      shutdown: watch::channel(None).0,
+      all_disconnected: Notify::new(),
+      background_tasks: Mutex::new(Vec::new()),
+      bus: Arc::new(InProcessBus::new(rooms)),
+    }
+  }
+
+  /// Replaces the default in-process bus, the bus has to deliver to `local_state`.
+  pub fn with_bus(mut self, bus: impl RoomBus + 'static) -> Lobby {
+    self.bus = Arc::new(bus);
+    self
+  }
+
+  /// Replaces the default limit of the chat messages a room member may send.
+  pub fn with_message_rate(mut self, limit: Option<RateLimit>) -> Lobby {
+    self.message_rate = limit.map(RateLimiter::new);
+    self
+  }
+
+  pub fn local_state(&self) -> LocalState {
+    LocalState {
+      rooms: self.rooms.clone(),
+      sessions: self.sessions.clone(),
+      users: self.socket_users.clone(),
+    }
+  }
+
+  /// Publishes the event to every socket of the room, on this instance and on the others
+  /// sharing the room bus. Events to be saved go out once they are saved, in order with the
+  /// other chat messages of the room, and are dropped when saving fails.
+  pub fn publish(&self, room_id: i64, msg: &ClientWsMessage) {
+    if msg.db_skip_write {
+      send_to_bus(&*self.bus, &self.metrics, room_id, msg);
+      return;
+    }
+    let writer = self
+      .rooms
+      .lock()
+      .unwrap()
+      .get(&room_id)
+      .and_then(|room_state| room_state.writer.clone());
+    let msg = msg.clone();
+    match writer {
+      // the writer only stops once the room state and with it the sender are gone
+      Some(writer) => {
+        let _ = writer.send(msg);
+      }
+      None => {
+        let (repos, metrics, bus) = (self.repos.clone(), self.metrics.clone(), self.bus.clone());
+        self.spawn_background(async move {
+          save_and_publish(&repos, &metrics, &*bus, room_id, msg).await;
+        });
+      }
+    }
+  }
+
+  pub fn stats(&self) -> LobbyStats {
+    let active_rooms = self.rooms.lock().unwrap().len();
+    let connections = self.connections.lock().unwrap();
+    LobbyStats {
+      active_rooms,
+      sockets: connections.values().sum(),
+      online_users: connections.len(),
+    }
+  }
+
+  pub fn is_online(&self, user_id: i64) -> bool {
+    self.connections.lock().unwrap().contains_key(&user_id)
+  }
+
+  /// Registers a socket opened with the session's token, the returned receiver turns
+  /// true once the session is revoked.
+  pub(crate) fn connect_user(
+    &self,
+    user_id: i64,
+    session_id: i64,
+    user: SocketUser,
+  ) -> watch::Receiver<bool> {
+    let first_socket = {
+      let mut connections = self.connections.lock().unwrap();
+      let count = connections.entry(user_id).or_insert(0);
+      *count += 1;
+      *count == 1
+    };
+    if first_socket {
+      let repos = self.repos.clone();
+      self.spawn_background(async move {
+        if let Err(e) = repos.users.update_online_instances(user_id, 1).await {
+          error!(user_id, error = %e, "error updating online_instances");
+        }
+      });
+    }
+    self.socket_users.lock().unwrap().insert(user_id, user);
+    let mut sessions = self.sessions.lock().unwrap();
+    let session = sessions
+      .entry(session_id)
+      .or_insert_with(|| SessionSockets {
+        revoked: watch::channel(false).0,
+        sockets: 0,
+      });
+    session.sockets += 1;
+    session.revoked.subscribe()
+  }
+
+  /// Closes every live socket of the session, the ones on other instances through the room
+  /// bus. Returns how many sockets on this instance were told.
+  pub fn revoke_session(&self, session_id: i64) -> usize {
+    self.bus.publish_session_revoked(session_id);
+    revoke_local_session(&self.sessions, session_id)
+  }
+
+  /// Changes the name the live sockets of the user send with their messages, the ones on
+  /// other instances through the room bus.
+  pub fn update_display_name(&self, user_id: i64, display_name: String) {
+    self
+      .bus
+      .publish_display_name_changed(user_id, &display_name);
+    update_local_display_name(&self.socket_users, user_id, display_name);
+  }
+
+  /// Changes whose messages the live sockets of the user skip, the ones on other instances
+  /// through the room bus.
+  pub fn update_blocked_users(&self, user_id: i64, blocked_user_ids: HashSet<i64>) {
+    self.bus.publish_blocks_changed(user_id, &blocked_user_ids);
+    update_local_blocked_users(&self.socket_users, user_id, blocked_user_ids);
+  }
+
+  /// The current display name of a user with a live socket, `fallback` otherwise.
+  pub(crate) fn display_name(&self, user_id: i64, fallback: &str) -> String {
+    match self.socket_users.lock().unwrap().get(&user_id) {
+      Some(user) => user.display_name.clone(),
+      None => fallback.to_owned(),
+    }
+  }
+
+  /// Whether the user with a live socket blocked `sender_id`.
+  pub(crate) fn is_blocked(&self, user_id: i64, sender_id: i64) -> bool {
+    self
+      .socket_users
+      .lock()
+      .unwrap()
+      .get(&user_id)
+      .is_some_and(|user| user.blocked_user_ids.contains(&sender_id))
+  }
+
+  /// returns true when the last socket of the user was closed
+  pub(crate) fn disconnect_user(&self, user_id: i64, session_id: i64) -> bool {
+    {
+      let mut sessions = self.sessions.lock().unwrap();
+      if let Some(session) = sessions.get_mut(&session_id) {
+        session.sockets -= 1;
+        if session.sockets == 0 {
+          sessions.remove(&session_id);
+        }
+      }
+    }
+    let mut connections = self.connections.lock().unwrap();
+    match connections.get_mut(&user_id) {
+      Some(count) if *count > 1 => {
+        *count -= 1;
+        false
+      }
+      Some(_) => {
+        connections.remove(&user_id);
+        self.socket_users.lock().unwrap().remove(&user_id);
+        if connections.is_empty() {
+          self.all_disconnected.notify_waiters();
+        }
+        true
+      }
+      None => false,
+    }
+  }
+
+  pub(crate) fn spawn_background<F>(&self, task: F)
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    let mut background_tasks = self.background_tasks.lock().unwrap();
+    background_tasks.retain(|task| !task.is_finished());
+    background_tasks.push(tokio::spawn(task.in_current_span()));
+  }
+
+  /// Takes a message token of the member, or returns how long to wait for the next one.
+  pub(crate) fn check_message_rate(&self, member_id: i64) -> Result<(), Duration> {
+    match &self.message_rate {
+      Some(limiter) => limiter
+        .check(member_id)
+        .inspect_err(|_| self.metrics.rate_limited("socket")),
+      None => Ok(()),
+    }
+  }
+
+  pub(crate) fn shutdown_receiver(&self) -> watch::Receiver<Option<Duration>> {
+    self.shutdown.subscribe()
+  }
+
+  pub fn is_shutting_down(&self) -> bool {
+    self.shutting_down.load(Ordering::Relaxed)
+  }
+
+  /// Marks the server as shutting down, the live sockets are left open.
+  pub fn begin_shutdown(&self) {
+    self.shutting_down.store(true, Ordering::Relaxed);
+  }
+
+  /// Asks every live socket to close, clients are told to reconnect after `retry_after`.
+  pub fn close_sockets(&self, retry_after: Duration) {
+    self.begin_shutdown();
+    self.shutdown.send_replace(Some(retry_after));
+  }
+
+  /// Waits until every socket is closed and every pending database write is flushed.
+  pub async fn drain(&self) {
+    loop {
+      let all_disconnected = self.all_disconnected.notified();
+      if self.connections.lock().unwrap().is_empty() {
+        break;
+      }
+      all_disconnected.await;
+    }
+    loop {
+      let background_tasks = std::mem::take(&mut *self.background_tasks.lock().unwrap());
+      if background_tasks.is_empty() {
+        break;
+      }
+      for task in background_tasks {
+        let _ = task.await;
+      }
+    }
+  }
+
+  /// Registers a socket of the user in the room and returns the room channel.
+  /// The room state and the task saving its messages are created with the first socket.
+  pub(crate) fn add_client(
+    &self,
+    room_id: i64,
+    name: String,
+    user_id: i64,
+  ) -> broadcast::Sender<String> {
+    let mut rooms = self.rooms.lock().unwrap();
+    let room_state = rooms.entry(room_id).or_insert_with(|| {
+      let (tx, _) = broadcast::channel(10);
+      let (writer_tx, writer_rx) = mpsc::unbounded_channel();
+      let writer = write_room_messages(
+        room_id,
+        writer_rx,
+        self.repos.clone(),
+        self.metrics.clone(),
+        self.bus.clone(),
+      );
+      // the writer outlives the socket that created the room
+      self.spawn_background(writer.instrument(info_span!(parent: None, "room_writer", room_id)));
+      let mut room_state = RoomState::new(name, tx);
+      room_state.writer = Some(writer_tx);
+      room_state
+    });
+    *room_state.clients.entry(user_id).or_insert(0) += 1;
+    room_state.tx.clone()
+  }
+
+  /// Unregisters a socket of the user, the room state is dropped with its last socket.
+  pub(crate) fn remove_client(&self, room_id: i64, user_id: i64) {
+    let mut rooms = self.rooms.lock().unwrap();
+    debug!(room_id, user_id, "removing socket from room");
+    if let Some(room_state) = rooms.get_mut(&room_id) {
+      match room_state.clients.get_mut(&user_id) {
+        Some(count) if *count > 1 => *count -= 1,
+        _ => {
+          room_state.clients.remove(&user_id);
+        }
+      }
+      if room_state.clients.is_empty() {
+        rooms.remove(&room_id);
+      }
     }
   }
 }
 
+/// Resolves with the retry hint once the server starts shutting down.
+pub(crate) async fn shutting_down(shutdown: &mut watch::Receiver<Option<Duration>>) -> Duration {
+  loop {
+    if let Some(retry_after) = *shutdown.borrow() {
+      return retry_after;
+    }
+    if shutdown.changed().await.is_err() {
+      // the lobby is gone, so there is nothing to wait for
+      return std::future::pending().await;
+    }
+  }
+}
+
+/// Closes every live socket of the session on this instance, returns how many were told.
+pub(crate) fn revoke_local_session(sessions: &LocalSessions, session_id: i64) -> usize {
+  match sessions.lock().unwrap().get(&session_id) {
+    Some(session) => {
+      session.revoked.send_replace(true);
+      session.sockets
+    }
+    None => 0,
+  }
+}
+
+/// Changes the name the live sockets of the user on this instance send with their messages.
+pub(crate) fn update_local_display_name(users: &LocalUsers, user_id: i64, display_name: String) {
+  if let Some(user) = users.lock().unwrap().get_mut(&user_id) {
+    user.display_name = display_name;
+  }
+}
+
+/// Changes whose messages the live sockets of the user on this instance skip.
+pub(crate) fn update_local_blocked_users(
+  users: &LocalUsers,
+  user_id: i64,
+  blocked_user_ids: HashSet<i64>,
+) {
+  if let Some(user) = users.lock().unwrap().get_mut(&user_id) {
+    user.blocked_user_ids = blocked_user_ids;
+  }
+}
+
+/// Resolves once the session of the socket is revoked.
+pub(crate) async fn session_revoked(revoked: &mut watch::Receiver<bool>) {
+  if revoked.wait_for(|revoked| *revoked).await.is_err() {
+    // the registry entry is gone, so the session can no longer be revoked
+    std::future::pending::<()>().await;
+  }
+}
+
+pub(crate) fn revoked_close_frame() -> Message {
+  Message::Close(Some(CloseFrame {
+    code: close_code::POLICY,
+    reason: "session revoked".into(),
+  }))
+}
+
+pub(crate) fn restart_close_frame(retry_after: Duration) -> Message {
+  Message::Close(Some(CloseFrame {
+    code: close_code::RESTART,
+    reason: format!("server restarting, retry in {}s", retry_after.as_secs()).into(),
+  }))
+}
+
+/// Tells the client a chat message was dropped because it is longer than `MAX_MESSAGE_LEN`.
+pub(crate) fn message_too_long_frame(room_id: i64) -> ServerFrame {
+  ServerFrame::Error {
+    room_id: Some(room_id),
+    code: ErrorCode::MessageTooLong,
+    message: format!(
+      "Message is too long, the limit is {} characters",
+      MAX_MESSAGE_LEN
+    ),
+    retry_after: None,
+  }
+}
+
+/// Tells the client a chat message was dropped because it sends too fast.
+pub(crate) fn rate_limited_frame(room_id: i64, retry_after: Duration) -> ServerFrame {
+  let secs = retry_after_secs(retry_after);
+  ServerFrame::Error {
+    room_id: Some(room_id),
+    code: ErrorCode::RateLimited,
+    message: format!("Too many messages, retry in {} seconds", secs),
+    retry_after: Some(secs),
+  }
+}
+
 impl RoomState {
   pub fn new(name: String, tx: broadcast::Sender<String>) -> RoomState {
     RoomState {
-      clients: HashSet::new(),
+      clients: HashMap::new(),
       name,
       tx,
+      writer: None,
     }
   }
 }
 
+/// Checks that the user may enter the room and returns the room together with the user's
+/// membership. The user becomes a member of the room on the first join.
+pub async fn resolve_room_member(
+  repos: &Repositories,
+  room_id: i64,
+  user_id: i64,
+) -> Result<(Room, Member), ServiceError> {
+  let room = repos
+    .rooms
+    .get_room_by_id(room_id)
+    .await
+    .map_err(not_found_as(ErrorCode::RoomNotFound, "Room does not exist"))?;
+  if room.deleted_at.is_some() {
+    return Err(ServiceError::new(
+      ErrorCode::RoomNotFound,
+      "Room is deleted",
+    ));
+  }
+  if room.is_direct() && !room.is_direct_participant(user_id) {
+    return Err(ServiceError::new(
+      ErrorCode::Forbidden,
+      "Cannot join a direct message room",
+    ));
+  }
+  if room.is_direct() {
+    let other_user_id = if room.direct_user_a == Some(user_id) {
+      room.direct_user_b
+    } else {
+      room.direct_user_a
+    };
+    if let Some(other_user_id) = other_user_id {
+      if repos
+        .blocks
+        .is_blocked_between(user_id, other_user_id)
+        .await
+        .map_err(db_error_to_service_error)?
+      {
+        return Err(ServiceError::new(
+          ErrorCode::UserBlocked,
+          "Direct messages between these users are blocked",
+        ));
+      }
+    }
+  }
+  let member = match repos.members.get_member(room_id, user_id).await {
+    Ok(member) => member,
+    Err(_) => repos
+      .members
+      .create_new_member(room.id, user_id)
+      .await
+      .map_err(db_error_to_service_error)?,
+  };
+  Ok((room, member))
+}
+
 pub async fn upgrade_to_websocket(
   stream: WebSocket,
   state: Arc<Lobby>,
   user_id: i64,
+  session_id: i64,
   room: Room,
   member: Member,
-  user_name: String,
+  user: SocketUser,
 ) {
   // By splitting we can send and receive at the same time.
   let (sender, receiver) = stream.split();
-  // We have more state now that needs to be pulled out of the connect loop
-  let mut tx: Option<broadcast::Sender<String>> = None::<broadcast::Sender<String>>;
   let member_id = member.id;
+  let user_name = user.display_name.clone();
+  let revoked = state.connect_user(user_id, session_id, user);
 
-  {
-    // create or get the room state
-    let room_id = room.id;
-    let name = room.name;
-    let mut rooms = state.rooms.lock().unwrap();
-    let room_state = rooms.entry(room_id).or_insert_with(|| {
-      let (tx, _) = broadcast::channel(10);
-      RoomState::new(name, tx)
-    });
-    if !room_state.clients.contains(&user_id) {
-      room_state.clients.insert(user_id);
-    }
-    tx = Some(room_state.tx.clone());
-  }
-
-  let tx = tx.unwrap();
+  // create or get the room state
+  let tx = state.add_client(room.id, room.name, user_id);
 
   let rx = tx.subscribe();
 
-  let rx_db = tx.subscribe();
+  let heartbeat = Arc::new(Heartbeat::new(state.heartbeat));
+  // frames the server answers the client with, like rate limit errors
+  let (replies_tx, replies_rx) = mpsc::channel(8);
 
-  let mut sender_task = create_sender_task(sender, rx, member.clone());
+  let mut sender_task = create_sender_task(
+    sender,
+    rx,
+    replies_rx,
+    member.clone(),
+    heartbeat.clone(),
+    revoked,
+    state.clone(),
+  );
 
-  let mut db_write_task = create_db_write_task(room.id, rx_db, state.pool.clone());
-
-  let mut receiver_task = create_receiver_task(receiver, tx.clone(), member, user_name.clone());
+  let mut receiver_task = create_receiver_task(
+    receiver,
+    state.clone(),
+    room.id,
+    member,
+    user_name.clone(),
+    heartbeat,
+    replies_tx,
+  );
 
   // If any one of the tasks run to completion, we abort the other.
   let reason = tokio::select! {
       reason = (&mut sender_task) => {
-        println!("sender task completed, member_id: {}, room_id: {}", member_id, room.id);
+        debug!("sender task completed");
         receiver_task.abort();
-        db_write_task.abort();
         reason
       },
       reason = (&mut receiver_task) => {
-        println!("receiver task completed, member_id: {}, room_id: {}", member_id, room.id);
+        debug!("receiver task completed");
         sender_task.abort();
-        db_write_task.abort();
-        reason
-      },
-      reason = (&mut db_write_task) => {
-        println!("db write task completed, member_id: {}, room_id: {}", member_id, room.id);
-        sender_task.abort();
-        receiver_task.abort();
         reason
       },
   };
 
   let room_id: i64 = room.id;
-  remove_user_from_room(member_id, user_id, room_id, user_name, tx, state, reason);
+  remove_user_from_room(
+    member_id, user_id, session_id, room_id, user_name, state, reason,
+  );
 }
 
 pub fn remove_user_from_room(
   member_id: i64,
   user_id: i64,
+  session_id: i64,
   room_id: i64,
   user_name: String,
-  tx: broadcast::Sender<String>,
   state: Arc<Lobby>,
   reason: Result<Result<ServerTaskTerminationReason, ServiceError>, tokio::task::JoinError>,
 ) {
+  let reason = match reason {
+    Ok(Ok(reason)) => Some(reason),
+    Ok(Err(e)) => {
+      warn!(error = e.message(), "ws task terminating with error");
+      None
+    }
+    Err(e) => {
+      error!(error = %e, "ws task failed");
+      None
+    }
+  };
+  leave_room_state(&state, member_id, user_id, room_id, &user_name, reason);
+  disconnect_socket(&state, user_id, session_id, user_name);
+}
+
+/// Announces why the member's socket left the room, unregisters it from the room state
+/// and updates the member's `last_joined_at`. A `None` reason means the socket failed.
+pub(crate) fn leave_room_state(
+  state: &Lobby,
+  member_id: i64,
+  user_id: i64,
+  room_id: i64,
+  user_name: &str,
+  reason: Option<ServerTaskTerminationReason>,
+) {
+  let user_name = &state.display_name(user_id, user_name);
   let mut db_skip_write = true;
   let msg = match reason {
-    Ok(reason) => match reason {
-      Ok(reason) => match reason {
-        ServerTaskTerminationReason::ClientDisconnected => {
-          format!("{} disconnected from the room", user_name)
-        }
-        ServerTaskTerminationReason::ClientLeft => {
-          db_skip_write = false;
-          format!("{} left the room", user_name)
-        }
-      },
-      Err(e) => {
-        println!(
-          ">>> ws task terminating with error: {} for user: {}",
-          e.message(),
-          user_id
-        );
-        format!("{} user faced some issues... disconnecting", user_name)
-      }
-    },
-    Err(e) => {
-      println!(
-        ">>> ws task terminating with join err, err: {}, user: {}",
-        e, user_id
-      );
-      format!("{} user faced some issues... disconnecting", user_name)
+    Some(ServerTaskTerminationReason::ClientDisconnected) => {
+      format!("{} disconnected from the room", user_name)
+    }
+    Some(ServerTaskTerminationReason::ClientLeft) => {
+      db_skip_write = false;
+      format!("{} left the room", user_name)
+    }
+    Some(ServerTaskTerminationReason::HeartbeatTimeout) => {
+      format!("{} lost the connection", user_name)
+    }
+    Some(ServerTaskTerminationReason::IdleTimeout) => {
+      format!("{} was disconnected for inactivity", user_name)
     }
+    Some(ServerTaskTerminationReason::ServerShutdown) => {
+      format!("{} disconnected, the server is restarting", user_name)
+    }
+    Some(ServerTaskTerminationReason::SessionRevoked) => {
+      format!("{} was logged out", user_name)
+    }
+    None => format!("{} user faced some issues... disconnecting", user_name),
   };
   let ws_msg = ClientWsMessage {
     member_id,
+    user_id,
     message_type: ClientWsMessageType::Message,
-    member_name: user_name.clone(),
+    member_name: user_name.to_owned(),
     message: msg,
     db_skip_write,
   };
-  tx.send(serde_json::to_string(&ws_msg).unwrap()).unwrap();
-  {
-    // remove the user from the room
-    let mut rooms = state.rooms.lock().unwrap();
-    println!(">>> removing user from room: {}", room_id);
-    let room_state = match rooms.get_mut(&room_id) {
-      Some(room_state) => room_state,
-      None => return,
-    };
-    room_state.clients.remove(&user_id);
-    if room_state.clients.is_empty() {
-      rooms.remove(&room_id);
-    }
-  }
-  let pool = state.pool.clone();
+  state.publish(room_id, &ws_msg);
+  state.remove_client(room_id, user_id);
 
   // update db status for member's table
-  tokio::spawn(async move {
-    let mut conn: bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>> = pool
-      .get()
-      .await
-      .map_err(internal_error_to_service_error)
-      .unwrap();
-    match update_last_joined_at(&mut conn, room_id, member_id).await {
+  let repos = state.repos.clone();
+  let user_name = user_name.to_owned();
+  state.spawn_background(async move {
+    match repos.members.update_last_joined_at(room_id, user_id).await {
       Ok(_) => {
-        println!(">>> {} left the room", user_name);
+        info!(room_id, member_id, reason = ?reason, "{} left the room", user_name);
       }
       Err(e) => {
-        println!(
-          "error updating last_joined_at for member: {}, err: {}",
-          user_name, e
-        );
-        println!("member_id: {}, room_id: {}", member_id, room_id);
+        error!(room_id, member_id, error = %e, "error updating last_joined_at");
       }
     }
   });
 }
 
-fn create_db_write_task(
+/// Unregisters one socket of the user. Once the user has no live socket left on this
+/// instance, the instance no longer counts towards its presence and `last_seen_at` is
+/// persisted.
+pub(crate) fn disconnect_socket(state: &Lobby, user_id: i64, session_id: i64, user_name: String) {
+  if !state.disconnect_user(user_id, session_id) {
+    return;
+  }
+  let repos = state.repos.clone();
+  state.spawn_background(async move {
+    if let Err(e) = repos.users.update_online_instances(user_id, -1).await {
+      error!(user_id, error = %e, "error updating online_instances");
+    }
+    if let Err(e) = repos.users.update_last_seen_at(user_id).await {
+      error!(user_id, error = %e, "error updating last_seen_at for {}", user_name);
+    }
+  });
+}
+
+/// Saves the chat messages of the room one after the other and publishes each one once it
+/// is saved. Stops once the room state is gone and every queued message is handled.
+async fn write_room_messages(
   room_id: i64,
-  mut rx: broadcast::Receiver<String>,
-  pool: bb8::Pool<bb8_postgres::PostgresConnectionManager<NoTls>>,
-) -> tokio::task::JoinHandle<Result<ServerTaskTerminationReason, ServiceError>> {
-  tokio::spawn(async move {
-    while let Ok(msg) = rx.recv().await {
-      match serde_json::from_str::<ClientWsMessage>(&msg) {
-        Ok(m) => {
-          if m.db_skip_write {
-            println!(">>> skipping db write for msg: {:?}", m.message);
-            continue;
-          }
-          let mut conn = pool.get().await.map_err(internal_error_to_service_error)?;
-          match add_message(&mut conn, room_id, m.member_id, &m.message).await {
-            Ok(_) => {
-              println!(">>> {} sent msg: {:?} saved in db", m.member_id, m.message);
-            }
-            Err(e) => {
-              println!(
-                "error saving msg: {:?} from {} in to db, err: {:}",
-                m.message, m.member_id, e
-              );
-            }
-          }
-        }
-        _ => {
-          println!("error parsing message");
-        }
-      }
+  mut rx: mpsc::UnboundedReceiver<ClientWsMessage>,
+  repos: Repositories,
+  metrics: Arc<Metrics>,
+  bus: Arc<dyn RoomBus>,
+) {
+  while let Some(msg) = rx.recv().await {
+    save_and_publish(&repos, &metrics, &*bus, room_id, msg).await;
+  }
+}
+
+async fn save_and_publish(
+  repos: &Repositories,
+  metrics: &Metrics,
+  bus: &dyn RoomBus,
+  room_id: i64,
+  mut msg: ClientWsMessage,
+) {
+  match repos
+    .messages
+    .add_message(room_id, msg.member_id, &msg.message)
+    .await
+  {
+    Ok(_) => {
+      metrics.messages_persisted.inc();
+      debug!(member_id = msg.member_id, message = %body(&msg.message), "message saved");
     }
-    Ok(ServerTaskTerminationReason::ClientDisconnected)
-  })
+    Err(e) => {
+      metrics.persistence_failures.inc();
+      error!(member_id = msg.member_id, error = %e, "error saving message, dropping it");
+      return;
+    }
+  }
+  msg.db_skip_write = true;
+  send_to_bus(bus, metrics, room_id, &msg);
+}
+
+fn send_to_bus(bus: &dyn RoomBus, metrics: &Metrics, room_id: i64, msg: &ClientWsMessage) {
+  metrics.messages_broadcast.inc();
+  bus.publish(room_id, serde_json::to_string(msg).unwrap());
 }
 
 fn create_receiver_task(
   mut receiver: SplitStream<WebSocket>,
-  tx: broadcast::Sender<String>,
+  state: Arc<Lobby>,
+  room_id: i64,
   member: Member,
-  member_name: String,
+  user_name: String,
+  heartbeat: Arc<Heartbeat>,
+  replies: mpsc::Sender<Message>,
 ) -> tokio::task::JoinHandle<Result<ServerTaskTerminationReason, ServiceError>> {
-  tokio::spawn(async move {
-    while let Some(msg) = receiver.next().await {
-      // In any websocket error, break loop.
-      // TODO: handle msg error
-      if process_message(tx.clone(), msg.unwrap(), member_name.clone(), member.id).is_break() {
-        break;
+  tokio::spawn(
+    async move {
+      while let Some(msg) = receiver.next().await {
+        // In any websocket error, break loop.
+        // TODO: handle msg error
+        if process_message(
+          &state,
+          room_id,
+          msg.unwrap(),
+          &member,
+          &user_name,
+          &heartbeat,
+          &replies,
+        )
+        .is_break()
+        {
+          break;
+        }
       }
+      Ok(ServerTaskTerminationReason::ClientDisconnected)
     }
-    Ok(ServerTaskTerminationReason::ClientDisconnected)
-  })
+    .in_current_span(),
+  )
 }
 
 fn create_sender_task(
   mut sender: SplitSink<WebSocket, Message>,
   mut rx: broadcast::Receiver<String>,
+  mut replies: mpsc::Receiver<Message>,
   member: Member,
+  heartbeat: Arc<Heartbeat>,
+  mut revoked: watch::Receiver<bool>,
+  state: Arc<Lobby>,
 ) -> tokio::task::JoinHandle<Result<ServerTaskTerminationReason, ServiceError>> {
-  tokio::spawn(async move {
-    while let Ok(msg) = rx.recv().await {
-      match serde_json::from_str::<ClientWsMessage>(&msg) {
-        Ok(m) => match m.message_type {
-          ClientWsMessageType::Leave => {
-            if m.member_id == member.id {
-              let msg = Message::Text(m.message);
-              if sender.send(msg).await.is_err() {
-                break;
+  let mut shutdown = state.shutdown_receiver();
+  tokio::spawn(
+    async move {
+      let mut ping_ticker = heartbeat.ping_ticker();
+      loop {
+        let msg = tokio::select! {
+          retry_after = shutting_down(&mut shutdown) => {
+            let _ = sender.send(restart_close_frame(retry_after)).await;
+            return Ok(ServerTaskTerminationReason::ServerShutdown);
+          },
+          _ = session_revoked(&mut revoked) => {
+            info!("closing socket, its session was revoked");
+            let _ = sender.send(revoked_close_frame()).await;
+            return Ok(ServerTaskTerminationReason::SessionRevoked);
+          },
+          msg = rx.recv() => match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+              if let RecvError::Lagged(skipped) = e {
+                state.metrics.broadcast_lag("socket");
+                warn!(skipped, "socket lagged behind the room, closing it");
               }
-              return Ok(ServerTaskTerminationReason::ClientLeft);
+              break;
             }
+          },
+          Some(reply) = replies.recv() => {
+            if sender.send(reply).await.is_err() {
+              break;
+            }
+            continue;
+          },
+          _ = ping_ticker.tick() => {
+            if let Some(reason) = heartbeat.check() {
+              info!(reason = ?reason, "closing socket after heartbeat check");
+              let _ = sender.send(Message::Close(None)).await;
+              return Ok(reason);
+            }
+            if sender.send(Message::Ping(Vec::new())).await.is_err() {
+              break;
+            }
+            continue;
           }
-          ClientWsMessageType::Message => {
-            // In any websocket error, break loop.
-            // we are skipping for now sending back user's own message
-            // this can be an issue if we want to support multiple sessions for same user
-            if member.id != m.member_id
-              && sender
+        };
+        match serde_json::from_str::<ClientWsMessage>(&msg) {
+          Ok(m) => match m.message_type {
+            ClientWsMessageType::Leave => {
+              if m.member_id == member.id {
+                let msg = Message::Text(m.message);
+                if sender.send(msg).await.is_err() {
+                  break;
+                }
+                return Ok(ServerTaskTerminationReason::ClientLeft);
+              }
+            }
+            ClientWsMessageType::Message => {
+              // we are skipping for now sending back user's own message
+              // this can be an issue if we want to support multiple sessions for same user
+              if member.id == m.member_id || state.is_blocked(member.user_id, m.user_id) {
+                continue;
+              }
+              // In any websocket error, break loop.
+              if sender
                 .send(Message::Text(m.message.to_owned()))
                 .await
                 .is_err()
-            {
-              println!(
-                "error sending message from {} to {}",
-                m.member_id, member.id
-              );
-              break;
-            } else {
-              println!(
-                ">>> {} sent str: {:?} to {}",
-                m.member_id, m.message, member.id
-              );
+              {
+                debug!(
+                  from_member_id = m.member_id,
+                  "error sending message, socket is gone"
+                );
+                break;
+              } else {
+                debug!(from_member_id = m.member_id, message = %body(&m.message), "message sent");
+              }
             }
+          },
+          _ => {
+            warn!("error parsing room event");
           }
-        },
-        _ => {
-          println!("error parsing message");
         }
       }
+      Ok(ServerTaskTerminationReason::ClientDisconnected)
     }
-    Ok(ServerTaskTerminationReason::ClientDisconnected)
-  })
+    .in_current_span(),
+  )
 }
 
 /// helper to print contents of messages to stdout. Has special treatment for Close.
 fn process_message(
-  tx: broadcast::Sender<String>,
+  state: &Lobby,
+  room_id: i64,
   msg: Message,
-  member_name: String,
-  member_id: i64,
+  member: &Member,
+  user_name: &str,
+  heartbeat: &Heartbeat,
+  replies: &mpsc::Sender<Message>,
 ) -> ControlFlow<(), ()> {
+  heartbeat.frame_received();
   match msg {
     Message::Text(t) => {
-      println!(">>> {} sent str: {:?}", member_id, t);
-      tx.send(
-        serde_json::to_string(&ClientWsMessage {
-          member_id,
-          member_name,
+      heartbeat.activity();
+      state.metrics.messages_received.inc();
+      debug!(message = %body(&t), "message received");
+      let rejected = if t.chars().count() > MAX_MESSAGE_LEN {
+        Some(message_too_long_frame(room_id))
+      } else {
+        state
+          .check_message_rate(member.id)
+          .err()
+          .map(|retry_after| rate_limited_frame(room_id, retry_after))
+      };
+      if let Some(frame) = rejected {
+        // a client that does not read its replies only misses the error
+        let _ = replies.try_send(Message::Text(serde_json::to_string(&frame).unwrap()));
+        return ControlFlow::Continue(());
+      }
+      state.publish(
+        room_id,
+        &ClientWsMessage {
+          member_id: member.id,
+          user_id: member.user_id,
+          member_name: state.display_name(member.user_id, user_name),
           message_type: ClientWsMessageType::Message,
           message: t,
           db_skip_write: false,
-        })
-        .unwrap(),
-      )
-      .unwrap();
+        },
+      );
     }
     Message::Binary(d) => {
-      println!(">>> {} sent {} bytes: {:?}", member_id, d.len(), d);
+      heartbeat.activity();
+      debug!(bytes = d.len(), "binary message received");
     }
     Message::Close(c) => {
       if let Some(cf) = c {
-        println!(
-          ">>> {} sent close with code {} and reason `{}`",
-          member_id, cf.code, cf.reason
-        );
+        debug!(code = cf.code, reason = %cf.reason, "client sent close");
       } else {
-        println!(
-          ">>> {} somehow sent close message without CloseFrame",
-          member_id
-        );
+        debug!("client sent close without a close frame");
       }
       return ControlFlow::Break(());
     }
 
     Message::Pong(v) => {
-      println!(">>> {} sent pong with {:?}", member_id, v);
+      debug!(bytes = v.len(), "pong received");
     }
     // You should never need to manually handle Message::Ping, as axum's websocket library
     // will do so for you automagically by replying with Pong and copying the v according to
     // spec. But if you need the contents of the pings you can see them here.
     Message::Ping(v) => {
-      println!(">>> {} sent ping with {:?}", member_id, v);
+      debug!(bytes = v.len(), "ping received");
     }
   }
   ControlFlow::Continue(())
@@ -1,22 +1,90 @@
+pub mod bus;
+pub mod heartbeat;
 pub mod lobby;
+pub mod multiplex;
+use crate::errors::ErrorCode;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[derive(Serialize, Deserialize)]
+/// Longest chat message in characters. Room events are relayed between instances as
+/// `pg_notify` payloads, which have to stay below 8000 bytes even when every character of
+/// the message is escaped twice.
+pub const MAX_MESSAGE_LEN: usize = 1000;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ClientWsMessage {
   pub member_id: i64,
+  // the user behind the member, so blocked users can be filtered out
+  pub user_id: i64,
   pub member_name: String,
   pub message_type: ClientWsMessageType,
   pub message: String,
   pub db_skip_write: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum ClientWsMessageType {
   Message,
   Leave,
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum ServerTaskTerminationReason {
   ClientDisconnected,
   ClientLeft,
+  // The client stopped answering the server pings.
+  HeartbeatTimeout,
+  // The client did not send any message for the configured idle timeout.
+  IdleTimeout,
+  // The server is shutting down and closed the socket.
+  ServerShutdown,
+  // The login session the socket was opened with got revoked.
+  SessionRevoked,
+}
+
+/// Frames sent by clients of the multiplexed `/ws` socket.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientFrame {
+  #[serde(rename_all = "camelCase")]
+  Subscribe { room_id: i64 },
+  #[serde(rename_all = "camelCase")]
+  Unsubscribe { room_id: i64 },
+  #[serde(rename_all = "camelCase")]
+  Message { room_id: i64, message: String },
+}
+
+/// Frames sent to clients of the multiplexed `/ws` socket, each one is tagged with its room.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerFrame {
+  #[serde(rename_all = "camelCase")]
+  Subscribed { room_id: i64 },
+  #[serde(rename_all = "camelCase")]
+  Unsubscribed { room_id: i64 },
+  #[serde(rename_all = "camelCase")]
+  Message {
+    room_id: i64,
+    member_id: i64,
+    member_name: String,
+    message: String,
+  },
+  #[serde(rename_all = "camelCase")]
+  Leave { room_id: i64, message: String },
+  #[serde(rename_all = "camelCase")]
+  Error {
+    room_id: Option<i64>,
+    code: ErrorCode,
+    message: String,
+    // whole seconds to wait before sending again, only set for `RATE_LIMITED`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+  },
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies a socket in the logs, unique within the process.
+pub fn next_session_id() -> u64 {
+  NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
 }
@@ -0,0 +1,346 @@
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{
+  stream::{SplitSink, StreamExt},
+  SinkExt,
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, broadcast::error::RecvError, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use super::heartbeat::Heartbeat;
+use super::lobby::{
+  disconnect_socket, leave_room_state, message_too_long_frame, rate_limited_frame,
+  resolve_room_member, restart_close_frame, revoked_close_frame, session_revoked, shutting_down,
+  Lobby, SocketUser,
+};
+use super::{
+  ClientFrame, ClientWsMessage, ClientWsMessageType, ServerFrame, ServerTaskTerminationReason,
+  MAX_MESSAGE_LEN,
+};
+use crate::db::member::Member;
+use crate::errors::ErrorCode;
+
+// Frames queued for the socket before the room forwarders wait on it.
+const OUTBOUND_BUFFER: usize = 64;
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Subscription {
+  member: Member,
+  forwarder: JoinHandle<()>,
+}
+
+struct Session {
+  state: Arc<Lobby>,
+  user_id: i64,
+  user_name: String,
+  out_tx: mpsc::Sender<Message>,
+  // room forwarders report here when the member got removed from their room
+  removed_tx: mpsc::UnboundedSender<i64>,
+  subscriptions: HashMap<i64, Subscription>,
+}
+
+pub async fn upgrade_to_multiplexed_websocket(
+  stream: WebSocket,
+  state: Arc<Lobby>,
+  user_id: i64,
+  session_id: i64,
+  user: SocketUser,
+) {
+  let (sender, mut receiver) = stream.split();
+  let user_name = user.display_name.clone();
+  let (out_tx, out_rx) = mpsc::channel(OUTBOUND_BUFFER);
+  let (removed_tx, mut removed_rx) = mpsc::unbounded_channel();
+  let mut revoked = state.connect_user(user_id, session_id, user);
+
+  let mut session = Session {
+    state,
+    user_id,
+    user_name,
+    out_tx,
+    removed_tx,
+    subscriptions: HashMap::new(),
+  };
+  let mut sender_task = create_outbound_task(sender, out_rx);
+  let heartbeat = Heartbeat::new(session.state.heartbeat);
+  let mut ping_ticker = heartbeat.ping_ticker();
+  let mut shutdown = session.state.shutdown_receiver();
+
+  let reason = loop {
+    tokio::select! {
+      msg = receiver.next() => {
+        let msg = match msg {
+          Some(Ok(msg)) => msg,
+          _ => break ServerTaskTerminationReason::ClientDisconnected,
+        };
+        heartbeat.frame_received();
+        match msg {
+          Message::Text(t) => {
+            heartbeat.activity();
+            session.process_frame(&t).await;
+          }
+          Message::Binary(_) => heartbeat.activity(),
+          Message::Close(_) => {
+            debug!("client closed the multiplexed socket");
+            break ServerTaskTerminationReason::ClientDisconnected;
+          }
+          _ => {}
+        }
+      }
+      Some(room_id) = removed_rx.recv() => {
+        session.unsubscribe(room_id, ServerTaskTerminationReason::ClientLeft);
+      }
+      _ = ping_ticker.tick() => {
+        if let Some(reason) = heartbeat.check() {
+          info!(reason = ?reason, "closing multiplexed socket after heartbeat check");
+          let _ = session.out_tx.send(Message::Close(None)).await;
+          break reason;
+        }
+        let _ = session.out_tx.send(Message::Ping(Vec::new())).await;
+      }
+      retry_after = shutting_down(&mut shutdown) => {
+        let _ = session.out_tx.send(restart_close_frame(retry_after)).await;
+        break ServerTaskTerminationReason::ServerShutdown;
+      }
+      _ = session_revoked(&mut revoked) => {
+        info!("closing multiplexed socket, its session was revoked");
+        let _ = session.out_tx.send(revoked_close_frame()).await;
+        break ServerTaskTerminationReason::SessionRevoked;
+      }
+      _ = &mut sender_task => {
+        debug!("outbound task completed");
+        break ServerTaskTerminationReason::ClientDisconnected;
+      }
+    }
+  };
+
+  // give the outbound task a moment to flush the close frame queued by the server
+  if !sender_task.is_finished() && closed_by_server(reason) {
+    let _ = tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, &mut sender_task).await;
+  }
+  sender_task.abort();
+  let room_ids: Vec<i64> = session.subscriptions.keys().copied().collect();
+  for room_id in room_ids {
+    session.unsubscribe(room_id, reason);
+  }
+  disconnect_socket(&session.state, user_id, session_id, session.user_name);
+}
+
+impl Session {
+  async fn process_frame(&mut self, text: &str) {
+    let frame = match serde_json::from_str::<ClientFrame>(text) {
+      Ok(frame) => frame,
+      Err(_) => {
+        self
+          .send(ServerFrame::Error {
+            room_id: None,
+            code: ErrorCode::InvalidFrame,
+            message: "Invalid frame".to_owned(),
+            retry_after: None,
+          })
+          .await;
+        return;
+      }
+    };
+    match frame {
+      ClientFrame::Subscribe { room_id } => self.subscribe(room_id).await,
+      ClientFrame::Unsubscribe { room_id } => {
+        if self.subscriptions.contains_key(&room_id) {
+          self.unsubscribe(room_id, ServerTaskTerminationReason::ClientDisconnected);
+        }
+        self.send(ServerFrame::Unsubscribed { room_id }).await;
+      }
+      ClientFrame::Message { room_id, message } => {
+        let subscription = match self.subscriptions.get(&room_id) {
+          Some(subscription) => subscription,
+          None => {
+            self
+              .send(ServerFrame::Error {
+                room_id: Some(room_id),
+                code: ErrorCode::NotSubscribed,
+                message: "Not subscribed to the room".to_owned(),
+                retry_after: None,
+              })
+              .await;
+            return;
+          }
+        };
+        self.state.metrics.messages_received.inc();
+        let member_id = subscription.member.id;
+        if message.chars().count() > MAX_MESSAGE_LEN {
+          self.send(message_too_long_frame(room_id)).await;
+          return;
+        }
+        if let Err(retry_after) = self.state.check_message_rate(member_id) {
+          self.send(rate_limited_frame(room_id, retry_after)).await;
+          return;
+        }
+        let ws_msg = ClientWsMessage {
+          member_id,
+          user_id: self.user_id,
+          member_name: self.state.display_name(self.user_id, &self.user_name),
+          message_type: ClientWsMessageType::Message,
+          message,
+          db_skip_write: false,
+        };
+        self.state.publish(room_id, &ws_msg);
+      }
+    }
+  }
+
+  async fn subscribe(&mut self, room_id: i64) {
+    if !self.subscriptions.contains_key(&room_id) {
+      let joined = resolve_room_member(&self.state.repos, room_id, self.user_id).await;
+      let (room, member) = match joined {
+        Ok(joined) => joined,
+        Err(e) => {
+          self
+            .send(ServerFrame::Error {
+              room_id: Some(room_id),
+              code: e.code(),
+              message: e.message().to_owned(),
+              retry_after: None,
+            })
+            .await;
+          return;
+        }
+      };
+      let tx = self.state.add_client(room.id, room.name, self.user_id);
+      let forwarder = create_room_forwarder(
+        room_id,
+        tx.subscribe(),
+        member.id,
+        self.user_id,
+        self.out_tx.clone(),
+        self.removed_tx.clone(),
+        self.state.clone(),
+      );
+      self
+        .subscriptions
+        .insert(room_id, Subscription { member, forwarder });
+    }
+    self.send(ServerFrame::Subscribed { room_id }).await;
+  }
+
+  fn unsubscribe(&mut self, room_id: i64, reason: ServerTaskTerminationReason) {
+    let subscription = match self.subscriptions.remove(&room_id) {
+      Some(subscription) => subscription,
+      None => return,
+    };
+    subscription.forwarder.abort();
+    leave_room_state(
+      &self.state,
+      subscription.member.id,
+      self.user_id,
+      room_id,
+      &self.user_name,
+      Some(reason),
+    );
+  }
+
+  async fn send(&self, frame: ServerFrame) {
+    // a closed outbound channel ends the session on its own
+    let _ = self
+      .out_tx
+      .send(Message::Text(serde_json::to_string(&frame).unwrap()))
+      .await;
+  }
+}
+
+/// Forwards the messages of one room to the shared outbound channel of the socket.
+fn create_room_forwarder(
+  room_id: i64,
+  mut rx: broadcast::Receiver<String>,
+  member_id: i64,
+  user_id: i64,
+  out_tx: mpsc::Sender<Message>,
+  removed_tx: mpsc::UnboundedSender<i64>,
+  state: Arc<Lobby>,
+) -> JoinHandle<()> {
+  tokio::spawn(
+    async move {
+      loop {
+        let msg = match rx.recv().await {
+          Ok(msg) => msg,
+          Err(RecvError::Lagged(skipped)) => {
+            state.metrics.broadcast_lag("subscription");
+            warn!(
+              skipped,
+              "room subscription lagged behind, messages were skipped"
+            );
+            continue;
+          }
+          Err(RecvError::Closed) => return,
+        };
+        let m = match serde_json::from_str::<ClientWsMessage>(&msg) {
+          Ok(m) => m,
+          Err(_) => {
+            warn!("error parsing room event");
+            continue;
+          }
+        };
+        let frame = match m.message_type {
+          ClientWsMessageType::Leave if m.member_id == member_id => {
+            let frame = ServerFrame::Leave {
+              room_id,
+              message: m.message,
+            };
+            let _ = out_tx
+              .send(Message::Text(serde_json::to_string(&frame).unwrap()))
+              .await;
+            let _ = removed_tx.send(room_id);
+            return;
+          }
+          // skipping the member's own messages and those of blocked users like the
+          // single room socket does
+          ClientWsMessageType::Message
+            if m.member_id != member_id && !state.is_blocked(user_id, m.user_id) =>
+          {
+            ServerFrame::Message {
+              room_id,
+              member_id: m.member_id,
+              member_name: m.member_name,
+              message: m.message,
+            }
+          }
+          _ => continue,
+        };
+        if out_tx
+          .send(Message::Text(serde_json::to_string(&frame).unwrap()))
+          .await
+          .is_err()
+        {
+          return;
+        }
+      }
+    }
+    .instrument(info_span!("room_subscription", room_id, member_id)),
+  )
+}
+
+fn closed_by_server(reason: ServerTaskTerminationReason) -> bool {
+  matches!(
+    reason,
+    ServerTaskTerminationReason::HeartbeatTimeout
+      | ServerTaskTerminationReason::IdleTimeout
+      | ServerTaskTerminationReason::ServerShutdown
+      | ServerTaskTerminationReason::SessionRevoked
+  )
+}
+
+fn create_outbound_task(
+  mut sender: SplitSink<WebSocket, Message>,
+  mut out_rx: mpsc::Receiver<Message>,
+) -> JoinHandle<()> {
+  tokio::spawn(
+    async move {
+      while let Some(msg) = out_rx.recv().await {
+        let is_close = matches!(msg, Message::Close(_));
+        if sender.send(msg).await.is_err() || is_close {
+          break;
+        }
+      }
+    }
+    .in_current_span(),
+  )
+}
@@ -0,0 +1,100 @@
+mod support;
+
+use std::time::Duration;
+use support::{TestApp, TestUser};
+
+// Messages are saved in the background, so the export is polled until it has them.
+async fn export_with_messages(user: &TestUser, count: usize) -> serde_json::Value {
+  tokio::time::timeout(Duration::from_secs(5), async {
+    loop {
+      let export = user.export().await.unwrap();
+      if export["messages"].as_array().unwrap().len() >= count {
+        return export;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .expect("messages were not saved")
+}
+
+#[tokio::test]
+async fn export_holds_profile_memberships_and_messages() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let room = user.create_room("general").await.unwrap();
+  let mut socket = user.join(room.room_id).await.unwrap();
+  socket.send_text("hello").await;
+
+  let export = export_with_messages(&user, 1).await;
+  assert_eq!(export["profile"]["id"], user.id);
+  assert_eq!(export["profile"]["name"], user.name.as_str());
+  assert_eq!(export["profile"]["twoFactorEnabled"], false);
+  let memberships = export["memberships"].as_array().unwrap();
+  assert_eq!(memberships.len(), 1);
+  assert_eq!(memberships[0]["roomId"], room.room_id);
+  assert_eq!(memberships[0]["owner"], true);
+  assert_eq!(export["messages"][0]["roomId"], room.room_id);
+  assert_eq!(export["messages"][0]["message"], "hello");
+}
+
+scenario_tests!(deleting_an_account_hands_over_its_rooms, delete_account);
+
+async fn delete_account(app: TestApp) {
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let shared = owner.create_room("shared").await.unwrap();
+  let lonely = owner.create_room("lonely").await.unwrap();
+  let mut user_socket = user.join(shared.room_id).await.unwrap();
+  let mut owner_socket = owner.join(shared.room_id).await.unwrap();
+  app.wait_for_socket(shared.room_id, owner.id).await;
+  owner_socket.send_text("bye").await;
+  assert_eq!(user_socket.next_text().await, "bye");
+  export_with_messages(&owner, 1).await;
+
+  let err = owner.delete_account("not the password").await.unwrap_err();
+  assert_eq!(err.status, 403);
+  assert_eq!(err.code, "WRONG_PASSWORD");
+
+  let deleted = owner.delete_account("password").await.unwrap();
+  assert_eq!(deleted["transferredRooms"][0]["roomId"], shared.room_id);
+  assert_eq!(deleted["transferredRooms"][0]["newOwnerId"], user.id);
+  assert_eq!(deleted["deletedRooms"], serde_json::json!([lonely.room_id]));
+  // the leave notice races with the revoked session, either one closes the socket
+  owner_socket
+    .expect_closed_after("account was deleted")
+    .await;
+  app.wait_for_disconnect(owner.id).await;
+
+  assert_eq!(owner.me().await.unwrap_err().code, "SESSION_REVOKED");
+  assert_eq!(
+    app.login(&owner.name, "password").await.unwrap_err().code,
+    "INVALID_CREDENTIALS"
+  );
+  // the deleted account cannot be reached by its id anymore
+  assert_eq!(
+    user.user(owner.id).await.unwrap_err().code,
+    "USER_NOT_FOUND"
+  );
+  let err = user.open_direct_room(owner.id).await.unwrap_err();
+  assert_eq!(err.status, 404);
+  assert_eq!(err.code, "USER_NOT_FOUND");
+  assert_eq!(
+    user.block(owner.id).await.unwrap_err().code,
+    "USER_NOT_FOUND"
+  );
+  assert_eq!(
+    user
+      .invite(shared.room_id, owner.id)
+      .await
+      .unwrap_err()
+      .code,
+    "USER_NOT_FOUND"
+  );
+
+  // the new owner runs the room
+  let other = app.signup("other").await;
+  other.join(shared.room_id).await.unwrap().close().await;
+  user.remove(shared.room_id, &other.name).await.unwrap();
+  app.stop().await;
+}
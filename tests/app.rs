@@ -0,0 +1,38 @@
+mod support;
+
+use rust_tokio_chat_app::Config;
+use support::TestApp;
+
+#[tokio::test]
+async fn route_groups_are_enabled_by_default() {
+  let app = TestApp::spawn().await;
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+
+  let room = alice.open_direct_room(bob.id).await.unwrap();
+  assert_eq!(room.other_user_id, bob.id);
+  assert!(alice.connect().await.is_ok());
+  assert!(alice.search("bob").await.is_ok());
+  assert!(alice.user(bob.id).await.is_ok());
+}
+
+#[tokio::test]
+async fn disabled_route_groups_are_not_served() {
+  let mut config = Config::in_memory();
+  config.routes.direct_messages = false;
+  config.routes.multiplexed_socket = false;
+  config.routes.search = false;
+  let app = TestApp::spawn_with_config(config).await;
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+
+  assert_eq!(
+    alice.open_direct_room(bob.id).await.unwrap_err().status,
+    404
+  );
+  assert_eq!(alice.connect().await.unwrap_err().status, 404);
+  assert_eq!(alice.search("bob").await.unwrap_err().status, 404);
+  assert_eq!(alice.user(bob.id).await.unwrap_err().status, 404);
+  // the core routes stay available
+  assert!(alice.create_room("general").await.is_ok());
+}
@@ -0,0 +1,238 @@
+mod support;
+
+use support::TestApp;
+
+scenario_tests!(blocked_users_are_listed_and_unblocked, blocks_are_managed);
+
+async fn blocks_are_managed(app: TestApp) {
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+
+  let blocked = alice.block(bob.id).await.unwrap();
+  assert_eq!(blocked["userId"], bob.id);
+  // blocking twice keeps the first block
+  let again = alice.block(bob.id).await.unwrap();
+  assert_eq!(again["blockedAt"], blocked["blockedAt"]);
+  let blocks = alice.blocks().await.unwrap();
+  assert_eq!(blocks["blocks"].as_array().unwrap().len(), 1);
+  assert_eq!(blocks["blocks"][0]["name"], bob.name.as_str());
+  assert!(bob.blocks().await.unwrap()["blocks"]
+    .as_array()
+    .unwrap()
+    .is_empty());
+
+  assert_eq!(alice.block(alice.id).await.unwrap_err().status, 400);
+  assert_eq!(alice.block(-1).await.unwrap_err().code, "USER_NOT_FOUND");
+
+  alice.unblock(bob.id).await.unwrap();
+  assert!(alice.blocks().await.unwrap()["blocks"]
+    .as_array()
+    .unwrap()
+    .is_empty());
+  assert_eq!(
+    alice.unblock(bob.id).await.unwrap_err().code,
+    "BLOCK_NOT_FOUND"
+  );
+  app.stop().await;
+}
+
+scenario_tests!(
+  direct_messages_are_refused_both_ways,
+  direct_messages_are_refused
+);
+
+async fn direct_messages_are_refused(app: TestApp) {
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+  let room = alice.open_direct_room(bob.id).await.unwrap();
+
+  alice.block(bob.id).await.unwrap();
+  for (user, other) in [(&alice, &bob), (&bob, &alice)] {
+    let err = user.open_direct_room(other.id).await.unwrap_err();
+    assert_eq!(err.status, 403);
+    assert_eq!(err.code, "USER_BLOCKED");
+    assert_eq!(
+      user.join(room.room_id).await.unwrap_err().code,
+      "USER_BLOCKED"
+    );
+  }
+
+  // an existing direct room cannot be subscribed to either
+  let mut socket = bob.connect().await.unwrap();
+  socket
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": room.room_id }))
+    .await;
+  let error = socket.next_json().await;
+  assert_eq!(error["type"], "error");
+  assert_eq!(error["code"], "USER_BLOCKED");
+
+  alice.unblock(bob.id).await.unwrap();
+  bob.open_direct_room(alice.id).await.unwrap();
+  app.stop().await;
+}
+
+scenario_tests!(history_leaves_out_blocked_users, history_is_filtered);
+
+async fn history_is_filtered(app: TestApp) {
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+  let room = alice.create_room("general").await.unwrap();
+  let mut alice_socket = alice.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, alice.id).await;
+  let mut bob_socket = bob.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, bob.id).await;
+  for text in ["one", "two", "three"] {
+    bob_socket.send_text(text).await;
+    assert_eq!(alice_socket.next_text().await, text);
+  }
+  alice_socket.send_text("four").await;
+  assert_eq!(bob_socket.next_text().await, "four");
+
+  let history = alice.messages(room.room_id, None).await.unwrap();
+  let texts: Vec<&str> = history["messages"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|m| m["message"].as_str().unwrap())
+    .collect();
+  assert_eq!(texts, ["one", "two", "three", "four"]);
+  assert_eq!(history["messages"][0]["userId"], bob.id);
+  let before = history["messages"][2]["id"].as_i64().unwrap();
+  let page = alice.messages(room.room_id, Some(before)).await.unwrap();
+  assert_eq!(page["messages"].as_array().unwrap().len(), 2);
+  assert_eq!(page["messages"][1]["message"], "two");
+
+  alice.block(bob.id).await.unwrap();
+  let history = alice.messages(room.room_id, None).await.unwrap();
+  assert_eq!(history["messages"].as_array().unwrap().len(), 1);
+  assert_eq!(history["messages"][0]["message"], "four");
+  // only the one who blocked gets a filtered history
+  let history = bob.messages(room.room_id, None).await.unwrap();
+  assert_eq!(history["messages"].as_array().unwrap().len(), 4);
+
+  let carol = app.signup("carol").await;
+  assert_eq!(
+    carol.messages(room.room_id, None).await.unwrap_err().code,
+    "NOT_A_MEMBER"
+  );
+  app.stop().await;
+}
+
+scenario_tests!(invites_are_refused_both_ways, invites_are_refused);
+
+async fn invites_are_refused(app: TestApp) {
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+  let carol = app.signup("carol").await;
+  let alice_room = alice.create_room("alice's").await.unwrap();
+  let bob_room = bob.create_room("bob's").await.unwrap();
+
+  alice.block(bob.id).await.unwrap();
+  let err = bob.invite(bob_room.room_id, alice.id).await.unwrap_err();
+  assert_eq!(err.status, 403);
+  assert_eq!(err.code, "USER_BLOCKED");
+  assert_eq!(
+    alice
+      .invite(alice_room.room_id, bob.id)
+      .await
+      .unwrap_err()
+      .code,
+    "USER_BLOCKED"
+  );
+
+  let invited = bob.invite(bob_room.room_id, carol.id).await.unwrap();
+  assert_eq!(invited["userId"], carol.id);
+  assert_eq!(
+    bob
+      .invite(bob_room.room_id, carol.id)
+      .await
+      .unwrap_err()
+      .code,
+    "CONFLICT"
+  );
+  // the invite made carol a member, so she can read the history
+  carol.messages(bob_room.room_id, None).await.unwrap();
+  assert_eq!(
+    alice
+      .invite(bob_room.room_id, carol.id)
+      .await
+      .unwrap_err()
+      .code,
+    "NOT_A_MEMBER"
+  );
+
+  alice.unblock(bob.id).await.unwrap();
+  bob.invite(bob_room.room_id, alice.id).await.unwrap();
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn messages_of_blocked_users_are_not_delivered() {
+  let app = TestApp::spawn().await;
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+  let carol = app.signup("carol").await;
+  let room = carol.create_room("general").await.unwrap();
+
+  let mut carol_socket = carol.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, carol.id).await;
+  let mut bob_socket = bob.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, bob.id).await;
+  let mut alice_socket = alice.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, alice.id).await;
+  let mut alice_multiplexed = alice.connect().await.unwrap();
+  alice_multiplexed
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": room.room_id }))
+    .await;
+  assert_eq!(alice_multiplexed.next_json().await["type"], "subscribed");
+
+  // the block reaches the sockets that are already open
+  alice.block(bob.id).await.unwrap();
+  bob_socket.send_text("from bob").await;
+  assert_eq!(carol_socket.next_text().await, "from bob");
+  carol_socket.send_text("from carol").await;
+  assert_eq!(alice_socket.next_text().await, "from carol");
+  let message = alice_multiplexed.next_json().await;
+  assert_eq!(message["type"], "message");
+  assert_eq!(message["message"], "from carol");
+
+  alice.unblock(bob.id).await.unwrap();
+  bob_socket.send_text("back again").await;
+  assert_eq!(alice_socket.next_text().await, "back again");
+  assert_eq!(alice_multiplexed.next_json().await["message"], "back again");
+  app.stop().await;
+}
+
+#[tokio::test]
+#[ignore = "needs the Postgres server configured in .env"]
+async fn blocks_reach_sockets_on_every_instance_on_postgres() {
+  let (first, second) = TestApp::spawn_postgres_cluster().await;
+  let alice = first.signup("alice").await;
+  let bob = first.signup("bob").await;
+  let carol = first.signup("carol").await;
+  let room = carol.create_room("general").await.unwrap();
+
+  let mut alice_socket = alice.on(&second).join(room.room_id).await.unwrap();
+  second.wait_for_socket(room.room_id, alice.id).await;
+  let mut bob_socket = bob.join(room.room_id).await.unwrap();
+  first.wait_for_socket(room.room_id, bob.id).await;
+  let mut carol_socket = carol.join(room.room_id).await.unwrap();
+  first.wait_for_socket(room.room_id, carol.id).await;
+  // a message from the first instance proves the second one listens on the bus
+  bob_socket.send_text("hello").await;
+  assert_eq!(alice_socket.next_text().await, "hello");
+  assert_eq!(carol_socket.next_text().await, "hello");
+
+  // the block goes through the first instance, the socket of alice lives on the second
+  alice.block(bob.id).await.unwrap();
+  bob_socket.send_text("from bob").await;
+  assert_eq!(carol_socket.next_text().await, "from bob");
+  carol_socket.send_text("from carol").await;
+  assert_eq!(alice_socket.next_text().await, "from carol");
+
+  alice.unblock(bob.id).await.unwrap();
+  bob_socket.send_text("back again").await;
+  assert_eq!(alice_socket.next_text().await, "back again");
+  second.stop().await;
+  first.stop().await;
+}
@@ -0,0 +1,100 @@
+mod support;
+
+use chrono::Utc;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use rust_tokio_chat_app::auth::keys::{DEFAULT_AUDIENCE, DEFAULT_ISSUER};
+use support::TestApp;
+
+#[tokio::test]
+async fn errors_carry_a_code_and_the_request_id() {
+  let app = TestApp::spawn().await;
+  let response = reqwest::Client::new()
+    .get(format!("http://{}/rooms/join/1", app.addr))
+    .header("x-request-id", "req-42")
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 401);
+  assert_eq!(response.headers()["x-request-id"], "req-42");
+  let body: serde_json::Value = response.json().await.unwrap();
+  assert_eq!(body["code"], "UNAUTHORIZED");
+  assert_eq!(body["requestId"], "req-42");
+  assert!(body["message"].is_string());
+}
+
+#[tokio::test]
+async fn unknown_room_is_not_found() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let err = user.join(4242).await.unwrap_err();
+  assert_eq!(err.status, 404);
+  assert_eq!(err.code, "ROOM_NOT_FOUND");
+  assert!(!err.request_id.is_empty());
+}
+
+#[tokio::test]
+async fn taken_name_is_a_conflict() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let err = app.try_signup(&user.name).await.unwrap_err();
+  assert_eq!(err.status, 409);
+  assert_eq!(err.code, "NAME_TAKEN");
+}
+
+#[tokio::test]
+async fn leaving_a_room_without_membership_is_forbidden() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+  let err = user.leave(room.room_id).await.unwrap_err();
+  assert_eq!(err.status, 403);
+  assert_eq!(err.code, "NOT_A_MEMBER");
+}
+
+#[tokio::test]
+async fn expired_token_is_reported() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let issued_at = Utc::now().timestamp() - 7200;
+  let token = encode(
+    &Header::default(),
+    &serde_json::json!({
+      "userId": user.id,
+      "sessionId": 1,
+      "iat": issued_at,
+      "exp": issued_at + 3600,
+      "iss": DEFAULT_ISSUER,
+      "aud": DEFAULT_AUDIENCE,
+    }),
+    &EncodingKey::from_secret(b"secret"),
+  )
+  .unwrap();
+  let response = reqwest::Client::new()
+    .get(format!("http://{}/rooms", app.addr))
+    .bearer_auth(token)
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 401);
+  let body: serde_json::Value = response.json().await.unwrap();
+  assert_eq!(body["code"], "TOKEN_EXPIRED");
+}
+
+#[tokio::test]
+async fn socket_error_frames_use_the_same_codes() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let mut socket = user.connect().await.unwrap();
+
+  socket
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": 4242 }))
+    .await;
+  let error = socket.next_json().await;
+  assert_eq!(error["type"], "error");
+  assert_eq!(error["roomId"], 4242);
+  assert_eq!(error["code"], "ROOM_NOT_FOUND");
+
+  socket.send_text("not json").await;
+  assert_eq!(socket.next_json().await["code"], "INVALID_FRAME");
+}
@@ -0,0 +1,104 @@
+mod support;
+
+use rust_tokio_chat_app::{serve, Config};
+use std::net::TcpListener;
+use std::time::Duration;
+use support::TestApp;
+use tokio::sync::oneshot;
+
+#[tokio::test]
+async fn liveness_answers_ok() {
+  let app = TestApp::spawn().await;
+  let (status, body) = app.get_json("/health/live").await;
+  assert_eq!(status, 200);
+  assert_eq!(body["status"], "ok");
+}
+
+#[tokio::test]
+async fn readiness_reports_lobby_stats() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let room = owner.create_room("general").await.unwrap();
+  let _socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+
+  let (status, body) = app.get_json("/health/ready").await;
+  assert_eq!(status, 200);
+  assert_eq!(body["status"], "ready");
+  assert_eq!(body["shuttingDown"], false);
+  assert_eq!(body["database"], "inMemory");
+  assert_eq!(body["lobby"]["activeRooms"], 1);
+  assert_eq!(body["lobby"]["sockets"], 1);
+  assert_eq!(body["lobby"]["onlineUsers"], 1);
+}
+
+#[tokio::test]
+async fn not_ready_while_shutting_down() {
+  let app = TestApp::spawn().await;
+  app.lobby.close_sockets(Duration::from_secs(5));
+
+  let (status, body) = app.get_json("/health/ready").await;
+  assert_eq!(status, 503);
+  assert_eq!(body["status"], "notReady");
+  assert_eq!(body["shuttingDown"], true);
+  // the process itself is still alive
+  let (status, _) = app.get_json("/health/live").await;
+  assert_eq!(status, 200);
+}
+
+#[tokio::test]
+#[ignore = "needs the Postgres server configured in .env"]
+async fn readiness_checks_the_database() {
+  let app = TestApp::spawn_postgres().await;
+  let (status, body) = app.get_json("/health/ready").await;
+  assert_eq!(status, 200);
+  assert_eq!(body["database"], "ok");
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn not_ready_during_the_drain_grace_period() {
+  let mut config = Config::in_memory();
+  config.addr = TcpListener::bind("127.0.0.1:0")
+    .unwrap()
+    .local_addr()
+    .unwrap();
+  config.drain_grace = Duration::from_secs(2);
+  let url = format!("http://{}/health/ready", config.addr);
+  let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+  let server = tokio::spawn(serve(config, async {
+    shutdown_rx.await.ok();
+  }));
+  let client = reqwest::Client::new();
+  let ready = tokio::time::timeout(Duration::from_secs(5), async {
+    loop {
+      if let Ok(response) = client.get(&url).send().await {
+        return response;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .expect("server did not start");
+  assert_eq!(ready.status(), 200);
+
+  shutdown_tx.send(()).unwrap();
+  let not_ready = tokio::time::timeout(Duration::from_secs(1), async {
+    loop {
+      let response = client.get(&url).send().await.unwrap();
+      if response.status() != 200 {
+        return response;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .expect("server stayed ready");
+  assert_eq!(not_ready.status(), 503);
+  let body: serde_json::Value = not_ready.json().await.unwrap();
+  assert_eq!(body["shuttingDown"], true);
+
+  // the listener closes once the grace period is over
+  server.await.unwrap().unwrap();
+  assert!(client.get(&url).send().await.is_err());
+}
@@ -0,0 +1,23 @@
+use rust_tokio_chat_app::ws::heartbeat::HeartbeatConfig;
+use std::panic::catch_unwind;
+use std::time::Duration;
+
+// The env variables are process wide, so every case runs in this single test.
+#[test]
+fn zero_ping_interval_and_missed_pongs_are_rejected() {
+  std::env::set_var("WS_PING_INTERVAL_SECS", "0");
+  assert!(catch_unwind(HeartbeatConfig::from_env).is_err());
+  std::env::remove_var("WS_PING_INTERVAL_SECS");
+
+  std::env::set_var("WS_MAX_MISSED_PONGS", "0");
+  assert!(catch_unwind(HeartbeatConfig::from_env).is_err());
+  std::env::remove_var("WS_MAX_MISSED_PONGS");
+
+  std::env::set_var("WS_PING_INTERVAL_SECS", "1");
+  std::env::set_var("WS_MAX_MISSED_PONGS", "1");
+  std::env::set_var("WS_IDLE_TIMEOUT_SECS", "0");
+  let config = HeartbeatConfig::from_env();
+  assert_eq!(config.ping_interval, Duration::from_secs(1));
+  assert_eq!(config.max_missed_pongs, 1);
+  assert!(config.idle_timeout.is_none());
+}
@@ -0,0 +1,111 @@
+mod support;
+
+use chrono::Utc;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rust_tokio_chat_app::auth::keys::{JwtKeys, DEFAULT_AUDIENCE, DEFAULT_ISSUER};
+use rust_tokio_chat_app::auth::AuthConfig;
+use rust_tokio_chat_app::Config;
+use std::sync::Arc;
+use support::{TestApp, TestUser};
+
+const RSA_KEY: &[u8] = include_bytes!("keys/rsa.pem");
+const ED25519_KEY: &[u8] = include_bytes!("keys/ed25519.pem");
+const NEXT_ED25519_KEY: &[u8] = include_bytes!("keys/ed25519_next.pem");
+
+async fn spawn_with_keys(keys: JwtKeys) -> TestApp {
+  let mut config = Config::in_memory();
+  config.state.auth = Arc::new(AuthConfig {
+    keys: Arc::new(keys),
+    ..AuthConfig::default()
+  });
+  TestApp::spawn_with_config(config).await
+}
+
+async fn jwks(app: &TestApp) -> JwkSet {
+  let (status, body) = app.get_json("/.well-known/jwks.json").await;
+  assert_eq!(status, 200);
+  serde_json::from_value(body).unwrap()
+}
+
+// Signs claims for the user's current session as another issuer of tokens would.
+async fn mint_token(keys: &JwtKeys, user: &TestUser) -> String {
+  let sessions = user.sessions().await.unwrap();
+  let now = Utc::now().timestamp();
+  keys
+    .encode(&serde_json::json!({
+      "userId": user.id,
+      "sessionId": sessions["sessions"][0]["id"],
+      "iat": now,
+      "exp": now + 60,
+      "iss": keys.issuer,
+      "aud": keys.audience,
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn shared_secrets_are_not_published() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+
+  let header = decode_header(&user.token).unwrap();
+  assert_eq!(header.alg, Algorithm::HS256);
+  assert!(header.kid.is_some());
+  assert!(jwks(&app).await.keys.is_empty());
+}
+
+#[tokio::test]
+async fn rs256_tokens_verify_against_the_published_keys() {
+  let app = spawn_with_keys(JwtKeys::from_pem(RSA_KEY).unwrap()).await;
+  let user = app.signup("user").await;
+  assert!(user.me().await.is_ok());
+
+  let header = decode_header(&user.token).unwrap();
+  assert_eq!(header.alg, Algorithm::RS256);
+  let jwks = jwks(&app).await;
+  let jwk = jwks.find(&header.kid.unwrap()).unwrap();
+  let mut validation = Validation::new(Algorithm::RS256);
+  validation.set_issuer(&[DEFAULT_ISSUER]);
+  validation.set_audience(&[DEFAULT_AUDIENCE]);
+  let claims = decode::<serde_json::Value>(
+    &user.token,
+    &DecodingKey::from_jwk(jwk).unwrap(),
+    &validation,
+  )
+  .unwrap()
+  .claims;
+  assert_eq!(claims["userId"], user.id);
+}
+
+#[tokio::test]
+async fn rotated_keys_keep_verifying_issued_tokens() {
+  let previous = JwtKeys::from_pem(ED25519_KEY).unwrap();
+  let keys = JwtKeys::from_pem(NEXT_ED25519_KEY)
+    .unwrap()
+    .with_verification_keys(&previous.jwks())
+    .unwrap();
+  let app = spawn_with_keys(keys).await;
+  let mut user = app.signup("user").await;
+  assert_eq!(decode_header(&user.token).unwrap().alg, Algorithm::EdDSA);
+  assert_eq!(jwks(&app).await.keys.len(), 2);
+
+  user.token = mint_token(&previous, &user).await;
+  assert!(user.me().await.is_ok());
+
+  user.token = mint_token(&JwtKeys::default(), &user).await;
+  assert_eq!(user.me().await.unwrap_err().code, "UNAUTHORIZED");
+}
+
+#[tokio::test]
+async fn tokens_for_another_audience_are_rejected() {
+  let app = TestApp::spawn().await;
+  let mut user = app.signup("user").await;
+  let mut keys = JwtKeys::default();
+  keys.audience = "another-service".to_owned();
+
+  user.token = mint_token(&keys, &user).await;
+  let err = user.me().await.unwrap_err();
+  assert_eq!(err.status, 401);
+  assert_eq!(err.code, "UNAUTHORIZED");
+}
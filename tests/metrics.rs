@@ -0,0 +1,66 @@
+mod support;
+
+use support::TestApp;
+
+/// Returns the value of the sample written exactly as `series` in the scrape.
+fn sample(metrics: &str, series: &str) -> Option<f64> {
+  metrics.lines().find_map(|line| {
+    let value = line.strip_prefix(series)?.strip_prefix(' ')?;
+    value.parse().ok()
+  })
+}
+
+#[tokio::test]
+async fn counts_sockets_and_messages() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+  let mut owner_socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+  let mut user_socket = user.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+
+  let metrics = app.metrics().await;
+  assert_eq!(sample(&metrics, "chat_sockets"), Some(2.0));
+  assert_eq!(sample(&metrics, "chat_active_rooms"), Some(1.0));
+  let room_sockets = format!("chat_room_sockets{{room_id=\"{}\"}}", room.room_id);
+  assert_eq!(sample(&metrics, &room_sockets), Some(2.0));
+
+  user_socket.send_text("hello").await;
+  assert_eq!(owner_socket.next_text().await, "hello");
+  // the message is only delivered once it is saved
+  let metrics = app.metrics().await;
+  assert_eq!(sample(&metrics, "chat_messages_persisted_total"), Some(1.0));
+  assert_eq!(sample(&metrics, "chat_messages_received_total"), Some(1.0));
+  assert_eq!(
+    sample(&metrics, "chat_persistence_failures_total"),
+    Some(0.0)
+  );
+}
+
+#[tokio::test]
+async fn counts_auth_failures_and_request_latency_per_route() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  owner.create_room("general").await.unwrap();
+  let err = app.login(&owner.name, "wrong").await.unwrap_err();
+  assert_eq!(err.status, 401);
+  assert_eq!(err.code, "INVALID_CREDENTIALS");
+
+  let metrics = app.metrics().await;
+  assert_eq!(
+    sample(
+      &metrics,
+      "chat_auth_failures_total{reason=\"invalid_credentials\"}"
+    ),
+    Some(1.0)
+  );
+  assert_eq!(
+    sample(
+      &metrics,
+      "http_request_duration_seconds_count{method=\"POST\",route=\"/rooms/create\",status=\"200\"}"
+    ),
+    Some(1.0)
+  );
+}
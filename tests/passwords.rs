@@ -0,0 +1,108 @@
+mod support;
+
+use support::TestApp;
+
+// Picks the reset token out of the mail, it is the only line of its own.
+fn reset_token(mail: &str) -> String {
+  mail
+    .lines()
+    .map(str::trim)
+    .find(|line| line.len() > 20 && !line.contains(' '))
+    .expect("mail without a token")
+    .to_owned()
+}
+
+#[tokio::test]
+async fn changing_the_password_signs_out_other_sessions() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let other = app.login(&user.name, "password").await.unwrap();
+
+  let err = user
+    .change_password("not the password", "new password")
+    .await
+    .unwrap_err();
+  assert_eq!(err.status, 403);
+  assert_eq!(err.code, "WRONG_PASSWORD");
+  let err = user.change_password("password", "short").await.unwrap_err();
+  assert_eq!(err.status, 400);
+  assert_eq!(err.code, "WEAK_PASSWORD");
+
+  let changed = user
+    .change_password("password", "new password")
+    .await
+    .unwrap();
+  assert_eq!(changed["revokedSessions"], 1);
+  assert!(user.me().await.is_ok());
+  assert_eq!(other.me().await.unwrap_err().code, "SESSION_REVOKED");
+  assert_eq!(
+    app.login(&user.name, "password").await.unwrap_err().code,
+    "INVALID_CREDENTIALS"
+  );
+  assert!(app.login(&user.name, "new password").await.is_ok());
+}
+
+scenario_tests!(reset_token_works_once, reset_password);
+
+async fn reset_password(app: TestApp) {
+  let email = format!("user.{}@example.com", app.addr.port());
+  let user = app.signup_with_email("user", &email).await.unwrap();
+  assert_eq!(user.me().await.unwrap()["email"], email.as_str());
+  let err = app
+    .signup_with_email("other", &email.to_uppercase())
+    .await
+    .unwrap_err();
+  assert_eq!(err.code, "EMAIL_TAKEN");
+
+  // addresses are matched case-insensitively, a newer token does not void older ones
+  app
+    .request_password_reset(&email.to_uppercase())
+    .await
+    .unwrap();
+  let first = reset_token(&app.mail_to(&email).await);
+  app.request_password_reset(&email).await.unwrap();
+  let mail = app.mail_to(&email).await;
+  assert!(mail.contains("Subject: Reset your password"));
+  let second = reset_token(&mail);
+  assert_ne!(first, second);
+
+  let err = app.reset_password(&first, "short").await.unwrap_err();
+  assert_eq!(err.code, "WEAK_PASSWORD");
+  let reset = app.reset_password(&first, "new password").await.unwrap();
+  assert_eq!(reset["id"], user.id);
+  assert_eq!(reset["revokedSessions"], 1);
+  assert_eq!(user.me().await.unwrap_err().code, "SESSION_REVOKED");
+  assert!(app.login(&user.name, "new password").await.is_ok());
+
+  // using a token voids every other token of the user
+  for token in [&first, &second] {
+    let err = app
+      .reset_password(token, "newer password")
+      .await
+      .unwrap_err();
+    assert_eq!(err.status, 400);
+    assert_eq!(err.code, "INVALID_RESET_TOKEN");
+  }
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn unknown_addresses_get_the_same_answer() {
+  let app = TestApp::spawn().await;
+  let email = "known@example.com";
+  app.signup_with_email("user", email).await.unwrap();
+
+  let known = app.request_password_reset(email).await.unwrap();
+  let unknown = app
+    .request_password_reset("unknown@example.com")
+    .await
+    .unwrap();
+  assert_eq!(known, unknown);
+  app.mail_to(email).await;
+
+  let err = app
+    .request_password_reset("not an address")
+    .await
+    .unwrap_err();
+  assert_eq!(err.code, "BAD_REQUEST");
+}
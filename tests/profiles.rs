@@ -0,0 +1,184 @@
+mod support;
+
+use std::time::Duration;
+use support::{TestApp, TestUser};
+
+// Presence is saved in the background, so the profile is polled until it shows the status.
+async fn wait_for_status(viewer: &TestUser, user_id: i64, status: &str) {
+  tokio::time::timeout(Duration::from_secs(5), async {
+    // slow enough to stay below the rate limit of the viewer
+    while viewer.user(user_id).await.unwrap()["status"] != status {
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+  })
+  .await
+  .unwrap_or_else(|_| panic!("user {} did not turn {}", user_id, status));
+}
+
+scenario_tests!(profile_fields_can_be_set_and_cleared, profile_edits);
+
+async fn profile_edits(app: TestApp) {
+  let user = app.signup("user").await;
+  let other = app.signup("other").await;
+  let me = user.me().await.unwrap();
+  assert_eq!(me["displayName"], user.name.as_str());
+  assert_eq!(me["bio"], serde_json::Value::Null);
+
+  let updated = user
+    .update_profile(serde_json::json!({
+      "displayName": "  Ada Lovelace ",
+      "bio": "Writes the first programs",
+      "avatarUrl": "https://example.com/ada.png",
+    }))
+    .await
+    .unwrap();
+  assert_eq!(updated["name"], user.name.as_str());
+  assert_eq!(updated["displayName"], "Ada Lovelace");
+  assert_eq!(updated["bio"], "Writes the first programs");
+  assert_eq!(updated["avatarUrl"], "https://example.com/ada.png");
+
+  // fields left out stay, empty ones are cleared
+  let updated = user
+    .update_profile(serde_json::json!({ "bio": "" }))
+    .await
+    .unwrap();
+  assert_eq!(updated["displayName"], "Ada Lovelace");
+  assert_eq!(updated["bio"], serde_json::Value::Null);
+
+  let err = user
+    .update_profile(serde_json::json!({ "name": other.name }))
+    .await
+    .unwrap_err();
+  assert_eq!(err.status, 409);
+  assert_eq!(err.code, "NAME_TAKEN");
+  let err = user
+    .update_profile(serde_json::json!({ "avatarUrl": "javascript:alert(1)" }))
+    .await
+    .unwrap_err();
+  assert_eq!(err.code, "BAD_REQUEST");
+
+  let new_name = format!("{}_renamed", user.name);
+  let renamed = user
+    .update_profile(serde_json::json!({ "name": new_name }))
+    .await
+    .unwrap();
+  assert_eq!(renamed["name"], new_name.as_str());
+  assert!(app.login(&new_name, "password").await.is_ok());
+  assert_eq!(
+    app.login(&user.name, "password").await.unwrap_err().code,
+    "INVALID_CREDENTIALS"
+  );
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn messages_carry_the_current_display_name() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  owner
+    .update_profile(serde_json::json!({ "displayName": "The Owner" }))
+    .await
+    .unwrap();
+  let room = owner.create_room("general").await.unwrap();
+
+  let mut socket = user.connect().await.unwrap();
+  socket
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": room.room_id }))
+    .await;
+  assert_eq!(socket.next_json().await["type"], "subscribed");
+  let mut owner_socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+
+  owner_socket.send_text("hello").await;
+  let message = socket.next_json().await;
+  assert_eq!(message["memberName"], "The Owner");
+
+  // renaming applies to the sockets already open
+  owner
+    .update_profile(serde_json::json!({ "displayName": "" }))
+    .await
+    .unwrap();
+  owner_socket.send_text("hello again").await;
+  let message = socket.next_json().await;
+  assert_eq!(message["memberName"], owner.name);
+  assert_eq!(message["message"], "hello again");
+}
+
+#[tokio::test]
+#[ignore = "needs the Postgres server configured in .env"]
+async fn renames_reach_sockets_on_every_instance_on_postgres() {
+  let (first, second) = TestApp::spawn_postgres_cluster().await;
+  let owner = first.signup("owner").await;
+  let user = first.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+
+  let mut owner_socket = owner.on(&second).join(room.room_id).await.unwrap();
+  second.wait_for_socket(room.room_id, owner.id).await;
+  let mut socket = user.connect().await.unwrap();
+  socket
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": room.room_id }))
+    .await;
+  assert_eq!(socket.next_json().await["type"], "subscribed");
+  // a message from the second instance proves the first one listens on the bus
+  owner_socket.send_text("hello").await;
+  assert_eq!(socket.next_json().await["memberName"], owner.name);
+
+  // the rename goes through the first instance, the socket of the owner lives on the second
+  owner
+    .update_profile(serde_json::json!({ "displayName": "The Owner" }))
+    .await
+    .unwrap();
+  // the rename reaches the second instance some time after the response
+  let mut member_name = serde_json::Value::Null;
+  for _ in 0..10 {
+    owner_socket.send_text("hello again").await;
+    let message = socket.next_json().await;
+    assert_eq!(message["message"], "hello again");
+    member_name = message["memberName"].clone();
+    if member_name == "The Owner" {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  assert_eq!(member_name, "The Owner");
+  second.stop().await;
+  first.stop().await;
+}
+
+scenario_tests!(presence_follows_live_sockets, presence);
+
+async fn presence(app: TestApp) {
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+  assert_eq!(bob.user(alice.id).await.unwrap()["status"], "offline");
+
+  let first = alice.connect().await.unwrap();
+  let second = alice.connect().await.unwrap();
+  app.wait_for_online(alice.id).await;
+  assert_eq!(bob.user(alice.id).await.unwrap()["status"], "online");
+  first.close().await;
+  wait_for_status(&bob, alice.id, "online").await;
+  second.close().await;
+  app.wait_for_disconnect(alice.id).await;
+  wait_for_status(&bob, alice.id, "offline").await;
+  app.stop().await;
+}
+
+#[tokio::test]
+#[ignore = "needs the Postgres server configured in .env"]
+async fn presence_is_shared_by_every_instance_on_postgres() {
+  let (first, second) = TestApp::spawn_postgres_cluster().await;
+  let alice = first.signup("alice").await;
+  let bob = first.signup("bob").await;
+
+  // the socket of alice lives on the second instance, bob asks the first one
+  let socket = alice.on(&second).connect().await.unwrap();
+  second.wait_for_online(alice.id).await;
+  wait_for_status(&bob, alice.id, "online").await;
+  socket.close().await;
+  second.wait_for_disconnect(alice.id).await;
+  wait_for_status(&bob, alice.id, "offline").await;
+  second.stop().await;
+  first.stop().await;
+}
@@ -0,0 +1,119 @@
+mod support;
+
+use rust_tokio_chat_app::db::Repositories;
+use rust_tokio_chat_app::rate_limit::{RateLimit, RateLimitConfig, RateLimits};
+use rust_tokio_chat_app::ws::heartbeat::HeartbeatConfig;
+use rust_tokio_chat_app::ws::lobby::Lobby;
+use rust_tokio_chat_app::Config;
+use std::sync::Arc;
+use support::TestApp;
+
+// a bucket of `burst` tokens that does not noticeably refill during a test
+fn slow(burst: u32) -> Option<RateLimit> {
+  Some(RateLimit {
+    burst,
+    per_minute: 1,
+  })
+}
+
+fn with_limits(ip: Option<RateLimit>, user: Option<RateLimit>) -> Config {
+  let mut config = Config::in_memory();
+  config.state.rate_limits = Arc::new(RateLimits::new(&RateLimitConfig {
+    ip,
+    user,
+    socket_messages: None,
+  }));
+  config
+}
+
+#[tokio::test]
+async fn routes_without_login_are_limited_by_address() {
+  let app = TestApp::spawn_with_config(with_limits(slow(2), None)).await;
+  let alice = app.signup("alice").await;
+  app.signup("bob").await;
+
+  let err = app.try_signup("carol").await.unwrap_err();
+  assert_eq!(err.status, 429);
+  assert_eq!(err.code, "RATE_LIMITED");
+  let response = reqwest::Client::new()
+    .post(format!("http://{}/users/login", app.addr))
+    .header("x-request-id", "req-429")
+    .json(&serde_json::json!({ "name": alice.name, "password": "password" }))
+    .send()
+    .await
+    .unwrap();
+  assert_eq!(response.status(), 429);
+  let retry_after: u64 = response.headers()["retry-after"]
+    .to_str()
+    .unwrap()
+    .parse()
+    .unwrap();
+  assert!(retry_after >= 1);
+  let body: serde_json::Value = response.json().await.unwrap();
+  assert_eq!(body["code"], "RATE_LIMITED");
+  assert_eq!(body["requestId"], "req-429");
+
+  // routes behind the login are limited by user instead
+  alice.me().await.unwrap();
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn routes_behind_login_are_limited_by_user() {
+  let app = TestApp::spawn_with_config(with_limits(None, slow(3))).await;
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+
+  for _ in 0..3 {
+    alice.me().await.unwrap();
+  }
+  let err = alice.create_room("general").await.unwrap_err();
+  assert_eq!(err.status, 429);
+  assert_eq!(err.code, "RATE_LIMITED");
+  bob.create_room("general").await.unwrap();
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn socket_messages_are_limited_by_member() {
+  let repos = Repositories::in_memory();
+  let lobby = Lobby::new(repos.clone(), HeartbeatConfig::default()).with_message_rate(slow(2));
+  let app = TestApp::spawn_with_config(Config::new(repos, lobby)).await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+
+  let mut owner_socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+  let mut user_socket = user.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+  for text in ["one", "two", "three"] {
+    user_socket.send_text(text).await;
+  }
+  let error = user_socket.next_json().await;
+  assert_eq!(error["type"], "error");
+  assert_eq!(error["code"], "RATE_LIMITED");
+  assert_eq!(error["roomId"], room.room_id);
+  assert!(error["retryAfter"].as_u64().unwrap() >= 1);
+  assert_eq!(owner_socket.next_text().await, "one");
+  assert_eq!(owner_socket.next_text().await, "two");
+
+  // the multiplexed socket of the same member shares the bucket
+  let mut socket = user.connect().await.unwrap();
+  socket
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": room.room_id }))
+    .await;
+  assert_eq!(socket.next_json().await["type"], "subscribed");
+  socket
+    .send_json(serde_json::json!({ "type": "message", "roomId": room.room_id, "message": "four" }))
+    .await;
+  let error = socket.next_json().await;
+  assert_eq!(error["type"], "error");
+  assert_eq!(error["code"], "RATE_LIMITED");
+  assert_eq!(error["roomId"], room.room_id);
+
+  // other members are not affected
+  owner_socket.send_text("hello").await;
+  assert_eq!(user_socket.next_text().await, "hello");
+  app.stop().await;
+}
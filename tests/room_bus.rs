@@ -0,0 +1,181 @@
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use rust_tokio_chat_app::db::{db_config, Repositories};
+use rust_tokio_chat_app::ws::bus::postgres::PostgresBus;
+use rust_tokio_chat_app::ws::bus::redis::RedisBus;
+use rust_tokio_chat_app::ws::heartbeat::HeartbeatConfig;
+use rust_tokio_chat_app::ws::lobby::{Lobby, RoomState};
+use rust_tokio_chat_app::ws::{ClientWsMessage, ClientWsMessageType, MAX_MESSAGE_LEN};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio_postgres::NoTls;
+
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn lobby() -> Lobby {
+  Lobby::new(Repositories::in_memory(), HeartbeatConfig::default())
+}
+
+// Other instances may share the bus channel, a fresh room id keeps their events apart.
+fn unique_room_id() -> i64 {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_nanos();
+  (nanos % i64::MAX as u128) as i64
+}
+
+// Opens the room on the lobby and returns the receiver its sockets would read from.
+fn open_room(lobby: &Lobby, room_id: i64) -> broadcast::Receiver<String> {
+  let (tx, rx) = broadcast::channel(64);
+  lobby
+    .rooms
+    .lock()
+    .unwrap()
+    .insert(room_id, RoomState::new("bus".to_owned(), tx));
+  rx
+}
+
+fn publish(lobby: &Lobby, room_id: i64, message: &str) {
+  lobby.publish(
+    room_id,
+    &ClientWsMessage {
+      member_id: 1,
+      user_id: 1,
+      member_name: "member".to_owned(),
+      message_type: ClientWsMessageType::Message,
+      message: message.to_owned(),
+      // the lobbies have no room writer, only the fan-out is tested
+      db_skip_write: true,
+    },
+  );
+}
+
+async fn next_message(rx: &mut broadcast::Receiver<String>) -> String {
+  let event = tokio::time::timeout(WAIT_TIMEOUT, rx.recv())
+    .await
+    .expect("no room event arrived")
+    .unwrap();
+  serde_json::from_str::<ClientWsMessage>(&event)
+    .unwrap()
+    .message
+}
+
+// Publishes probes until the other lobby receives one, the subscriptions start in the
+// background.
+async fn wait_for_subscription(from: &Lobby, room_id: i64, rx: &mut broadcast::Receiver<String>) {
+  tokio::time::timeout(WAIT_TIMEOUT, async {
+    loop {
+      publish(from, room_id, "probe");
+      if tokio::time::timeout(Duration::from_millis(100), rx.recv())
+        .await
+        .is_ok()
+      {
+        return;
+      }
+    }
+  })
+  .await
+  .expect("the bus did not subscribe");
+}
+
+async fn settle(rxs: [&mut broadcast::Receiver<String>; 2]) {
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  for rx in rxs {
+    while rx.try_recv().is_ok() {}
+  }
+}
+
+async fn events_fan_out_in_order(first: Lobby, second: Lobby) {
+  let room_id = unique_room_id();
+  let mut first_rx = open_room(&first, room_id);
+  let mut second_rx = open_room(&second, room_id);
+  wait_for_subscription(&first, room_id, &mut second_rx).await;
+  wait_for_subscription(&second, room_id, &mut first_rx).await;
+  settle([&mut first_rx, &mut second_rx]).await;
+
+  let messages: Vec<String> = (0..20).map(|i| format!("message {}", i)).collect();
+  for message in &messages {
+    publish(&first, room_id, message);
+  }
+  for message in &messages {
+    assert_eq!(&next_message(&mut second_rx).await, message);
+  }
+
+  // the publishing lobby sees every event once, an echo from the bus would arrive before
+  // the reply of the other lobby
+  publish(&second, room_id, "reply");
+  for message in &messages {
+    assert_eq!(&next_message(&mut first_rx).await, message);
+  }
+  assert_eq!(next_message(&mut first_rx).await, "reply");
+  assert_eq!(next_message(&mut second_rx).await, "reply");
+}
+
+#[tokio::test]
+#[ignore = "needs the Redis server configured in .env"]
+async fn events_fan_out_in_order_on_redis() {
+  dotenv::dotenv().ok();
+  // passes without a Redis server, so that every ignored test can run without one
+  let url = match std::env::var("REDIS_URL") {
+    Ok(url) => url,
+    Err(_) => {
+      eprintln!("skipping, REDIS_URL is not set");
+      return;
+    }
+  };
+  let first = lobby();
+  let bus = RedisBus::connect(&url, first.local_state()).await.unwrap();
+  let first = first.with_bus(bus);
+  let second = lobby();
+  let bus = RedisBus::connect(&url, second.local_state()).await.unwrap();
+  let second = second.with_bus(bus);
+  events_fan_out_in_order(first, second).await;
+}
+
+async fn postgres_lobbies() -> (Lobby, Lobby) {
+  dotenv::dotenv().ok();
+  let pool = Pool::builder()
+    .build(PostgresConnectionManager::new(db_config(), NoTls))
+    .await
+    .unwrap();
+  let first = lobby();
+  let bus = PostgresBus::spawn(pool.clone(), db_config(), first.local_state());
+  let first = first.with_bus(bus);
+  let second = lobby();
+  let bus = PostgresBus::spawn(pool, db_config(), second.local_state());
+  (first, second.with_bus(bus))
+}
+
+#[tokio::test]
+#[ignore = "needs the Postgres server configured in .env"]
+async fn events_fan_out_in_order_on_postgres() {
+  let (first, second) = postgres_lobbies().await;
+  events_fan_out_in_order(first, second).await;
+}
+
+#[tokio::test]
+#[ignore = "needs the Postgres server configured in .env"]
+async fn longest_message_fits_a_notification_on_postgres() {
+  let (first, second) = postgres_lobbies().await;
+  let room_id = unique_room_id();
+  let mut first_rx = open_room(&first, room_id);
+  let mut second_rx = open_room(&second, room_id);
+  wait_for_subscription(&first, room_id, &mut second_rx).await;
+  settle([&mut first_rx, &mut second_rx]).await;
+
+  // control characters take the most room once the event is escaped for the bus
+  let message = "\u{1}".repeat(MAX_MESSAGE_LEN);
+  first.publish(
+    room_id,
+    &ClientWsMessage {
+      member_id: i64::MAX,
+      user_id: i64::MAX,
+      member_name: "\"".repeat(64),
+      message_type: ClientWsMessageType::Message,
+      message: message.clone(),
+      db_skip_write: true,
+    },
+  );
+  assert_eq!(next_message(&mut second_rx).await, message);
+}
@@ -0,0 +1,262 @@
+mod support;
+
+use rust_tokio_chat_app::ws::MAX_MESSAGE_LEN;
+use std::time::Duration;
+use support::TestApp;
+
+#[tokio::test]
+async fn members_receive_each_others_messages() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+
+  let mut owner_socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+  let mut user_socket = user.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+
+  user_socket.send_text("hello").await;
+  assert_eq!(owner_socket.next_text().await, "hello");
+  owner_socket.send_text("hi").await;
+  // the sender never gets its own message back
+  assert_eq!(user_socket.next_text().await, "hi");
+  // messages go out once they are saved
+  assert_eq!(
+    user.export().await.unwrap()["messages"][0]["message"],
+    "hello"
+  );
+  assert_eq!(
+    owner.export().await.unwrap()["messages"][0]["message"],
+    "hi"
+  );
+}
+
+scenario_tests!(
+  owner_kicks_user_and_socket_receives_leave_notice,
+  owner_kicks_user
+);
+
+async fn owner_kicks_user(app: TestApp) {
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+
+  let mut owner_socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+  let mut user_socket = user.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+
+  let removed = owner.remove(room.room_id, &user.name).await.unwrap();
+  assert_eq!(removed.removed_user_id, user.id);
+  assert_eq!(removed.active_members_count, 1);
+
+  assert_eq!(user_socket.next_text().await, "user is kicked");
+  user_socket.expect_closed().await;
+  app.wait_for_disconnect(user.id).await;
+  assert_eq!(
+    owner_socket.next_text().await,
+    format!("{} left the room", user.name)
+  );
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn only_the_owner_can_remove_members() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+  let _socket = user.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+
+  let err = user.remove(room.room_id, &owner.name).await.unwrap_err();
+  assert_eq!(err.status, 403);
+  assert_eq!(err.code, "NOT_ROOM_OWNER");
+}
+
+#[tokio::test]
+async fn leaving_the_room_closes_the_socket() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let room = owner.create_room("general").await.unwrap();
+  let mut socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+
+  let left = owner.leave(room.room_id).await.unwrap();
+  assert_eq!(left.member_id, room.member_id);
+  // the last member leaving deletes the room
+  assert_eq!(left.active_members_count, 0);
+  assert_eq!(socket.next_text().await, "left the room by user's request");
+  socket.expect_closed().await;
+
+  let err = owner.join(room.room_id).await.unwrap_err();
+  assert_eq!(err.status, 404);
+  assert_eq!(err.code, "ROOM_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn kicked_user_keeps_the_multiplexed_socket() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let general = owner.create_room("general").await.unwrap();
+  let random = owner.create_room("random").await.unwrap();
+
+  let mut socket = user.connect().await.unwrap();
+  for room_id in [general.room_id, random.room_id] {
+    socket
+      .send_json(serde_json::json!({ "type": "subscribe", "roomId": room_id }))
+      .await;
+    let subscribed = socket.next_json().await;
+    assert_eq!(subscribed["type"], "subscribed");
+    assert_eq!(subscribed["roomId"], room_id);
+  }
+
+  owner.remove(general.room_id, &user.name).await.unwrap();
+  let leave = socket.next_json().await;
+  assert_eq!(leave["type"], "leave");
+  assert_eq!(leave["roomId"], general.room_id);
+  assert_eq!(leave["message"], "user is kicked");
+
+  // the other subscription is still live
+  let mut owner_socket = owner.join(random.room_id).await.unwrap();
+  app.wait_for_socket(random.room_id, owner.id).await;
+  owner_socket.send_text("still here?").await;
+  let message = socket.next_json().await;
+  assert_eq!(message["type"], "message");
+  assert_eq!(message["roomId"], random.room_id);
+  assert_eq!(message["memberName"], owner.name);
+  assert_eq!(message["message"], "still here?");
+}
+
+#[tokio::test]
+async fn messages_over_the_length_limit_are_rejected() {
+  let app = TestApp::spawn().await;
+  let owner = app.signup("owner").await;
+  let user = app.signup("user").await;
+  let room = owner.create_room("general").await.unwrap();
+
+  let mut owner_socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+  let mut user_socket = user.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+  user_socket
+    .send_text(&"a".repeat(MAX_MESSAGE_LEN + 1))
+    .await;
+  let error = user_socket.next_json().await;
+  assert_eq!(error["type"], "error");
+  assert_eq!(error["code"], "MESSAGE_TOO_LONG");
+  assert_eq!(error["roomId"], room.room_id);
+
+  let mut socket = user.connect().await.unwrap();
+  socket
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": room.room_id }))
+    .await;
+  assert_eq!(socket.next_json().await["type"], "subscribed");
+  socket
+    .send_json(serde_json::json!({
+      "type": "message",
+      "roomId": room.room_id,
+      "message": "a".repeat(MAX_MESSAGE_LEN + 1),
+    }))
+    .await;
+  assert_eq!(socket.next_json().await["code"], "MESSAGE_TOO_LONG");
+
+  // neither message reached the room, a message at the limit does
+  let longest = "a".repeat(MAX_MESSAGE_LEN);
+  user_socket.send_text(&longest).await;
+  assert_eq!(owner_socket.next_text().await, longest);
+}
+
+scenario_tests!(
+  room_names_do_not_clash_with_direct_rooms,
+  room_names_do_not_clash
+);
+
+async fn room_names_do_not_clash(app: TestApp) {
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+
+  let err = alice
+    .create_room(&format!("dm-{}-{}", alice.id, bob.id))
+    .await
+    .unwrap_err();
+  assert_eq!(err.status, 400);
+  assert_eq!(err.code, "BAD_REQUEST");
+
+  // the same owner may reuse a name, each room is its own
+  let first = alice.create_room("general").await.unwrap();
+  let second = alice.create_room("general").await.unwrap();
+  assert_ne!(first.room_id, second.room_id);
+
+  let direct = alice.open_direct_room(bob.id).await.unwrap();
+  assert_ne!(direct.room_id, first.room_id);
+  assert_ne!(direct.room_id, second.room_id);
+  assert_eq!(
+    bob.open_direct_room(alice.id).await.unwrap().room_id,
+    direct.room_id
+  );
+  app.stop().await;
+}
+
+scenario_tests!(
+  closing_a_socket_records_the_last_join,
+  last_join_is_recorded
+);
+
+async fn last_join_is_recorded(app: TestApp) {
+  // memberships of another user keep the member ids apart from the user ids
+  let other = app.signup("other").await;
+  for name in ["first", "second"] {
+    other.create_room(name).await.unwrap();
+  }
+  let owner = app.signup("owner").await;
+  let room = owner.create_room("general").await.unwrap();
+  // the update goes by room and user id, never by member id
+  assert_ne!(room.member_id, owner.id);
+  let joined_at = owner.export().await.unwrap()["memberships"][0]["lastJoinedAt"].clone();
+
+  let socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+  socket.close().await;
+  app.wait_for_disconnect(owner.id).await;
+  tokio::time::timeout(Duration::from_secs(5), async {
+    while owner.export().await.unwrap()["memberships"][0]["lastJoinedAt"] == joined_at {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .expect("last_joined_at was not updated");
+  app.stop().await;
+}
+
+scenario_tests!(
+  messages_sent_just_before_the_last_socket_leaves_are_saved,
+  last_messages_are_saved
+);
+
+async fn last_messages_are_saved(app: TestApp) {
+  let owner = app.signup("owner").await;
+  let room = owner.create_room("general").await.unwrap();
+
+  // the room writer only stops once the last socket of the room is gone
+  let mut socket = owner.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, owner.id).await;
+  for i in 0..10 {
+    socket.send_text(&format!("message {}", i)).await;
+  }
+  socket.close().await;
+  app.wait_for_disconnect(owner.id).await;
+  app.lobby.drain().await;
+
+  let messages: Vec<_> = owner.export().await.unwrap()["messages"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|message| message["message"].as_str().unwrap().to_owned())
+    .collect();
+  let expected: Vec<_> = (0..10).map(|i| format!("message {}", i)).collect();
+  assert_eq!(messages, expected);
+  app.stop().await;
+}
@@ -0,0 +1,48 @@
+mod support;
+
+use support::TestApp;
+
+// Ids of the users in a search result.
+fn found(result: &serde_json::Value) -> Vec<i64> {
+  result["users"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|user| user["id"].as_i64().unwrap())
+    .collect()
+}
+
+scenario_tests!(users_are_found_by_name_prefix, search_users);
+
+async fn search_users(app: TestApp) {
+  let alice = app.signup("alice").await;
+  let bob = app.signup("bob").await;
+  bob
+    .update_profile(serde_json::json!({ "displayName": "Robert Tables" }))
+    .await
+    .unwrap();
+
+  let result = bob.search(&alice.name.to_uppercase()).await.unwrap();
+  assert_eq!(found(&result), vec![alice.id]);
+  assert_eq!(result["users"][0]["name"], alice.name.as_str());
+  assert!(result["users"][0].get("email").is_none());
+  assert_eq!(found(&alice.search("rob").await.unwrap()), vec![bob.id]);
+  assert_eq!(found(&alice.search("bo").await.unwrap()), vec![bob.id]);
+  // wildcards are matched literally
+  assert!(found(&alice.search("%").await.unwrap()).is_empty());
+  assert!(found(&alice.search("_").await.unwrap()).is_empty());
+  assert_eq!(alice.search("  ").await.unwrap_err().status, 400);
+
+  // hidden users are left out but can still be looked up
+  let updated = bob
+    .update_profile(serde_json::json!({ "hiddenFromSearch": true }))
+    .await
+    .unwrap();
+  assert_eq!(updated["hiddenFromSearch"], true);
+  assert!(found(&alice.search("rob").await.unwrap()).is_empty());
+  let profile = alice.user(bob.id).await.unwrap();
+  assert_eq!(profile["displayName"], "Robert Tables");
+  assert!(profile.get("hiddenFromSearch").is_none());
+  assert_eq!(alice.user(-1).await.unwrap_err().code, "USER_NOT_FOUND");
+  app.stop().await;
+}
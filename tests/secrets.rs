@@ -0,0 +1,20 @@
+use rust_tokio_chat_app::auth::AuthConfig;
+use std::panic::catch_unwind;
+
+// The env variables are process wide, so every case runs in this single test.
+#[test]
+fn secrets_are_required_outside_of_tests() {
+  std::env::remove_var("JWT_SIGNING_KEY");
+  std::env::remove_var("JWT_SECRET");
+  std::env::set_var("TOTP_ENCRYPTION_KEY", "totp key");
+  assert!(catch_unwind(AuthConfig::from_env).is_err());
+
+  std::env::set_var("JWT_SECRET", "jwt secret");
+  std::env::remove_var("TOTP_ENCRYPTION_KEY");
+  assert!(catch_unwind(AuthConfig::from_env).is_err());
+
+  std::env::set_var("TOTP_ENCRYPTION_KEY", "totp key");
+  assert!(catch_unwind(AuthConfig::from_env).is_ok());
+  std::env::remove_var("JWT_SECRET");
+  std::env::remove_var("TOTP_ENCRYPTION_KEY");
+}
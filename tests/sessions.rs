@@ -0,0 +1,155 @@
+mod support;
+
+use support::TestApp;
+
+scenario_tests!(refresh_rotates_the_refresh_token, rotates_refresh_token);
+
+async fn rotates_refresh_token(app: TestApp) {
+  let mut user = app.signup("user").await;
+  let first_refresh_token = user.refresh_token.clone();
+
+  let tokens = app.refresh(&first_refresh_token).await.unwrap();
+  assert_ne!(tokens.refresh_token, first_refresh_token);
+  assert_eq!(tokens.expires_in, 15 * 60);
+  user.token = tokens.auth_token;
+  assert_eq!(user.me().await.unwrap()["id"], user.id);
+
+  // presenting the rotated token again looks like theft and ends the session
+  let err = app.refresh(&first_refresh_token).await.unwrap_err();
+  assert_eq!(err.status, 401);
+  assert_eq!(err.code, "INVALID_REFRESH_TOKEN");
+  let err = app.refresh(&tokens.refresh_token).await.unwrap_err();
+  assert_eq!(err.code, "SESSION_REVOKED");
+  assert_eq!(user.me().await.unwrap_err().code, "SESSION_REVOKED");
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn logout_revokes_the_session() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let other_login = app.login(&user.name, "password").await.unwrap();
+
+  user.logout().await.unwrap();
+  assert_eq!(user.me().await.unwrap_err().code, "SESSION_REVOKED");
+  let err = app.refresh(&user.refresh_token).await.unwrap_err();
+  assert_eq!(err.code, "SESSION_REVOKED");
+  // other sessions are left alone
+  assert!(other_login.me().await.is_ok());
+}
+
+#[tokio::test]
+async fn logout_all_revokes_every_session() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let other_login = app.login(&user.name, "password").await.unwrap();
+
+  let revoked = user.logout_all().await.unwrap();
+  assert_eq!(revoked["revokedSessions"], 2);
+  assert_eq!(user.me().await.unwrap_err().code, "SESSION_REVOKED");
+  assert_eq!(other_login.me().await.unwrap_err().code, "SESSION_REVOKED");
+  let err = app.refresh(&other_login.refresh_token).await.unwrap_err();
+  assert_eq!(err.status, 401);
+}
+
+#[tokio::test]
+async fn unknown_refresh_token_is_rejected() {
+  let app = TestApp::spawn().await;
+  let err = app.refresh("not-a-token").await.unwrap_err();
+  assert_eq!(err.status, 401);
+  assert_eq!(err.code, "INVALID_REFRESH_TOKEN");
+}
+
+#[tokio::test]
+async fn lists_sessions_with_client_details() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  app.login(&user.name, "password").await.unwrap();
+
+  let sessions = user.sessions().await.unwrap();
+  let sessions = sessions["sessions"].as_array().unwrap();
+  assert_eq!(sessions.len(), 2);
+  assert_eq!(sessions.iter().filter(|s| s["current"] == true).count(), 1);
+  for session in sessions {
+    assert_eq!(session["userAgent"], support::USER_AGENT);
+    assert_eq!(session["ipAddress"], "127.0.0.1");
+    assert!(session["lastUsedAt"].is_string());
+  }
+}
+
+#[tokio::test]
+async fn revoking_a_session_closes_its_sockets() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let phone = app.login(&user.name, "password").await.unwrap();
+  let room = user.create_room("general").await.unwrap();
+  let mut room_socket = phone.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+  let mut socket = phone.connect().await.unwrap();
+  let friend = app.signup("friend").await;
+  let mut friend_socket = friend.join(room.room_id).await.unwrap();
+  app.wait_for_socket(room.room_id, friend.id).await;
+
+  let sessions = phone.sessions().await.unwrap();
+  let phone_session = sessions["sessions"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .find(|s| s["current"] == true)
+    .unwrap()["id"]
+    .as_i64()
+    .unwrap();
+  let revoked = user.revoke_session(phone_session).await.unwrap();
+  assert_eq!(revoked["closedSockets"], 2);
+
+  // 1008 is the policy violation close code
+  assert_eq!(room_socket.expect_close_code().await, 1008);
+  assert_eq!(socket.expect_close_code().await, 1008);
+  assert_eq!(phone.me().await.unwrap_err().code, "SESSION_REVOKED");
+  assert!(user.me().await.is_ok());
+  assert_eq!(
+    friend_socket.next_text().await,
+    format!("{} was logged out", user.name)
+  );
+}
+
+#[tokio::test]
+async fn sessions_of_other_users_cannot_be_revoked() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let other = app.signup("other").await;
+  let sessions = other.sessions().await.unwrap();
+  let other_session = sessions["sessions"][0]["id"].as_i64().unwrap();
+
+  let err = user.revoke_session(other_session).await.unwrap_err();
+  assert_eq!(err.status, 404);
+  assert_eq!(err.code, "SESSION_NOT_FOUND");
+  assert!(other.me().await.is_ok());
+}
+
+#[tokio::test]
+#[ignore = "needs the Postgres server configured in .env"]
+async fn revoking_closes_sockets_on_every_instance_on_postgres() {
+  let (first, second) = TestApp::spawn_postgres_cluster().await;
+  let user = first.signup("user").await;
+  let friend = first.signup("friend").await;
+  let room = user.create_room("general").await.unwrap();
+
+  let mut socket = user.on(&second).connect().await.unwrap();
+  socket
+    .send_json(serde_json::json!({ "type": "subscribe", "roomId": room.room_id }))
+    .await;
+  assert_eq!(socket.next_json().await["type"], "subscribed");
+  second.wait_for_online(user.id).await;
+  // a message from the first instance proves the second one listens on the bus
+  let mut friend_socket = friend.join(room.room_id).await.unwrap();
+  first.wait_for_socket(room.room_id, friend.id).await;
+  friend_socket.send_text("hello").await;
+  assert_eq!(socket.next_json().await["message"], "hello");
+
+  user.logout().await.unwrap();
+  socket.expect_closed().await;
+  second.wait_for_disconnect(user.id).await;
+  second.stop().await;
+  first.stop().await;
+}
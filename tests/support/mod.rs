@@ -0,0 +1,879 @@
+//! Boots the application on an ephemeral port and drives it over HTTP and WebSocket.
+#![allow(dead_code)]
+
+use futures_util::{SinkExt, StreamExt};
+use reqwest::StatusCode;
+use rust_tokio_chat_app::db::{db_config, setup_conn_pool_with_config, Repositories};
+use rust_tokio_chat_app::mail::FileMailer;
+use rust_tokio_chat_app::ws::bus::postgres::PostgresBus;
+use rust_tokio_chat_app::ws::heartbeat::HeartbeatConfig;
+use rust_tokio_chat_app::ws::lobby::Lobby;
+use rust_tokio_chat_app::{app, Config};
+use serde::Deserialize;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+// How long a test waits for a frame or a state change before failing.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+const PASSWORD: &str = "password";
+pub const USER_AGENT: &str = "chat-tests";
+
+/// Declares a module named `$name` with two tests running `$scenario(app: TestApp)`, one
+/// against the in-memory repositories and one, ignored by default, against Postgres.
+#[macro_export]
+macro_rules! scenario_tests {
+  ($name:ident, $scenario:ident) => {
+    mod $name {
+      #[tokio::test]
+      async fn in_memory() {
+        super::$scenario($crate::support::TestApp::spawn().await).await;
+      }
+
+      #[tokio::test]
+      #[ignore = "needs the Postgres server configured in .env"]
+      async fn on_postgres() {
+        super::$scenario($crate::support::TestApp::spawn_postgres().await).await;
+      }
+    }
+  };
+}
+
+pub struct TestApp {
+  pub addr: SocketAddr,
+  pub lobby: Arc<Lobby>,
+  client: reqwest::Client,
+  server: JoinHandle<()>,
+  // throwaway database, dropped with the last instance using it
+  database: Option<Arc<TestDatabase>>,
+  // every mail sent by the app lands here
+  mail_dir: PathBuf,
+}
+
+impl TestApp {
+  /// Runs the application against the in-memory repositories.
+  pub async fn spawn() -> TestApp {
+    TestApp::spawn_with(Config::in_memory(), None).await
+  }
+
+  /// Runs the application with custom settings, the address of `config` is ignored.
+  pub async fn spawn_with_config(config: Config) -> TestApp {
+    TestApp::spawn_with(config, None).await
+  }
+
+  /// Runs the application against a freshly created and migrated Postgres database,
+  /// the connection settings come from the same env variables the server uses.
+  pub async fn spawn_postgres() -> TestApp {
+    let (config, database) = create_database().await;
+    let pool = setup_conn_pool_with_config(config).await;
+    let repos = Repositories::postgres(pool);
+    let lobby = Lobby::new(repos.clone(), HeartbeatConfig::default());
+    TestApp::spawn_with(Config::new(repos, lobby), Some(database)).await
+  }
+
+  /// Runs two instances of the application sharing a freshly created Postgres database and
+  /// a Postgres room bus.
+  pub async fn spawn_postgres_cluster() -> (TestApp, TestApp) {
+    let (config, database) = create_database().await;
+    let first = TestApp::spawn_instance(config.clone(), Some(database.clone())).await;
+    let second = TestApp::spawn_instance(config, Some(database)).await;
+    (first, second)
+  }
+
+  async fn spawn_instance(
+    config: tokio_postgres::Config,
+    database: Option<Arc<TestDatabase>>,
+  ) -> TestApp {
+    let pool = setup_conn_pool_with_config(config.clone()).await;
+    let repos = Repositories::postgres(pool.clone());
+    let lobby = Lobby::new(repos.clone(), HeartbeatConfig::default());
+    let bus = PostgresBus::spawn(pool, config, lobby.local_state());
+    let lobby = lobby.with_bus(bus);
+    TestApp::spawn_with(Config::new(repos, lobby), database).await
+  }
+
+  async fn spawn_with(mut config: Config, database: Option<Arc<TestDatabase>>) -> TestApp {
+    let mail_dir = std::env::temp_dir().join(format!("chat_mail_{}", unique_suffix()));
+    config.state.mailer = Arc::new(FileMailer::new(&mail_dir));
+    let lobby = config.state.lobby.clone();
+    let app = app(&config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+      .unwrap()
+      .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    let server = tokio::spawn(async move {
+      server.await.unwrap();
+    });
+    TestApp {
+      addr,
+      lobby,
+      client: reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .unwrap(),
+      server,
+      database,
+      mail_dir,
+    }
+  }
+
+  /// Stops the server, the throwaway database goes with the last instance using it.
+  pub async fn stop(mut self) {
+    self.server.abort();
+    let _ = (&mut self.server).await;
+  }
+
+  fn url(&self, path: &str) -> String {
+    format!("http://{}{}", self.addr, path)
+  }
+
+  /// Signs up a user with a unique name starting with `name`.
+  pub async fn signup(&self, name: &str) -> TestUser {
+    let name = format!("{}_{}", name, unique_suffix());
+    self.try_signup(&name).await.unwrap()
+  }
+
+  /// Signs up a user with exactly the given name.
+  pub async fn try_signup(&self, name: &str) -> Result<TestUser, ApiError> {
+    let auth: AuthResponse = send(
+      self
+        .client
+        .post(self.url("/users/signup"))
+        .json(&serde_json::json!({ "name": name, "password": PASSWORD })),
+    )
+    .await?;
+    Ok(self.user(auth))
+  }
+
+  /// Signs up a user with a unique name starting with `name` and an email address.
+  pub async fn signup_with_email(&self, name: &str, email: &str) -> Result<TestUser, ApiError> {
+    let name = format!("{}_{}", name, unique_suffix());
+    let auth: AuthResponse = send(
+      self
+        .client
+        .post(self.url("/users/signup"))
+        .json(&serde_json::json!({ "name": name, "password": PASSWORD, "email": email })),
+    )
+    .await?;
+    Ok(self.user(auth))
+  }
+
+  pub async fn login(&self, name: &str, password: &str) -> Result<TestUser, ApiError> {
+    let auth: AuthResponse = send(
+      self
+        .client
+        .post(self.url("/users/login"))
+        .json(&serde_json::json!({ "name": name, "password": password })),
+    )
+    .await?;
+    Ok(self.user(auth))
+  }
+
+  /// Logs in with a password, returning the challenge of a user with two-factor
+  /// authentication instead of tokens.
+  pub async fn login_challenge(&self, name: &str, password: &str) -> Result<String, ApiError> {
+    let challenge: serde_json::Value = send(
+      self
+        .client
+        .post(self.url("/users/login"))
+        .json(&serde_json::json!({ "name": name, "password": password })),
+    )
+    .await?;
+    assert_eq!(challenge["twoFactorRequired"], true);
+    Ok(challenge["challengeToken"].as_str().unwrap().to_owned())
+  }
+
+  /// Second step of a two-factor login.
+  pub async fn login_totp(&self, challenge_token: &str, code: &str) -> Result<TestUser, ApiError> {
+    let auth: AuthResponse = send(
+      self
+        .client
+        .post(self.url("/users/login/totp"))
+        .json(&serde_json::json!({ "challengeToken": challenge_token, "code": code })),
+    )
+    .await?;
+    Ok(self.user(auth))
+  }
+
+  pub async fn request_password_reset(&self, email: &str) -> Result<serde_json::Value, ApiError> {
+    send(
+      self
+        .client
+        .post(self.url("/auth/password-reset"))
+        .json(&serde_json::json!({ "email": email })),
+    )
+    .await
+  }
+
+  pub async fn reset_password(
+    &self,
+    token: &str,
+    new_password: &str,
+  ) -> Result<serde_json::Value, ApiError> {
+    send(
+      self
+        .client
+        .post(self.url("/auth/password-reset/confirm"))
+        .json(&serde_json::json!({ "token": token, "newPassword": new_password })),
+    )
+    .await
+  }
+
+  /// Waits for the next mail sent to `to`, returning it whole. Mails are sent in the
+  /// background, so they may arrive after the response.
+  pub async fn mail_to(&self, to: &str) -> String {
+    let header = format!("To: {}\r\n", to);
+    tokio::time::timeout(WAIT_TIMEOUT, async {
+      loop {
+        let mut paths: Vec<_> = std::fs::read_dir(&self.mail_dir)
+          .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+          .unwrap_or_default();
+        paths.sort();
+        for path in paths {
+          let mail = std::fs::read_to_string(&path).unwrap();
+          if mail.starts_with(&header) {
+            // taken, so the next call waits for a newer mail
+            std::fs::remove_file(&path).unwrap();
+            return mail;
+          }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    })
+    .await
+    .expect("no mail was sent")
+  }
+
+  /// Scrapes `/metrics`.
+  pub async fn metrics(&self) -> String {
+    let response = self.client.get(self.url("/metrics")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.text().await.unwrap()
+  }
+
+  /// Sends an unauthenticated GET, returning the status and the JSON body.
+  pub async fn get_json(&self, path: &str) -> (u16, serde_json::Value) {
+    let response = self.client.get(self.url(path)).send().await.unwrap();
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap())
+  }
+
+  /// Trades a refresh token for new tokens.
+  pub async fn refresh(&self, refresh_token: &str) -> Result<Tokens, ApiError> {
+    send(
+      self
+        .client
+        .post(self.url("/auth/refresh"))
+        .json(&serde_json::json!({ "refreshToken": refresh_token })),
+    )
+    .await
+  }
+
+  fn user(&self, auth: AuthResponse) -> TestUser {
+    TestUser {
+      id: auth.id,
+      name: auth.name,
+      token: auth.auth_token,
+      refresh_token: auth.refresh_token,
+      addr: self.addr,
+      client: self.client.clone(),
+    }
+  }
+
+  /// Waits until the lobby has a live socket of the user in the room.
+  pub async fn wait_for_socket(&self, room_id: i64, user_id: i64) {
+    self
+      .wait_until(|lobby| {
+        lobby
+          .rooms
+          .lock()
+          .unwrap()
+          .get(&room_id)
+          .is_some_and(|room| room.clients.contains_key(&user_id))
+      })
+      .await;
+  }
+
+  /// Waits until the user has a live socket.
+  pub async fn wait_for_online(&self, user_id: i64) {
+    self.wait_until(|lobby| lobby.is_online(user_id)).await;
+  }
+
+  /// Waits until the user has no live socket left.
+  pub async fn wait_for_disconnect(&self, user_id: i64) {
+    self.wait_until(|lobby| !lobby.is_online(user_id)).await;
+  }
+
+  async fn wait_until(&self, condition: impl Fn(&Lobby) -> bool) {
+    tokio::time::timeout(WAIT_TIMEOUT, async {
+      while !condition(&self.lobby) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    })
+    .await
+    .expect("lobby did not reach the expected state");
+  }
+}
+
+/// A signed up user, every request is sent with the user's token.
+#[derive(Debug)]
+pub struct TestUser {
+  pub id: i64,
+  pub name: String,
+  pub token: String,
+  pub refresh_token: String,
+  addr: SocketAddr,
+  client: reqwest::Client,
+}
+
+impl TestUser {
+  /// The same login, sending its requests to another instance.
+  pub fn on(&self, app: &TestApp) -> TestUser {
+    TestUser {
+      id: self.id,
+      name: self.name.clone(),
+      token: self.token.clone(),
+      refresh_token: self.refresh_token.clone(),
+      addr: app.addr,
+      client: self.client.clone(),
+    }
+  }
+
+  fn url(&self, path: &str) -> String {
+    format!("http://{}{}", self.addr, path)
+  }
+
+  async fn request<T: serde::de::DeserializeOwned>(
+    &self,
+    request: reqwest::RequestBuilder,
+  ) -> Result<T, ApiError> {
+    send(request.bearer_auth(&self.token)).await
+  }
+
+  /// Fetches the user's own profile, handy to check whether the token still works.
+  pub async fn me(&self) -> Result<serde_json::Value, ApiError> {
+    self.request(self.client.get(self.url("/users"))).await
+  }
+
+  /// Sends `PATCH /users/me` with the given fields.
+  pub async fn update_profile(
+    &self,
+    profile: serde_json::Value,
+  ) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.patch(self.url("/users/me")).json(&profile))
+      .await
+  }
+
+  /// Fetches the public profile of any user.
+  pub async fn user(&self, user_id: i64) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.get(self.url(&format!("/users/{}", user_id))))
+      .await
+  }
+
+  /// Searches users by the start of their name or display name.
+  pub async fn search(&self, query: &str) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .get(self.url("/users/search"))
+          .query(&[("q", query)]),
+      )
+      .await
+  }
+
+  pub async fn export(&self) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.get(self.url("/users/me/export")))
+      .await
+  }
+
+  pub async fn delete_account(&self, password: &str) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .delete(self.url("/users/me"))
+          .json(&serde_json::json!({ "password": password })),
+      )
+      .await
+  }
+
+  pub async fn blocks(&self) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.get(self.url("/users/me/blocks")))
+      .await
+  }
+
+  pub async fn block(&self, user_id: i64) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .post(self.url("/users/me/blocks"))
+          .json(&serde_json::json!({ "userId": user_id })),
+      )
+      .await
+  }
+
+  pub async fn unblock(&self, user_id: i64) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .delete(self.url(&format!("/users/me/blocks/{}", user_id))),
+      )
+      .await
+  }
+
+  pub async fn logout(&self) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.post(self.url("/auth/logout")))
+      .await
+  }
+
+  pub async fn logout_all(&self) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.post(self.url("/auth/logout-all")))
+      .await
+  }
+
+  pub async fn sessions(&self) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.get(self.url("/users/me/sessions")))
+      .await
+  }
+
+  pub async fn revoke_session(&self, session_id: i64) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .delete(self.url(&format!("/users/me/sessions/{}", session_id))),
+      )
+      .await
+  }
+
+  pub async fn create_room(&self, name: &str) -> Result<CreatedRoom, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .post(self.url("/rooms/create"))
+          .json(&serde_json::json!({ "name": name })),
+      )
+      .await
+  }
+
+  pub async fn leave(&self, room_id: i64) -> Result<LeftRoom, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .post(self.url(&format!("/rooms/leave/{}", room_id))),
+      )
+      .await
+  }
+
+  pub async fn remove(&self, room_id: i64, user_name: &str) -> Result<RemovedMember, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .delete(self.url(&format!("/rooms/remove/{}", room_id)))
+          .json(&serde_json::json!({ "userName": user_name })),
+      )
+      .await
+  }
+
+  pub async fn open_direct_room(&self, user_id: i64) -> Result<DirectRoom, ApiError> {
+    self
+      .request(self.client.post(self.url(&format!("/dm/{}", user_id))))
+      .await
+  }
+
+  /// Pages backwards through the history of the room, oldest message first.
+  pub async fn messages(
+    &self,
+    room_id: i64,
+    before: Option<i64>,
+  ) -> Result<serde_json::Value, ApiError> {
+    let mut request = self
+      .client
+      .get(self.url(&format!("/rooms/messages/{}", room_id)));
+    if let Some(before) = before {
+      request = request.query(&[("before", before)]);
+    }
+    self.request(request).await
+  }
+
+  pub async fn invite(&self, room_id: i64, user_id: i64) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .post(self.url(&format!("/rooms/invite/{}", room_id)))
+          .json(&serde_json::json!({ "userId": user_id })),
+      )
+      .await
+  }
+
+  /// Opens the single room socket of `/rooms/join/:room_id`.
+  pub async fn join(&self, room_id: i64) -> Result<WsClient, ApiError> {
+    self.websocket(&format!("/rooms/join/{}", room_id)).await
+  }
+
+  /// Opens the multiplexed socket of `/ws`.
+  pub async fn connect(&self) -> Result<WsClient, ApiError> {
+    self.websocket("/ws").await
+  }
+
+  /// Starts enrolling an authenticator, returning the secret and provisioning URI.
+  pub async fn enrol_totp(&self) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(self.client.post(self.url("/users/me/totp")))
+      .await
+  }
+
+  pub async fn confirm_totp(&self, code: &str) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .post(self.url("/users/me/totp/confirm"))
+          .json(&serde_json::json!({ "code": code })),
+      )
+      .await
+  }
+
+  pub async fn disable_totp(&self, code: &str) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .delete(self.url("/users/me/totp"))
+          .json(&serde_json::json!({ "code": code })),
+      )
+      .await
+  }
+
+  pub async fn change_password(
+    &self,
+    current_password: &str,
+    new_password: &str,
+  ) -> Result<serde_json::Value, ApiError> {
+    self
+      .request(
+        self
+          .client
+          .post(self.url("/users/me/password"))
+          .json(&serde_json::json!({
+            "currentPassword": current_password,
+            "newPassword": new_password,
+          })),
+      )
+      .await
+  }
+
+  /// Trades the token for a single-use websocket ticket.
+  pub async fn ws_ticket(&self) -> Result<String, ApiError> {
+    let ticket: serde_json::Value = self
+      .request(self.client.post(self.url("/auth/ws-ticket")))
+      .await?;
+    Ok(ticket["ticket"].as_str().unwrap().to_owned())
+  }
+
+  async fn websocket(&self, path: &str) -> Result<WsClient, ApiError> {
+    let mut request = format!("ws://{}{}", self.addr, path)
+      .into_client_request()
+      .unwrap();
+    request.headers_mut().insert(
+      "Authorization",
+      format!("Bearer {}", self.token).parse().unwrap(),
+    );
+    open_websocket(request).await
+  }
+}
+
+/// Opens a socket without a bearer token, offering `protocols` as a browser would.
+pub async fn websocket_without_token(
+  addr: SocketAddr,
+  path: &str,
+  protocols: Option<&str>,
+) -> Result<WsClient, ApiError> {
+  let mut request = format!("ws://{}{}", addr, path)
+    .into_client_request()
+    .unwrap();
+  if let Some(protocols) = protocols {
+    request
+      .headers_mut()
+      .insert("Sec-WebSocket-Protocol", protocols.parse().unwrap());
+  }
+  open_websocket(request).await
+}
+
+async fn open_websocket(
+  request: tungstenite::handshake::client::Request,
+) -> Result<WsClient, ApiError> {
+  match tokio_tungstenite::connect_async(request).await {
+    Ok((stream, response)) => Ok(WsClient {
+      stream,
+      protocol: response
+        .headers()
+        .get("Sec-WebSocket-Protocol")
+        .map(|value| value.to_str().unwrap().to_owned()),
+    }),
+    Err(tungstenite::Error::Http(response)) => {
+      let body: ErrorResponse = response
+        .body()
+        .as_ref()
+        .and_then(|body| serde_json::from_slice(body).ok())
+        .unwrap_or_default();
+      Err(ApiError {
+        status: response.status().as_u16(),
+        code: body.code,
+        message: body.message,
+        request_id: body.request_id,
+      })
+    }
+    Err(e) => panic!("websocket handshake failed, err: {}", e),
+  }
+}
+
+async fn send<T: serde::de::DeserializeOwned>(
+  request: reqwest::RequestBuilder,
+) -> Result<T, ApiError> {
+  let response = request.send().await.unwrap();
+  let status = response.status();
+  if status.is_success() {
+    return Ok(response.json().await.unwrap());
+  }
+  let body = response.json::<ErrorResponse>().await.unwrap_or_default();
+  Err(ApiError {
+    status: status.as_u16(),
+    code: body.code,
+    message: body.message,
+    request_id: body.request_id,
+  })
+}
+
+#[derive(Debug)]
+pub struct WsClient {
+  stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+  // subprotocol selected by the server
+  pub protocol: Option<String>,
+}
+
+impl WsClient {
+  pub async fn send_text(&mut self, text: &str) {
+    self
+      .stream
+      .send(Message::Text(text.to_owned()))
+      .await
+      .unwrap();
+  }
+
+  pub async fn send_json(&mut self, frame: serde_json::Value) {
+    self.send_text(&frame.to_string()).await;
+  }
+
+  /// Returns the next text frame, pings and pongs are skipped.
+  pub async fn next_text(&mut self) -> String {
+    loop {
+      match self.next().await {
+        Some(Message::Text(text)) => return text,
+        Some(Message::Ping(_)) | Some(Message::Pong(_)) => continue,
+        other => panic!("expected a text frame, got {:?}", other),
+      }
+    }
+  }
+
+  pub async fn next_json(&mut self) -> serde_json::Value {
+    serde_json::from_str(&self.next_text().await).unwrap()
+  }
+
+  /// Waits for the server to close the socket, with or without a close frame.
+  pub async fn expect_closed(&mut self) {
+    loop {
+      match self.next().await {
+        None | Some(Message::Close(_)) => return,
+        Some(Message::Ping(_)) | Some(Message::Pong(_)) => continue,
+        Some(other) => panic!("expected the socket to close, got {:?}", other),
+      }
+    }
+  }
+
+  /// Like `expect_closed`, but `text` may arrive first when it races with the close.
+  pub async fn expect_closed_after(&mut self, text: &str) {
+    loop {
+      match self.next().await {
+        None | Some(Message::Close(_)) => return,
+        Some(Message::Ping(_)) | Some(Message::Pong(_)) => continue,
+        Some(Message::Text(t)) if t == text => continue,
+        Some(other) => panic!("expected the socket to close, got {:?}", other),
+      }
+    }
+  }
+
+  /// Waits for the server to close the socket and returns the code of its close frame.
+  pub async fn expect_close_code(&mut self) -> u16 {
+    loop {
+      match self.next().await {
+        Some(Message::Close(Some(frame))) => return frame.code.into(),
+        Some(Message::Ping(_)) | Some(Message::Pong(_)) => continue,
+        other => panic!("expected a close frame, got {:?}", other),
+      }
+    }
+  }
+
+  pub async fn close(mut self) {
+    let _ = self.stream.close(None).await;
+  }
+
+  async fn next(&mut self) -> Option<Message> {
+    let msg = tokio::time::timeout(WAIT_TIMEOUT, self.stream.next())
+      .await
+      .expect("timed out waiting for a websocket frame");
+    match msg {
+      Some(Ok(msg)) => Some(msg),
+      // the server may drop the connection without a closing handshake
+      Some(Err(tungstenite::Error::Protocol(_)))
+      | Some(Err(tungstenite::Error::ConnectionClosed)) => None,
+      Some(Err(e)) => panic!("websocket error, err: {}", e),
+      None => None,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+  pub status: u16,
+  pub code: String,
+  pub message: String,
+  pub request_id: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorResponse {
+  code: String,
+  message: String,
+  request_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthResponse {
+  id: i64,
+  name: String,
+  auth_token: String,
+  refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tokens {
+  pub auth_token: String,
+  pub refresh_token: String,
+  pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedRoom {
+  pub room_id: i64,
+  pub room_name: String,
+  pub member_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeftRoom {
+  pub room_name: String,
+  pub member_id: i64,
+  pub active_members_count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovedMember {
+  pub room_name: String,
+  pub removed_user_id: i64,
+  pub removed_user_name: String,
+  pub active_members_count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectRoom {
+  pub room_id: i64,
+  pub room_name: String,
+  pub other_user_id: i64,
+}
+
+impl Drop for TestApp {
+  fn drop(&mut self) {
+    self.server.abort();
+    let _ = std::fs::remove_dir_all(&self.mail_dir);
+  }
+}
+
+/// A throwaway database, dropped once the tests are done with it, failed ones included.
+pub struct TestDatabase {
+  name: String,
+}
+
+impl Drop for TestDatabase {
+  fn drop(&mut self) {
+    let statement = format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", self.name);
+    // the test runtime may be blocked on this drop, so the database is dropped from a
+    // runtime of its own
+    let dropped = std::thread::spawn(move || {
+      tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async move {
+          let (admin, connection) = db_config().connect(tokio_postgres::NoTls).await?;
+          tokio::spawn(connection);
+          admin.batch_execute(&statement).await
+        })
+    })
+    .join();
+    if !matches!(dropped, Ok(Ok(()))) {
+      eprintln!("could not drop the test database {}", self.name);
+    }
+  }
+}
+
+// Creates a throwaway database on the Postgres server configured in `.env`.
+async fn create_database() -> (tokio_postgres::Config, Arc<TestDatabase>) {
+  dotenv::dotenv().ok();
+  let mut config = db_config();
+  let (admin, connection) = config.connect(tokio_postgres::NoTls).await.unwrap();
+  tokio::spawn(connection);
+  let database = format!("chat_test_{}", unique_suffix());
+  admin
+    .batch_execute(&format!("CREATE DATABASE {}", database))
+    .await
+    .unwrap();
+  config.dbname(&database);
+  (config, Arc::new(TestDatabase { name: database }))
+}
+
+fn unique_suffix() -> String {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_nanos();
+  format!("{}_{}", std::process::id(), nanos)
+}
@@ -0,0 +1,135 @@
+mod support;
+
+use rust_tokio_chat_app::auth::totp::{base32_decode, code_at, current_step};
+use support::{TestApp, TestUser};
+
+// Enrols and confirms an authenticator, returning its secret, the step of the code
+// confirmed with and the recovery codes.
+async fn enable_totp(user: &TestUser) -> (Vec<u8>, i64, Vec<String>) {
+  let enrolment = user.enrol_totp().await.unwrap();
+  let uri = enrolment["provisioningUri"].as_str().unwrap();
+  assert!(uri.starts_with("otpauth://totp/rust-tokio-chat-app:"));
+  let secret = base32_decode(enrolment["secret"].as_str().unwrap()).unwrap();
+
+  let step = current_step();
+  let confirmed = user.confirm_totp(&code_at(&secret, step)).await.unwrap();
+  let recovery_codes = confirmed["recoveryCodes"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|code| code.as_str().unwrap().to_owned())
+    .collect();
+  (secret, step, recovery_codes)
+}
+
+#[test]
+fn codes_match_the_rfc_6238_vectors() {
+  // the vectors have eight digits, six are kept
+  assert_eq!(code_at(b"12345678901234567890", 59 / 30), "287082");
+  assert_eq!(code_at(b"12345678901234567890", 1111111109 / 30), "081804");
+}
+
+scenario_tests!(login_takes_a_code_once_enabled, two_step_login);
+
+async fn two_step_login(app: TestApp) {
+  let user = app.signup("user").await;
+  let (secret, step, recovery_codes) = enable_totp(&user).await;
+  assert_eq!(recovery_codes.len(), 10);
+
+  let challenge = app.login_challenge(&user.name, "password").await.unwrap();
+  let err = app.login_totp(&challenge, "000000").await.unwrap_err();
+  assert_eq!(err.status, 401);
+  assert_eq!(err.code, "INVALID_TOTP_CODE");
+
+  // the code confirmed with is used up
+  let code = code_at(&secret, step);
+  assert_eq!(
+    app.login_totp(&challenge, &code).await.unwrap_err().code,
+    "INVALID_TOTP_CODE"
+  );
+  let code = code_at(&secret, step + 1);
+  let logged_in = app.login_totp(&challenge, &code).await.unwrap();
+  assert_eq!(logged_in.id, user.id);
+  assert!(logged_in.me().await.is_ok());
+  assert_eq!(
+    app.login_totp(&challenge, &code).await.unwrap_err().code,
+    "INVALID_LOGIN_CHALLENGE"
+  );
+
+  // recovery codes work once
+  let challenge = app.login_challenge(&user.name, "password").await.unwrap();
+  let recovery_code = recovery_codes[0].to_uppercase();
+  assert!(app.login_totp(&challenge, &recovery_code).await.is_ok());
+  let challenge = app.login_challenge(&user.name, "password").await.unwrap();
+  assert_eq!(
+    app
+      .login_totp(&challenge, &recovery_code)
+      .await
+      .unwrap_err()
+      .code,
+    "INVALID_TOTP_CODE"
+  );
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn challenge_token_is_useless_on_guarded_routes() {
+  let app = TestApp::spawn().await;
+  let mut user = app.signup("user").await;
+  enable_totp(&user).await;
+
+  user.token = app.login_challenge(&user.name, "password").await.unwrap();
+  let err = user.me().await.unwrap_err();
+  assert_eq!(err.status, 401);
+  assert_eq!(err.code, "UNAUTHORIZED");
+}
+
+#[tokio::test]
+async fn challenge_ends_after_too_many_wrong_codes() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let (secret, step, _) = enable_totp(&user).await;
+
+  let challenge = app.login_challenge(&user.name, "password").await.unwrap();
+  for _ in 0..5 {
+    let err = app.login_totp(&challenge, "000000").await.unwrap_err();
+    assert_eq!(err.code, "INVALID_TOTP_CODE");
+  }
+  let code = code_at(&secret, step + 1);
+  let err = app.login_totp(&challenge, &code).await.unwrap_err();
+  assert_eq!(err.code, "INVALID_LOGIN_CHALLENGE");
+}
+
+#[tokio::test]
+async fn enrolment_needs_a_valid_code() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  assert_eq!(
+    user.confirm_totp("123456").await.unwrap_err().code,
+    "TOTP_NOT_ENROLLED"
+  );
+
+  user.enrol_totp().await.unwrap();
+  let err = user.confirm_totp("abc").await.unwrap_err();
+  assert_eq!(err.code, "INVALID_TOTP_CODE");
+  // not enabled yet, so the password is enough
+  assert!(app.login(&user.name, "password").await.is_ok());
+}
+
+#[tokio::test]
+async fn disabling_takes_a_code() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let (_, _, recovery_codes) = enable_totp(&user).await;
+  assert_eq!(
+    user.enrol_totp().await.unwrap_err().code,
+    "TOTP_ALREADY_ENABLED"
+  );
+
+  assert_eq!(
+    user.disable_totp("000000").await.unwrap_err().code,
+    "INVALID_TOTP_CODE"
+  );
+  user.disable_totp(&recovery_codes[1]).await.unwrap();
+  assert!(app.login(&user.name, "password").await.is_ok());
+}
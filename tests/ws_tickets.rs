@@ -0,0 +1,85 @@
+mod support;
+
+use rust_tokio_chat_app::auth::AuthConfig;
+use rust_tokio_chat_app::Config;
+use std::sync::Arc;
+use std::time::Duration;
+use support::{websocket_without_token, TestApp};
+
+scenario_tests!(
+  ticket_in_the_query_opens_a_socket_once,
+  tickets_are_single_use
+);
+
+async fn tickets_are_single_use(app: TestApp) {
+  let user = app.signup("user").await;
+  let room = user.create_room("general").await.unwrap();
+  let ticket = user.ws_ticket().await.unwrap();
+  let path = format!("/rooms/join/{}?ticket={}", room.room_id, ticket);
+
+  let _socket = websocket_without_token(app.addr, &path, None)
+    .await
+    .unwrap();
+  app.wait_for_socket(room.room_id, user.id).await;
+
+  let err = websocket_without_token(app.addr, &path, None)
+    .await
+    .unwrap_err();
+  assert_eq!(err.status, 401);
+  assert_eq!(err.code, "INVALID_TICKET");
+  app.stop().await;
+}
+
+#[tokio::test]
+async fn ticket_can_be_passed_as_a_subprotocol() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let ticket = user.ws_ticket().await.unwrap();
+
+  let protocols = format!("chat, ticket.{}", ticket);
+  let socket = websocket_without_token(app.addr, "/ws", Some(&protocols))
+    .await
+    .unwrap();
+  assert_eq!(socket.protocol.as_deref(), Some("chat"));
+}
+
+#[tokio::test]
+async fn expired_ticket_is_rejected() {
+  let mut config = Config::in_memory();
+  config.state.auth = Arc::new(AuthConfig {
+    ws_ticket_ttl: Duration::ZERO,
+    ..AuthConfig::default()
+  });
+  let app = TestApp::spawn_with_config(config).await;
+  let user = app.signup("user").await;
+  let ticket = user.ws_ticket().await.unwrap();
+
+  let err = websocket_without_token(app.addr, &format!("/ws?ticket={}", ticket), None)
+    .await
+    .unwrap_err();
+  assert_eq!(err.code, "INVALID_TICKET");
+}
+
+#[tokio::test]
+async fn ticket_dies_with_its_session() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let ticket = user.ws_ticket().await.unwrap();
+  user.logout().await.unwrap();
+
+  let err = websocket_without_token(app.addr, &format!("/ws?ticket={}", ticket), None)
+    .await
+    .unwrap_err();
+  assert_eq!(err.code, "SESSION_REVOKED");
+}
+
+#[tokio::test]
+async fn ticket_is_ignored_outside_of_handshakes() {
+  let app = TestApp::spawn().await;
+  let user = app.signup("user").await;
+  let ticket = user.ws_ticket().await.unwrap();
+
+  let (status, body) = app.get_json(&format!("/users?ticket={}", ticket)).await;
+  assert_eq!(status, 401);
+  assert_eq!(body["code"], "UNAUTHORIZED");
+}